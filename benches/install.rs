@@ -0,0 +1,62 @@
+//! UFFDIO_MOVE against UFFDIO_COPY for installing source pages into
+//! anonymous memory.
+//!
+//! One thread faults in every page of a fresh anonymous region, the way a
+//! guest touches freshly restored memory, while the handler reads each
+//! page from an in-memory source and installs it.
+//!
+//! Run with `cargo bench --bench install`.
+
+use std::{io, time::Instant};
+
+use uffd_bug::{
+    create_uffd, move_supported, page_size, FaultHandler, Install, Page, PageSource, VmRegion,
+};
+use userfaultfd::RegisterMode;
+
+const PAGES: usize = 32768;
+const ROUNDS: usize = 3;
+
+struct FilledSource;
+
+impl PageSource for FilledSource {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        buf.fill(index as u8 | 1);
+        Ok(Page::Data)
+    }
+}
+
+fn faults_per_second(install: Install) -> f64 {
+    let vm = VmRegion::anonymous(PAGES * page_size()).unwrap();
+    let uffd = create_uffd().unwrap();
+    vm.register(&uffd, RegisterMode::MISSING).unwrap();
+    FaultHandler::new(uffd, &vm)
+        .page_source(FilledSource, None)
+        .install(install)
+        .spawn();
+
+    let base = vm.as_ptr() as usize;
+    let start = Instant::now();
+    for page in 0..PAGES {
+        let byte = (base + page * page_size()) as *const u8;
+        assert_eq!(unsafe { byte.read_volatile() }, page as u8 | 1);
+    }
+    PAGES as f64 / start.elapsed().as_secs_f64()
+}
+
+fn best_of(install: Install) -> f64 {
+    (0..ROUNDS)
+        .map(|_| faults_per_second(install))
+        .fold(0.0, f64::max)
+}
+
+fn main() {
+    if !move_supported() {
+        println!("UFFDIO_MOVE is not supported by this kernel; nothing to compare");
+        return;
+    }
+    let copy = best_of(Install::Copy);
+    let moved = best_of(Install::Move);
+    println!("copy: {:>10.0} faults/s", copy);
+    println!("move: {:>10.0} faults/s ({:.2}x)", moved, moved / copy);
+}
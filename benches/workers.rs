@@ -0,0 +1,68 @@
+//! Fault throughput of the worker pool against the single-threaded loop.
+//!
+//! Eight threads fault in disjoint stripes of a fresh region whose pages
+//! come from a source with a fixed per-page latency, standing in for a
+//! snapshot on slow storage or a remote peer.
+//!
+//! Run with `cargo bench --bench workers`.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use uffd_bug::{create_uffd, page_size, FaultHandler, MemfdRegion, Page, PageSource};
+use userfaultfd::RegisterMode;
+
+const PAGES: usize = 8192;
+const THREADS: usize = 8;
+const LATENCY: Duration = Duration::from_micros(20);
+
+struct SlowSource;
+
+impl PageSource for SlowSource {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        std::thread::sleep(LATENCY);
+        buf.fill(index as u8);
+        Ok(Page::Data)
+    }
+}
+
+fn faults_per_second(workers: usize) -> f64 {
+    let memory = MemfdRegion::new(PAGES * page_size()).unwrap();
+    let vm = memory.map_vm().unwrap();
+    let uffd = create_uffd().unwrap();
+    vm.register(&uffd, RegisterMode::MISSING).unwrap();
+    FaultHandler::new(uffd, &vm)
+        .page_source(SlowSource, None)
+        .workers(workers)
+        .spawn();
+
+    let base = vm.as_ptr() as usize;
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for thread in 0..THREADS {
+            s.spawn(move || {
+                for page in (thread..PAGES).step_by(THREADS) {
+                    let byte = (base + page * page_size()) as *const u8;
+                    assert_eq!(unsafe { byte.read_volatile() }, page as u8);
+                }
+            });
+        }
+    });
+    PAGES as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let single = faults_per_second(1);
+    println!("single thread: {:>10.0} faults/s", single);
+    for workers in [2, 4, 8] {
+        let pooled = faults_per_second(workers);
+        println!(
+            "{} workers:     {:>10.0} faults/s ({:.2}x)",
+            workers,
+            pooled,
+            pooled / single
+        );
+    }
+}
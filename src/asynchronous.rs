@@ -0,0 +1,333 @@
+//! Drives a [`FaultHandler`] from a tokio runtime instead of a dedicated
+//! thread blocking in `poll`.
+//!
+//! Each uffd is non-blocking and wrapped in an [`AsyncFd`]. Before a fault
+//! is resolved, the pages it needs are fetched from an [`AsyncPageSource`]
+//! and staged; the handler then fills from the staged pages exactly as the
+//! blocking loop would, so events are handled with the same semantics as
+//! [`FaultHandler::run`].
+
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    fs::File,
+    future::{poll_fn, Future},
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::Instant,
+};
+
+use tokio::io::unix::AsyncFd;
+use userfaultfd::{Event, FaultKind, ReadWrite, Uffd};
+
+use crate::{
+    handler::requested_features, sys, Error, ErrorClass, FaultHandler, Page, PageSource, Result,
+};
+
+/// Opens a non-blocking userfaultfd asking for the same features as
+/// [`crate::create_uffd`], as [`AsyncFaultHandler`] needs.
+pub fn create_uffd() -> Result<Uffd> {
+    Ok(sys::create(requested_features()?, true)?)
+}
+
+/// Where guest pages come from, read without blocking the runtime.
+///
+/// Like [`PageSource`], pages are numbered by backing file offset / page
+/// size.
+pub trait AsyncPageSource: Send + Sync {
+    /// Fills `buf`, exactly one page long, with page `index`.
+    fn read_page(
+        &self,
+        index: usize,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<Page>> + Send;
+
+    /// The checksum page `index` was stored with, as
+    /// [`PageSource::checksum`].
+    fn checksum(&self, _index: usize) -> Option<u32> {
+        None
+    }
+}
+
+/// Serves a blocking [`PageSource`] from tokio's blocking thread pool.
+pub struct Blocking<S>(pub Arc<S>);
+
+impl<S: PageSource + 'static> AsyncPageSource for Blocking<S> {
+    async fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        let source = self.0.clone();
+        let mut page = vec![0; buf.len()];
+        let (result, page) = tokio::task::spawn_blocking(move || {
+            let result = source.read_page(index, &mut page);
+            (result, page)
+        })
+        .await
+        .map_err(io::Error::other)?;
+        buf.copy_from_slice(&page);
+        result
+    }
+
+    fn checksum(&self, index: usize) -> Option<u32> {
+        self.0.checksum(index)
+    }
+}
+
+/// A page fetched from the source, with the checksum to verify it by. A
+/// failed read is staged too, for the handler's [`CorruptPolicy`] to deal
+/// with as it would in blocking mode.
+///
+/// [`CorruptPolicy`]: crate::CorruptPolicy
+struct StagedPage {
+    page: io::Result<Page>,
+    data: Vec<u8>,
+    checksum: Option<u32>,
+}
+
+/// Pages fetched for the fault being handled.
+#[derive(Default)]
+struct Staged {
+    pages: Mutex<HashMap<usize, StagedPage>>,
+}
+
+impl PageSource for Staged {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        match self.pages.lock().unwrap().get(&index) {
+            Some(staged) => match &staged.page {
+                Ok(page) => {
+                    buf.copy_from_slice(&staged.data);
+                    Ok(*page)
+                }
+                // io::Error is not Clone, and only its kind and message
+                // reach the handler's policy.
+                Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            },
+            // Only pages the fault was expected to read are staged.
+            None => Ok(Page::Absent),
+        }
+    }
+
+    fn checksum(&self, index: usize) -> Option<u32> {
+        let pages = self.pages.lock().unwrap();
+        pages.get(&index).and_then(|staged| staged.checksum)
+    }
+}
+
+/// An [`Event`] with its addresses as integers. Events carry raw pointers,
+/// so a batch is held in this form across the awaits that resolve its
+/// faults, for the future to stay Send.
+enum Queued {
+    Pagefault {
+        kind: FaultKind,
+        rw: ReadWrite,
+        addr: usize,
+    },
+    Fork {
+        uffd: Uffd,
+    },
+    Remap {
+        from: usize,
+        to: usize,
+        len: usize,
+    },
+    Remove {
+        start: usize,
+        end: usize,
+    },
+    Unmap {
+        start: usize,
+        end: usize,
+    },
+}
+
+impl From<Event> for Queued {
+    fn from(event: Event) -> Self {
+        match event {
+            Event::Pagefault { kind, rw, addr } => Queued::Pagefault {
+                kind,
+                rw,
+                addr: addr as usize,
+            },
+            Event::Fork { uffd } => Queued::Fork { uffd },
+            Event::Remap { from, to, len } => Queued::Remap {
+                from: from as usize,
+                to: to as usize,
+                len,
+            },
+            Event::Remove { start, end } => Queued::Remove {
+                start: start as usize,
+                end: end as usize,
+            },
+            Event::Unmap { start, end } => Queued::Unmap {
+                start: start as usize,
+                end: end as usize,
+            },
+        }
+    }
+}
+
+impl From<Queued> for Event {
+    fn from(event: Queued) -> Self {
+        match event {
+            Queued::Pagefault { kind, rw, addr } => Event::Pagefault {
+                kind,
+                rw,
+                addr: addr as *mut c_void,
+            },
+            Queued::Fork { uffd } => Event::Fork { uffd },
+            Queued::Remap { from, to, len } => Event::Remap {
+                from: from as *mut c_void,
+                to: to as *mut c_void,
+                len,
+            },
+            Queued::Remove { start, end } => Event::Remove {
+                start: start as *mut c_void,
+                end: end as *mut c_void,
+            },
+            Queued::Unmap { start, end } => Event::Unmap {
+                start: start as *mut c_void,
+                end: end as *mut c_void,
+            },
+        }
+    }
+}
+
+/// A borrowed uffd, registered with the runtime while its target lives.
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// A [`FaultHandler`] whose events are awaited and whose pages come from
+/// an [`AsyncPageSource`].
+pub struct AsyncFaultHandler<S> {
+    handler: FaultHandler,
+    source: S,
+    staged: Arc<Staged>,
+}
+
+impl<S: AsyncPageSource> AsyncFaultHandler<S> {
+    /// Wraps `handler`, whose uffd must be non-blocking, to fill faults
+    /// from `source` as [`FaultHandler::page_source`] would.
+    ///
+    /// Any page source already set on `handler` is replaced.
+    pub fn new(handler: FaultHandler, source: S, memfd: Option<File>) -> Self {
+        let staged = Arc::new(Staged::default());
+        Self {
+            handler: handler.page_source(staged.clone(), memfd),
+            source,
+            staged,
+        }
+    }
+
+    /// Awaits events on every uffd and handles them until an error occurs.
+    ///
+    /// A target whose process has exited is dropped; the future completes
+    /// once no target is left.
+    pub async fn run(mut self) -> Result<()> {
+        let mut fds = Vec::new();
+        while self.handler.target_count() > 0 {
+            // Forked children are appended to the targets.
+            for index in fds.len()..self.handler.target_count() {
+                fds.push(AsyncFd::new(Fd(self.handler.target_fd(index)))?);
+            }
+
+            let (index, events) = {
+                let (index, mut guard) = poll_fn(|cx| {
+                    for (index, fd) in fds.iter().enumerate() {
+                        if let Poll::Ready(guard) = fd.poll_read_ready(cx) {
+                            return Poll::Ready(guard.map(|guard| (index, guard)));
+                        }
+                    }
+                    Poll::Pending
+                })
+                .await?;
+
+                if guard.ready().is_error() {
+                    drop(guard);
+                    fds.remove(index);
+                    self.handler.drop_target(index);
+                    continue;
+                }
+                // Read every queued message, up to the batch size, and
+                // resolve them all before waiting again.
+                let events: Vec<Queued> = self
+                    .handler
+                    .read_target_events(index)?
+                    .into_iter()
+                    .map(Queued::from)
+                    .collect();
+                if events.is_empty() {
+                    guard.clear_ready();
+                    continue;
+                }
+                (index, events)
+            };
+            // Latency counts from here, so it includes fetching the pages.
+            let read = Instant::now();
+
+            for event in events {
+                let result = match event {
+                    Queued::Pagefault { kind, rw, addr } => {
+                        self.handle_pagefault(index, kind, rw, addr, read).await
+                    }
+                    event => self.handler.handle_target_event(index, event.into(), read),
+                };
+                match result {
+                    Err(Error::Resolve {
+                        class: ErrorClass::TargetExited,
+                        ..
+                    }) => {
+                        // The rest of the batch was for the exited process
+                        // too.
+                        fds.remove(index);
+                        self.handler.drop_target(index);
+                        break;
+                    }
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_pagefault(
+        &mut self,
+        index: usize,
+        kind: FaultKind,
+        rw: ReadWrite,
+        addr: usize,
+        read: Instant,
+    ) -> Result<()> {
+        self.stage(self.handler.wanted_pages(index, kind, addr))
+            .await;
+        let event = Event::Pagefault {
+            kind,
+            rw,
+            addr: addr as *mut c_void,
+        };
+        let result = self.handler.handle_target_event(index, event, read);
+        self.staged.pages.lock().unwrap().clear();
+        result
+    }
+
+    /// Fetches `pages` from the source into the staging area.
+    async fn stage(&self, pages: Vec<usize>) {
+        for index in pages {
+            let mut data = vec![0; self.handler.page_size()];
+            let page = self.source.read_page(index, &mut data).await;
+            let checksum = self.source.checksum(index);
+            self.staged.pages.lock().unwrap().insert(
+                index,
+                StagedPage {
+                    page,
+                    data,
+                    checksum,
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::ops::Range;
+
+/// One bit per page of a region.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageBitmap {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl PageBitmap {
+    /// Creates a bitmap of `len` pages, all clear.
+    pub fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(64)],
+            len,
+        }
+    }
+
+    /// Number of pages covered.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, page: usize) -> bool {
+        page < self.len && self.words[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    pub fn set(&mut self, page: usize) {
+        assert!(page < self.len, "page {} out of range", page);
+        self.words[page / 64] |= 1 << (page % 64);
+    }
+
+    pub fn clear(&mut self, page: usize) {
+        assert!(page < self.len, "page {} out of range", page);
+        self.words[page / 64] &= !(1 << (page % 64));
+    }
+
+    pub fn set_range(&mut self, pages: Range<usize>) {
+        for page in pages {
+            self.set(page);
+        }
+    }
+
+    pub fn clear_range(&mut self, pages: Range<usize>) {
+        for page in pages {
+            self.clear(page);
+        }
+    }
+
+    /// Clears every bit.
+    pub fn clear_all(&mut self) {
+        self.words.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Sets every page that is set in `other`.
+    pub fn union_with(&mut self, other: &PageBitmap) {
+        assert_eq!(self.len, other.len, "bitmaps cover different regions");
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Number of set pages.
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Indices of set pages, in ascending order.
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(move |&page| self.get(page))
+    }
+
+    /// Serialises the bitmap as little-endian 64-bit words.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.words
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+
+    /// Reads a bitmap of `len` pages written by [`PageBitmap::to_bytes`].
+    pub fn from_bytes(len: usize, bytes: &[u8]) -> Option<Self> {
+        let words = len.div_ceil(64);
+        if bytes.len() != words * 8 {
+            return None;
+        }
+        let words = bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        Some(Self { words, len })
+    }
+
+    /// Number of set pages before `page`, given the per-word counts from
+    /// [`PageBitmap::word_ranks`].
+    pub fn rank(&self, ranks: &[u64], page: usize) -> u64 {
+        let below = self.words[page / 64] & ((1u64 << (page % 64)) - 1);
+        ranks[page / 64] + below.count_ones() as u64
+    }
+
+    /// Number of set pages before each 64-page word.
+    pub fn word_ranks(&self) -> Vec<u64> {
+        let mut total = 0;
+        self.words
+            .iter()
+            .map(|word| {
+                let rank = total;
+                total += word.count_ones() as u64;
+                rank
+            })
+            .collect()
+    }
+
+    /// Maximal runs of consecutive clear pages within `pages`.
+    pub fn clear_runs(&self, pages: Range<usize>) -> Vec<Range<usize>> {
+        let mut runs = Vec::new();
+        let mut start = None;
+        for page in pages.clone() {
+            match (self.get(page), start) {
+                (false, None) => start = Some(page),
+                (true, Some(s)) => {
+                    runs.push(s..page);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            runs.push(s..pages.end);
+        }
+        runs
+    }
+}
@@ -0,0 +1,332 @@
+//! Compressed memory images: a flat image cut into independently
+//! compressed blocks, so serving a page only decompresses its block.
+//!
+//! A compressed file is laid out as:
+//!
+//! ```text
+//! magic        8 bytes  "UFFDCMPR"
+//! version      u32
+//! page_size    u32
+//! block_size   u32      bytes of image per block, a multiple of page_size
+//! reserved     u32
+//! len          u64      bytes of image
+//! block_count  u64
+//! index        block_count * (offset u64, stored_len u32)
+//! data         the blocks, in order
+//! ```
+//!
+//! All integers are little-endian. Blocks are compressed with LZ4. A block
+//! stored at its full length did not compress and is kept as is; one
+//! stored with length 0 is all zeroes. The last block may be shorter than
+//! `block_size`.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{is_zero, Page, PageSource};
+
+const MAGIC: &[u8; 8] = b"UFFDCMPR";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 40;
+const ENTRY_LEN: usize = 12;
+
+/// Block size used unless the writer is told otherwise.
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Decompressed blocks kept unless [`CompressedFile::cache_blocks`] says
+/// otherwise.
+const DEFAULT_CACHE_BLOCKS: usize = 16;
+
+/// What [`write_compressed`] produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressStats {
+    pub blocks: usize,
+    /// Blocks stored as a zero-length entry.
+    pub zero_blocks: usize,
+    /// Bytes of image read.
+    pub raw_bytes: u64,
+    /// Bytes written, header and index included.
+    pub stored_bytes: u64,
+}
+
+impl CompressStats {
+    pub fn ratio(&self) -> f64 {
+        match self.stored_bytes {
+            0 => 0.0,
+            stored => self.raw_bytes as f64 / stored as f64,
+        }
+    }
+}
+
+/// Compresses the `len` bytes of `memory`, a flat image such as a memfd,
+/// into `out` in blocks of `block_size` bytes.
+pub fn write_compressed(
+    out: &mut (impl Write + Seek),
+    memory: &File,
+    len: u64,
+    page_size: usize,
+    block_size: usize,
+) -> io::Result<CompressStats> {
+    assert!(
+        block_size >= page_size && block_size % page_size == 0,
+        "blocks must hold whole pages"
+    );
+    let block_count = (len as usize).div_ceil(block_size);
+
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(page_size as u32).to_le_bytes())?;
+    out.write_all(&(block_size as u32).to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(&(block_count as u64).to_le_bytes())?;
+    // The index is filled in once the blocks are written.
+    out.write_all(&vec![0; block_count * ENTRY_LEN])?;
+
+    let mut offset = HEADER_LEN + (block_count * ENTRY_LEN) as u64;
+    let mut index = Vec::with_capacity(block_count * ENTRY_LEN);
+    let mut stats = CompressStats {
+        blocks: block_count,
+        zero_blocks: 0,
+        raw_bytes: len,
+        stored_bytes: 0,
+    };
+    let mut buf = vec![0; block_size];
+
+    for block in 0..block_count {
+        let start = (block * block_size) as u64;
+        let raw = &mut buf[..(len - start).min(block_size as u64) as usize];
+        memory.read_exact_at(raw, start)?;
+
+        let compressed;
+        let stored: &[u8] = if is_zero(raw) {
+            stats.zero_blocks += 1;
+            &[]
+        } else {
+            compressed = lz4_flex::block::compress(raw);
+            if compressed.len() < raw.len() {
+                &compressed
+            } else {
+                raw
+            }
+        };
+        out.write_all(stored)?;
+        index.extend_from_slice(&offset.to_le_bytes());
+        index.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        offset += stored.len() as u64;
+    }
+
+    out.seek(SeekFrom::Start(HEADER_LEN))?;
+    out.write_all(&index)?;
+    out.seek(SeekFrom::Start(offset))?;
+    stats.stored_bytes = offset;
+    Ok(stats)
+}
+
+/// A compressed image opened for reading, served as a [`PageSource`].
+///
+/// Pages past the end of the image are absent.
+pub struct CompressedFile {
+    file: File,
+    page_size: usize,
+    block_size: usize,
+    len: u64,
+    /// Where each block is stored and how many bytes it takes.
+    index: Vec<(u64, u32)>,
+    cache: Mutex<BlockCache>,
+    cache_hits: AtomicU64,
+    decompressed: AtomicU64,
+}
+
+/// The most recently used decompressed blocks, newest last.
+struct BlockCache {
+    capacity: usize,
+    blocks: Vec<(usize, Arc<Vec<u8>>)>,
+}
+
+impl CompressedFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_file(File::open(path)?)
+    }
+
+    pub fn from_file(mut file: File) -> io::Result<Self> {
+        let mut header = [0; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not a compressed image"));
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        if u32_at(8) != VERSION {
+            return Err(invalid("unsupported compressed image version"));
+        }
+        let page_size = u32_at(12) as usize;
+        let block_size = u32_at(16) as usize;
+        let len = u64_at(24);
+        let block_count = u64_at(32);
+        if !page_size.is_power_of_two() {
+            return Err(invalid("page size is not a power of two"));
+        }
+        if block_size == 0 || block_size % page_size != 0 {
+            return Err(invalid("bad block size"));
+        }
+        if block_count != len.div_ceil(block_size as u64) {
+            return Err(invalid("block count does not match length"));
+        }
+
+        // Check the index against the file before allocating anything for
+        // it.
+        let file_len = file.metadata()?.len();
+        let truncated = || invalid("image is shorter than its header says");
+        let data_offset = block_count
+            .checked_mul(ENTRY_LEN as u64)
+            .and_then(|len| len.checked_add(HEADER_LEN))
+            .filter(|&end| end <= file_len)
+            .ok_or_else(truncated)?;
+
+        let mut entries = vec![0; (data_offset - HEADER_LEN) as usize];
+        file.read_exact(&mut entries)?;
+        let index = entries
+            .chunks_exact(ENTRY_LEN)
+            .enumerate()
+            .map(|(block, entry)| {
+                let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let stored = u32::from_le_bytes(entry[8..].try_into().unwrap());
+                let raw_len = (len - (block * block_size) as u64).min(block_size as u64);
+                if offset < data_offset || stored as u64 > raw_len {
+                    return Err(invalid("bad index entry"));
+                }
+                match offset.checked_add(stored as u64) {
+                    Some(end) if end <= file_len => Ok((offset, stored)),
+                    _ => Err(truncated()),
+                }
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            file,
+            page_size,
+            block_size,
+            len,
+            index,
+            cache: Mutex::new(BlockCache {
+                capacity: DEFAULT_CACHE_BLOCKS,
+                blocks: Vec::new(),
+            }),
+            cache_hits: AtomicU64::new(0),
+            decompressed: AtomicU64::new(0),
+        })
+    }
+
+    /// Keeps up to `blocks` decompressed blocks around instead of 16.
+    pub fn cache_blocks(self, blocks: usize) -> Self {
+        self.cache.lock().unwrap().capacity = blocks.max(1);
+        self
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Bytes of image, uncompressed.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads of a page whose block was already decompressed.
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Blocks decompressed so far, counting each time one is evicted and
+    /// needed again.
+    pub fn blocks_decompressed(&self) -> u64 {
+        self.decompressed.load(Ordering::Relaxed)
+    }
+
+    /// Block `block`, decompressed, from the cache if it is there.
+    fn block(&self, block: usize) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(data) = self.cache.lock().unwrap().get(block) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+
+        // Decompress without holding the lock; two faults racing for the
+        // same block both do the work, and the second insert wins.
+        let (offset, stored) = self.index[block];
+        let start = (block * self.block_size) as u64;
+        let raw_len = (self.len - start).min(self.block_size as u64) as usize;
+        let mut stored_data = vec![0; stored as usize];
+        self.file.read_exact_at(&mut stored_data, offset)?;
+        let data = if stored as usize == raw_len {
+            stored_data
+        } else {
+            let mut data = vec![0; raw_len];
+            let written = lz4_flex::block::decompress_into(&stored_data, &mut data)
+                .map_err(|_| invalid("corrupt block"))?;
+            if written != raw_len {
+                return Err(invalid("short block"));
+            }
+            data
+        };
+        self.decompressed.fetch_add(1, Ordering::Relaxed);
+
+        let data = Arc::new(data);
+        self.cache.lock().unwrap().insert(block, data.clone());
+        Ok(data)
+    }
+}
+
+impl PageSource for CompressedFile {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        let offset = (index * buf.len()) as u64;
+        if offset >= self.len {
+            return Ok(Page::Absent);
+        }
+        let block = offset as usize / self.block_size;
+        if self.index[block].1 == 0 {
+            return Ok(Page::Zero);
+        }
+
+        let data = self.block(block)?;
+        let start = offset as usize % self.block_size;
+        let available = (data.len() - start).min(buf.len());
+        buf[..available].copy_from_slice(&data[start..start + available]);
+        buf[available..].fill(0);
+        Ok(Page::Data)
+    }
+}
+
+impl BlockCache {
+    fn get(&mut self, block: usize) -> Option<Arc<Vec<u8>>> {
+        let at = self.blocks.iter().position(|(b, _)| *b == block)?;
+        let entry = self.blocks.remove(at);
+        let data = entry.1.clone();
+        self.blocks.push(entry);
+        Some(data)
+    }
+
+    fn insert(&mut self, block: usize, data: Arc<Vec<u8>>) {
+        self.blocks.retain(|(b, _)| *b != block);
+        if self.blocks.len() == self.capacity {
+            self.blocks.remove(0);
+        }
+        self.blocks.push((block, data));
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
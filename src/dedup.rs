@@ -0,0 +1,304 @@
+//! Content-addressed page storage: snapshots of VMs booted from the same
+//! image share most of their pages, so each distinct page is stored once,
+//! keyed by its hash, and every snapshot is a manifest of hashes.
+//!
+//! A store file is laid out as:
+//!
+//! ```text
+//! magic      8 bytes  "UFFDPGST"
+//! version    u32
+//! page_size  u32
+//! records    until the end of the file:
+//!   hash     32 bytes  BLAKE3 of the page
+//!   data     page_size bytes
+//! ```
+//!
+//! and a manifest file as:
+//!
+//! ```text
+//! magic        8 bytes  "UFFDMNFT"
+//! version      u32
+//! page_size    u32
+//! total_pages  u64
+//! hashes       total_pages * 32 bytes, page 0 first
+//! ```
+//!
+//! All integers are little-endian. Pages that are all zeroes are never
+//! stored; their hash is recognised instead. A store is only appended to,
+//! and a record cut short by a crash is overwritten by the next insert.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::{is_zero, Page, PageSource};
+
+const STORE_MAGIC: &[u8; 8] = b"UFFDPGST";
+const MANIFEST_MAGIC: &[u8; 8] = b"UFFDMNFT";
+const VERSION: u32 = 1;
+const STORE_HEADER_LEN: u64 = 16;
+
+/// The hash a page is stored under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PageHash(pub [u8; 32]);
+
+impl PageHash {
+    pub fn of(page: &[u8]) -> Self {
+        Self(*blake3::hash(page).as_bytes())
+    }
+}
+
+/// Distinct pages, keyed by [`PageHash`], shared by any number of
+/// snapshots and handlers.
+pub struct PageStore {
+    file: File,
+    page_size: usize,
+    zero: PageHash,
+    /// Where each stored page's record starts.
+    index: RwLock<HashMap<PageHash, u64>>,
+    /// End of the last complete record; held while appending.
+    end: Mutex<u64>,
+}
+
+/// What [`write_snapshot`] did with each page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Pages new to the store.
+    pub stored: usize,
+    /// Pages the store already had.
+    pub shared: usize,
+    /// Pages that were all zeroes.
+    pub zero: usize,
+}
+
+impl PageStore {
+    /// Creates an empty store at `path` for pages of `page_size` bytes,
+    /// replacing any file there.
+    pub fn create(path: impl AsRef<Path>, page_size: usize) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.write_all(STORE_MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        file.write_all(&(page_size as u32).to_le_bytes())?;
+        Ok(Self::with_index(
+            file,
+            page_size,
+            HashMap::new(),
+            STORE_HEADER_LEN,
+        ))
+    }
+
+    /// Opens an existing store for reading and adding pages.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = [0; STORE_HEADER_LEN as usize];
+        file.read_exact_at(&mut header, 0)?;
+        if &header[..8] != STORE_MAGIC {
+            return Err(invalid("not a page store"));
+        }
+        if u32::from_le_bytes(header[8..12].try_into().unwrap()) != VERSION {
+            return Err(invalid("unsupported page store version"));
+        }
+        let page_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+
+        let record_len = (32 + page_size) as u64;
+        let len = file.metadata()?.len();
+        let mut index = HashMap::new();
+        let mut end = STORE_HEADER_LEN;
+        let mut hash = [0; 32];
+        while end + record_len <= len {
+            file.read_exact_at(&mut hash, end)?;
+            index.insert(PageHash(hash), end);
+            end += record_len;
+        }
+        Ok(Self::with_index(file, page_size, index, end))
+    }
+
+    fn with_index(file: File, page_size: usize, index: HashMap<PageHash, u64>, end: u64) -> Self {
+        Self {
+            file,
+            page_size,
+            zero: PageHash::of(&vec![0; page_size]),
+            index: RwLock::new(index),
+            end: Mutex::new(end),
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Distinct pages stored.
+    pub fn len(&self) -> usize {
+        self.index.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stores `page` unless the store already has it, and returns its
+    /// hash along with whether it was new.
+    pub fn insert(&self, page: &[u8]) -> io::Result<(PageHash, bool)> {
+        assert_eq!(page.len(), self.page_size);
+        if is_zero(page) {
+            return Ok((self.zero, false));
+        }
+        let hash = PageHash::of(page);
+        if self.index.read().unwrap().contains_key(&hash) {
+            return Ok((hash, false));
+        }
+
+        let mut end = self.end.lock().unwrap();
+        // Another writer may have added it while we hashed.
+        if self.index.read().unwrap().contains_key(&hash) {
+            return Ok((hash, false));
+        }
+        let mut record = Vec::with_capacity(32 + page.len());
+        record.extend_from_slice(&hash.0);
+        record.extend_from_slice(page);
+        self.file.write_all_at(&record, *end)?;
+        self.index.write().unwrap().insert(hash, *end);
+        *end += record.len() as u64;
+        Ok((hash, true))
+    }
+
+    /// Reads the page stored under `hash` into `buf`.
+    pub fn read(&self, hash: &PageHash, buf: &mut [u8]) -> io::Result<Page> {
+        if *hash == self.zero {
+            return Ok(Page::Zero);
+        }
+        let offset = match self.index.read().unwrap().get(hash) {
+            Some(&offset) => offset,
+            None => return Ok(Page::Absent),
+        };
+        self.file.read_exact_at(buf, offset + 32)?;
+        Ok(Page::Data)
+    }
+}
+
+/// The pages of one snapshot, by hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub page_size: usize,
+    pub hashes: Vec<PageHash>,
+}
+
+impl Manifest {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MANIFEST_MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(self.page_size as u32).to_le_bytes())?;
+        out.write_all(&(self.hashes.len() as u64).to_le_bytes())?;
+        for hash in &self.hashes {
+            out.write_all(&hash.0)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(input: &mut impl Read) -> io::Result<Self> {
+        let mut header = [0; 24];
+        input.read_exact(&mut header)?;
+        if &header[..8] != MANIFEST_MAGIC {
+            return Err(invalid("not a snapshot manifest"));
+        }
+        if u32::from_le_bytes(header[8..12].try_into().unwrap()) != VERSION {
+            return Err(invalid("unsupported manifest version"));
+        }
+        let page_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        let total_pages = u64::from_le_bytes(header[16..24].try_into().unwrap());
+
+        let mut hashes = Vec::new();
+        let mut hash = [0; 32];
+        for _ in 0..total_pages {
+            input.read_exact(&mut hash)?;
+            hashes.push(PageHash(hash));
+        }
+        Ok(Self { page_size, hashes })
+    }
+}
+
+/// Adds every page of the `len` bytes of `memory`, typically a
+/// [`MemfdRegion`](crate::MemfdRegion)'s memfd, to `store` and returns the
+/// snapshot's manifest.
+pub fn write_snapshot(
+    store: &PageStore,
+    memory: &File,
+    len: usize,
+) -> io::Result<(Manifest, DedupStats)> {
+    let page_size = store.page_size();
+    let mut stats = DedupStats::default();
+    let mut hashes = Vec::with_capacity(len / page_size);
+    let mut buf = vec![0; page_size];
+
+    for page in 0..len / page_size {
+        memory.read_exact_at(&mut buf, (page * page_size) as u64)?;
+        let (hash, new) = store.insert(&buf)?;
+        if hash == store.zero {
+            stats.zero += 1;
+        } else if new {
+            stats.stored += 1;
+        } else {
+            stats.shared += 1;
+        }
+        hashes.push(hash);
+    }
+    Ok((Manifest { page_size, hashes }, stats))
+}
+
+/// One snapshot served from a shared store.
+///
+/// Pages past the end of the manifest, or whose hash the store lacks, are
+/// absent. A stored page that no longer hashes to what the manifest says
+/// is an error.
+pub struct StoreSource {
+    store: Arc<PageStore>,
+    manifest: Manifest,
+}
+
+impl StoreSource {
+    pub fn new(store: Arc<PageStore>, manifest: Manifest) -> io::Result<Self> {
+        if manifest.page_size != store.page_size() {
+            return Err(invalid("manifest page size differs from the store's"));
+        }
+        Ok(Self { store, manifest })
+    }
+}
+
+impl PageSource for StoreSource {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        match self.manifest.hashes.get(index) {
+            Some(hash) => {
+                let page = self.store.read(hash, buf)?;
+                if page == Page::Data && PageHash::of(buf) != *hash {
+                    return Err(invalid("stored page does not match its hash"));
+                }
+                Ok(page)
+            }
+            None => Ok(Page::Absent),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,153 @@
+use std::{
+    ffi::c_void,
+    ops::Range,
+    os::unix::prelude::{AsRawFd, FromRawFd},
+    sync::{Arc, Mutex},
+};
+
+use nix::unistd::dup;
+use userfaultfd::{ReadWrite, Uffd};
+
+use crate::{bitmap::PageBitmap, page_size, region::VmRegion, Result};
+
+/// Records which pages of a region were written, using write-protect
+/// faults.
+///
+/// The region must be registered with `RegisterMode::WRITE_PROTECT`. Every
+/// page starts out protected; the first write to it faults, the handler
+/// marks it dirty and lifts the protection. [`DirtyTracker::take`] hands
+/// out the dirty set and protects those pages again for the next round.
+///
+/// Clones share the same dirty set.
+#[derive(Clone)]
+pub struct DirtyTracker {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// A second descriptor for the handler's uffd.
+    uffd: Uffd,
+    addr: usize,
+    /// Pages in the tracked region.
+    len: usize,
+    page_size: usize,
+    /// Held across every protect/unprotect so a page's protection always
+    /// matches whether it is in the current set.
+    dirty: Mutex<PageBitmap>,
+}
+
+impl DirtyTracker {
+    /// Starts tracking `region`, registered with `uffd`, by write-protecting
+    /// all of it.
+    pub fn new(uffd: &Uffd, region: &VmRegion) -> Result<Self> {
+        let fd = dup(uffd.as_raw_fd())?;
+        let uffd = unsafe { Uffd::from_raw_fd(fd) };
+        let page_size = page_size();
+
+        uffd.write_protect(region.as_ptr(), region.len())?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                uffd,
+                addr: region.as_ptr() as usize,
+                len: region.len() / page_size,
+                page_size,
+                dirty: Mutex::new(PageBitmap::new(region.len() / page_size)),
+            }),
+        })
+    }
+
+    /// Returns the pages written since the last call and write-protects
+    /// them again.
+    pub fn take(&self) -> Result<PageBitmap> {
+        let inner = &self.inner;
+        let mut dirty = inner.dirty.lock().unwrap();
+
+        for run in runs(&dirty) {
+            let (addr, len) = inner.span(run);
+            inner.uffd.write_protect(addr, len)?;
+        }
+
+        let fresh = PageBitmap::new(dirty.len());
+        Ok(std::mem::replace(&mut *dirty, fresh))
+    }
+
+    /// Number of pages dirtied since the last [`DirtyTracker::take`].
+    pub fn count(&self) -> usize {
+        self.inner.dirty.lock().unwrap().count_ones()
+    }
+
+    /// Handles a write-protect fault at `addr`: marks its page dirty, lifts
+    /// the protection and wakes the writer. Returns false, doing nothing,
+    /// if `addr` is outside the tracked region.
+    pub(crate) fn record(&self, uffd: &Uffd, addr: usize) -> Result<bool> {
+        let inner = &self.inner;
+        let page = match inner.page_of(addr) {
+            Some(page) => page,
+            None => return Ok(false),
+        };
+        let mut dirty = inner.dirty.lock().unwrap();
+
+        dirty.set(page);
+        let (addr, len) = inner.span(page..page + 1);
+        uffd.remove_write_protection(addr, len, true)?;
+        Ok(true)
+    }
+
+    /// Called after the page-aligned `block` was first mapped for a fault
+    /// at `fault_addr`, before the faulting thread is woken.
+    ///
+    /// Freshly mapped pages come in writable, so a write fault's own page
+    /// is dirty already and every other page is protected again.
+    pub(crate) fn after_fill(
+        &self,
+        uffd: &Uffd,
+        block: Range<usize>,
+        fault_addr: usize,
+        rw: ReadWrite,
+    ) -> Result<()> {
+        let inner = &self.inner;
+        let pages = match (inner.page_of(block.start), inner.page_of(block.end - 1)) {
+            (Some(first), Some(last)) => first..last + 1,
+            _ => return Ok(()),
+        };
+        let mut dirty = inner.dirty.lock().unwrap();
+
+        let protect = match inner.page_of(fault_addr) {
+            Some(fault_page) if rw == ReadWrite::Write => {
+                dirty.set(fault_page);
+                vec![pages.start..fault_page, fault_page + 1..pages.end]
+            }
+            _ => vec![pages],
+        };
+        for run in protect.into_iter().filter(|run| !run.is_empty()) {
+            let (addr, len) = inner.span(run);
+            uffd.write_protect(addr, len)?;
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn page_of(&self, addr: usize) -> Option<usize> {
+        let page = addr.checked_sub(self.addr)? / self.page_size;
+        (page < self.len).then_some(page)
+    }
+
+    fn span(&self, pages: Range<usize>) -> (*mut c_void, usize) {
+        let addr = self.addr + pages.start * self.page_size;
+        (addr as *mut c_void, pages.len() * self.page_size)
+    }
+}
+
+/// Runs of consecutive set pages.
+fn runs(bitmap: &PageBitmap) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for page in bitmap.iter_ones() {
+        match runs.last_mut() {
+            Some(run) if run.end == page => run.end += 1,
+            _ => runs.push(page..page + 1),
+        }
+    }
+    runs
+}
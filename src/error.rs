@@ -0,0 +1,124 @@
+use std::{fmt, io};
+
+use crate::retry::ErrorClass;
+
+/// Errors returned by the fault handling library.
+#[derive(Debug)]
+pub enum Error {
+    /// A userfaultfd operation failed.
+    Uffd(userfaultfd::Error),
+    /// A raw system call (mmap, memfd_create, poll, ...) failed.
+    Sys(nix::Error),
+    /// Reading or writing a backing file failed.
+    Io(io::Error),
+    /// A peer sent a malformed or unexpected message.
+    Protocol(String),
+    /// A fault arrived for an address outside every known region.
+    OutOfRegion { addr: usize },
+    /// A region table entry is empty or overlaps another.
+    InvalidRegion(String),
+    /// A fault could not be resolved within the retry policy.
+    Resolve {
+        addr: usize,
+        class: ErrorClass,
+        attempts: u32,
+    },
+    /// A source page, numbered by backing file offset / page size, does
+    /// not match the checksum it was stored with.
+    Corrupt {
+        page: usize,
+        expected: u32,
+        actual: u32,
+    },
+    /// The worker threads of a multi-threaded handler exited early, which
+    /// only happens when one panics.
+    WorkersExited,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Uffd(err) => write!(f, "userfaultfd: {}", err),
+            Error::Sys(err) => write!(f, "system call: {}", err),
+            Error::Io(err) => write!(f, "io: {}", err),
+            Error::Protocol(msg) => write!(f, "protocol: {}", msg),
+            Error::OutOfRegion { addr } => {
+                write!(f, "fault at {:#x} is outside every region", addr)
+            }
+            Error::InvalidRegion(msg) => write!(f, "invalid region: {}", msg),
+            Error::Resolve {
+                addr,
+                class,
+                attempts,
+            } => write!(
+                f,
+                "resolving fault at {:#x}: {} after {} attempt(s)",
+                addr, class, attempts
+            ),
+            Error::Corrupt {
+                page,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "page {} has checksum {:#010x}, expected {:#010x}",
+                page, actual, expected
+            ),
+            Error::WorkersExited => write!(f, "fault workers exited"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Uffd(err) => Some(err),
+            Error::Sys(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Protocol(_)
+            | Error::OutOfRegion { .. }
+            | Error::InvalidRegion(_)
+            | Error::Resolve { .. }
+            | Error::Corrupt { .. }
+            | Error::WorkersExited => None,
+        }
+    }
+}
+
+impl From<userfaultfd::Error> for Error {
+    fn from(err: userfaultfd::Error) -> Self {
+        Error::Uffd(err)
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(err: nix::Error) -> Self {
+        Error::Sys(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// The errno behind a failed uffd ioctl, whether made through the
+/// userfaultfd crate or directly.
+///
+/// userfaultfd variants that do not carry one fall back to the thread's
+/// errno, so this must be called right after the failing call.
+pub(crate) fn uffd_errno(err: &Error) -> Option<i32> {
+    match err {
+        Error::Sys(errno) => Some(*errno as i32),
+        Error::Uffd(
+            userfaultfd::Error::CopyFailed(errno)
+            | userfaultfd::Error::ZeropageFailed(errno)
+            | userfaultfd::Error::SystemError(errno),
+        ) => Some(*errno as i32),
+        Error::Uffd(_) => io::Error::last_os_error().raw_os_error(),
+        _ => None,
+    }
+}
@@ -0,0 +1,160 @@
+//! The handshake Firecracker uses to hand guest memory to a page fault
+//! handler.
+//!
+//! Firecracker connects to a Unix socket the handler listens on and sends
+//! a single message: a JSON array of [`GuestRegionUffdMapping`]s as the
+//! payload and the uffd, already registered on those regions, as an
+//! SCM_RIGHTS control message.
+
+use std::{
+    fs::File,
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::Path,
+};
+
+use nix::sys::{
+    socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+    uio::IoVec,
+};
+use serde::{Deserialize, Serialize};
+use userfaultfd::{RegisterMode, Uffd};
+
+use crate::{trace::TraceRecorder, Error, FaultHandler, Region, RegionTable, Result, SnapshotFile};
+
+/// One guest memory region as described by Firecracker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestRegionUffdMapping {
+    /// Start of the region in Firecracker's address space.
+    pub base_host_virt_addr: u64,
+    /// Length of the region in bytes.
+    pub size: usize,
+    /// Offset of the region's contents in the memory snapshot file.
+    pub offset: u64,
+    /// Page size in KiB, as sent by Firecracker before v1.7.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size_kib: Option<usize>,
+    /// Page size in bytes, as sent by later Firecracker versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<usize>,
+}
+
+impl GuestRegionUffdMapping {
+    /// The page size in bytes, whichever field carried it.
+    pub fn page_bytes(&self) -> usize {
+        self.page_size
+            .or(self.page_size_kib.map(|kib| kib * 1024))
+            .unwrap_or_else(crate::page_size)
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.base_host_virt_addr && addr - self.base_host_virt_addr < self.size as u64
+    }
+}
+
+/// What a connecting Firecracker sent.
+pub struct Handshake {
+    pub uffd: Uffd,
+    pub mappings: Vec<GuestRegionUffdMapping>,
+    /// The connection, kept open for as long as the VM runs.
+    pub stream: UnixStream,
+}
+
+/// Accepts one connection on `listener` and reads its handshake.
+pub fn accept(listener: &UnixListener) -> Result<Handshake> {
+    let (stream, _) = listener.accept()?;
+    receive(stream)
+}
+
+/// Reads the handshake message from `stream`.
+pub fn receive(stream: UnixStream) -> Result<Handshake> {
+    let mut buf = vec![0u8; 16 * 1024];
+    let (len, fd) = {
+        let iov = [IoVec::from_mut_slice(&mut buf)];
+        let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+        let msg = recvmsg(stream.as_raw_fd(), &iov, Some(&mut cmsg), MsgFlags::empty())?;
+        let fd = msg.cmsgs().find_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+            _ => None,
+        });
+        (msg.bytes, fd)
+    };
+
+    let fd = fd.ok_or_else(|| Error::Protocol("handshake carried no uffd".into()))?;
+    let uffd = unsafe { Uffd::from_raw_fd(fd) };
+    let mappings: Vec<GuestRegionUffdMapping> = serde_json::from_slice(&buf[..len])
+        .map_err(|e| Error::Protocol(format!("bad region list: {}", e)))?;
+    if mappings.is_empty() {
+        return Err(Error::Protocol("region list is empty".into()));
+    }
+
+    Ok(Handshake {
+        uffd,
+        mappings,
+        stream,
+    })
+}
+
+/// Sends a handshake the way Firecracker does: `mappings` as JSON with
+/// `uffd` attached.
+pub fn send(stream: &UnixStream, uffd: RawFd, mappings: &[GuestRegionUffdMapping]) -> Result<()> {
+    let body = serde_json::to_vec(mappings).map_err(|e| Error::Protocol(e.to_string()))?;
+    let fds = [uffd];
+    sendmsg(
+        stream.as_raw_fd(),
+        &[IoVec::from_slice(&body)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    Ok(())
+}
+
+/// Builds a handler serving `handshake`'s regions from a flat memory
+/// snapshot, as written by Firecracker's `snapshot create`.
+pub fn handler(handshake: Handshake, snapshot: File) -> Result<FaultHandler> {
+    let page_size = match handshake.mappings.first() {
+        Some(mapping) => mapping.page_bytes(),
+        None => return Err(Error::Protocol("no guest memory regions".into())),
+    };
+
+    // Firecracker registers every region for MISSING faults only.
+    let mut table = RegionTable::new();
+    for m in &handshake.mappings {
+        table.insert(Region {
+            start: m.base_host_virt_addr as usize,
+            len: m.size,
+            offset: m.offset,
+            mode: RegisterMode::MISSING,
+        })?;
+    }
+    let source = SnapshotFile::from_file(snapshot)?;
+    Ok(FaultHandler::with_regions(handshake.uffd, table, page_size).page_source(source, None))
+}
+
+/// Listens on `socket`, waits for Firecracker to connect and serves its
+/// guest memory from `snapshot` until the VM goes away, recording every
+/// fault to `trace` if given.
+pub fn serve(socket: &Path, snapshot: &Path, trace: Option<&Path>) -> Result<()> {
+    let listener = UnixListener::bind(socket)?;
+    let handshake = accept(&listener)?;
+    let snapshot = File::open(snapshot)?;
+    let page_size = handshake.mappings.first().map_or(0, |m| m.page_bytes());
+
+    let mut handler = handler(handshake, snapshot)?;
+    let recorder = match trace {
+        Some(path) => Some(TraceRecorder::create(path, page_size)?),
+        None => None,
+    };
+    if let Some(recorder) = &recorder {
+        handler = handler.trace(recorder.clone());
+    }
+    handler.run()?;
+
+    if let Some(recorder) = recorder {
+        recorder.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1233 @@
+use std::{
+    ffi::c_void,
+    fs::File,
+    io,
+    ops::Range,
+    os::unix::{
+        fs::FileExt,
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    },
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Instant,
+};
+
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    unistd::dup,
+};
+use userfaultfd::{Event, EventBuffer, FaultKind, FeatureFlags, ReadWrite, RegisterMode, Uffd};
+
+use crate::{
+    bitmap::PageBitmap,
+    dirty::DirtyTracker,
+    install::{Install, Scratch},
+    integrity::{checksum, CorruptPolicy},
+    page_size, pool,
+    readahead::Stream,
+    region::{Region, RegionTable, VmRegion},
+    resolver::Resolver,
+    retry::{ErrorClass, Fallback, RetryPolicy},
+    source::{is_zero, Page, PageSource},
+    stats::{EventStats, IntegrityStats, Mismatch, ReadaheadStats, ZeroPageStats},
+    sys,
+    trace::TraceRecorder,
+    Error, Result,
+};
+
+/// Features the handler relies on: every non-cooperative event plus
+/// MISSING and MINOR faults on shmem and write-protect fault reporting.
+pub fn required_features() -> FeatureFlags {
+    FeatureFlags::EVENT_REMOVE
+        | FeatureFlags::EVENT_REMAP
+        | FeatureFlags::EVENT_FORK
+        | FeatureFlags::EVENT_UNMAP
+        | FeatureFlags::MISSING_SHMEM
+        | FeatureFlags::MINOR_SHMEM
+        | FeatureFlags::PAGEFAULT_FLAG_WP
+}
+
+/// The [`required_features`] the running kernel offers, probed once.
+///
+/// Without some of them the handler does less instead of failing to
+/// start: no MINOR_SHMEM means memfds are registered for MISSING faults
+/// only, and registering for anything else that is missing fails then.
+/// See [`Capabilities`](crate::probe::Capabilities) for the details.
+///
+/// Fails if the kernel could not be asked, such as when userfaultfd is
+/// unavailable altogether.
+pub fn available_features() -> Result<FeatureFlags> {
+    let kernel = sys::cached_features()?;
+    Ok(required_features() & FeatureFlags::from_bits_truncate(kernel))
+}
+
+/// How to register memfd-backed regions on the running kernel: for
+/// MISSING and MINOR faults where shmem MINOR faults are available, for
+/// MISSING faults alone otherwise. The handler fills pages either way.
+pub fn memfd_register_mode() -> Result<RegisterMode> {
+    Ok(memfd_mode(available_features()?))
+}
+
+pub(crate) fn memfd_mode(features: FeatureFlags) -> RegisterMode {
+    if features.contains(FeatureFlags::MINOR_SHMEM) {
+        RegisterMode::MISSING | RegisterMode::MODE_MINOR
+    } else {
+        RegisterMode::MISSING
+    }
+}
+
+/// Features used where the kernel has them but not required:
+/// UFFDIO_POISON and UFFDIO_MOVE.
+const OPTIONAL_FEATURES: u64 = sys::FEATURE_POISON | sys::FEATURE_MOVE;
+
+/// What a new uffd asks for: the [`available_features`] and whichever
+/// optional ones the kernel has, the same the handler decides by in
+/// [`poison_supported`](crate::poison_supported) and
+/// [`move_supported`](crate::move_supported).
+pub(crate) fn requested_features() -> Result<u64> {
+    let kernel = sys::cached_features()?;
+    Ok(available_features()?.bits() | kernel & OPTIONAL_FEATURES)
+}
+
+/// Opens a blocking userfaultfd with the [`available_features`], and
+/// UFFDIO_POISON and UFFDIO_MOVE where the kernel has them.
+pub fn create_uffd() -> Result<Uffd> {
+    Ok(sys::create(requested_features()?, false)?)
+}
+
+/// Resolves faults on the regions of a [`RegionTable`], and on the copies
+/// of them in any children forked while they are registered.
+pub struct FaultHandler {
+    /// The original uffd first, then one per adopted child.
+    targets: Vec<Target>,
+    filler: Arc<Filler>,
+    buf: Scratch,
+    /// Room for the messages returned by one read of a uffd.
+    events: EventBuffer,
+    stats: EventStats,
+    verbose: bool,
+    workers: usize,
+}
+
+/// Messages read from a uffd at once unless [`FaultHandler::read_batch`]
+/// says otherwise.
+const DEFAULT_READ_BATCH: usize = 64;
+
+/// How faults get filled; shared by every target, since forked children
+/// map the same memfd, and by every worker.
+pub(crate) struct Filler {
+    resolver: Resolver,
+    retry: RetryPolicy,
+    source: Option<Arc<dyn PageSource>>,
+    /// The memfd behind the regions, populated before UFFDIO_CONTINUE.
+    memfd: Option<File>,
+    /// Backing file pages whose contents were already taken from `source`,
+    /// numbered like the source by file offset / page size. Once loaded
+    /// the memfd is authoritative, so a page removed later reads as zero
+    /// instead of coming back from the source.
+    loaded: Mutex<PageBitmap>,
+    /// Set when write-protect faults feed a dirty set. Fills then map
+    /// without waking, so pages can be protected before anyone writes.
+    dirty: Option<DirtyTracker>,
+    trace: Option<TraceRecorder>,
+    /// Most pages resolved ahead of a fault; 0 turns readahead off.
+    readahead: usize,
+    readahead_stats: ReadaheadStats,
+    zero_pages: ZeroPageStats,
+    integrity: IntegrityStats,
+    /// What to do with source pages that fail their checksum or cannot
+    /// be read.
+    on_corrupt: CorruptPolicy,
+    /// Whether the kernel supports UFFDIO_POISON.
+    can_poison: bool,
+    /// How MISSING faults get source pages; [`Install::Move`] only if the
+    /// kernel supports it.
+    install: Install,
+    /// Backing file pages that could not be fetched, numbered like
+    /// `loaded`. They are poisoned wherever they get mapped.
+    poisoned: Mutex<PageBitmap>,
+    /// Whether faults no region covers are resolved instead of failing.
+    serve_stray: bool,
+    /// The handler's event counts, for faults no region covers.
+    events: EventStats,
+}
+
+/// What [`Filler::read`] found for a page.
+pub(crate) enum Fetched<'a> {
+    /// Contents to copy in.
+    Data(&'a [u8]),
+    /// Nothing to copy; map the zero page.
+    Zero,
+    /// The page could not be fetched and must be poisoned.
+    Poison,
+}
+
+/// Source of [`Target::id`]s.
+static NEXT_TARGET_ID: AtomicU64 = AtomicU64::new(0);
+
+/// One uffd and the regions it reports faults for.
+pub(crate) struct Target {
+    /// Unique for the life of the process, unlike the uffd's descriptor,
+    /// which a later target may be given once this one is closed.
+    pub(crate) id: u64,
+    pub(crate) uffd: Uffd,
+    regions: Vec<Mapped>,
+}
+
+/// A region as currently mapped behind one uffd.
+struct Mapped {
+    region: Region,
+    /// Whether the region is in the handler's own address space, which
+    /// UFFDIO_MOVE needs.
+    local: bool,
+    /// Pages known to be mapped in the region.
+    populated: Mutex<PageBitmap>,
+    stream: Mutex<Stream>,
+}
+
+impl FaultHandler {
+    /// Creates a handler for `region`, which must already be registered
+    /// with `uffd` and whose contents start at offset 0 of its memfd.
+    pub fn new(uffd: Uffd, region: &VmRegion) -> Self {
+        // The handler never registers anything itself, so the mode is only
+        // descriptive here.
+        let mut table = RegionTable::new();
+        table
+            .insert(region.region(0, RegisterMode::all()))
+            .expect("a lone mapping is page-aligned and cannot overlap");
+        let mut handler = Self::with_regions(uffd, table, page_size());
+        handler.targets[0].regions[0].local = true;
+        handler
+    }
+
+    /// Creates a handler for every region in `table`, registered with
+    /// `uffd` possibly by another process, that faults in units of
+    /// `page_size`.
+    ///
+    /// Faults outside the table fail with [`Error::OutOfRegion`] unless
+    /// [`FaultHandler::serve_stray`] is set.
+    pub fn with_regions(uffd: Uffd, table: RegionTable, page_size: usize) -> Self {
+        let backing_pages = (table.backing_len() as usize).div_ceil(page_size);
+        let stats = EventStats::default();
+        Self {
+            targets: vec![Target::new(uffd, table.iter(), page_size)],
+            filler: Arc::new(Filler {
+                resolver: Resolver::new(page_size),
+                retry: RetryPolicy::default(),
+                source: None,
+                memfd: None,
+                loaded: Mutex::new(PageBitmap::new(backing_pages)),
+                dirty: None,
+                trace: None,
+                readahead: 0,
+                readahead_stats: ReadaheadStats::default(),
+                zero_pages: ZeroPageStats::default(),
+                integrity: IntegrityStats::default(),
+                on_corrupt: CorruptPolicy::default(),
+                can_poison: sys::poison_supported(),
+                install: Install::default(),
+                poisoned: Mutex::new(PageBitmap::new(backing_pages)),
+                serve_stray: false,
+                events: stats.clone(),
+            }),
+            buf: Scratch::new(page_size),
+            events: EventBuffer::new(DEFAULT_READ_BATCH),
+            stats,
+            verbose: false,
+            workers: 1,
+        }
+    }
+
+    /// Resolves `block_pages` pages around each fault instead of one.
+    pub fn block_pages(mut self, block_pages: usize) -> Self {
+        let filler = self.filler_mut();
+        filler.resolver = filler.resolver.block_pages(block_pages);
+        self
+    }
+
+    /// Resolves up to `max_pages` pages ahead of faults that walk a region
+    /// sequentially or at a fixed stride, on top of the faulting block.
+    ///
+    /// The window starts small once the distance between faults repeats
+    /// and doubles each time the walk reaches its end, stopping at the
+    /// region end. Read-ahead pages are filled like the faulting block,
+    /// with UFFDIO_COPY or UFFDIO_CONTINUE, before the faulting thread is
+    /// woken.
+    pub fn readahead(mut self, max_pages: usize) -> Self {
+        self.filler_mut().readahead = max_pages;
+        self
+    }
+
+    /// Replaces the default [`RetryPolicy`] for failed fills.
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.filler_mut().retry = retry;
+        self
+    }
+
+    /// Fills faults from `source`: MISSING faults with UFFDIO_COPY, MINOR
+    /// faults by writing the page into `memfd` before UFFDIO_CONTINUE.
+    /// Source pages are numbered by backing file offset / page size.
+    ///
+    /// Without a memfd, MINOR faults map whatever the page cache holds.
+    pub fn page_source(mut self, source: impl PageSource + 'static, memfd: Option<File>) -> Self {
+        let filler = self.filler_mut();
+        filler.source = Some(Arc::new(source));
+        filler.memfd = memfd;
+        self
+    }
+
+    /// Installs source pages on MISSING faults with `install`;
+    /// [`Install::Copy`] unless set. [`Install::Move`] falls back to
+    /// copying on kernels without UFFDIO_MOVE, see
+    /// [`move_supported`](crate::move_supported), and for regions not
+    /// known to be in this process: those of
+    /// [`FaultHandler::with_regions`] and of forked children.
+    pub fn install(mut self, install: Install) -> Self {
+        self.filler_mut().install = match install {
+            Install::Move if !sys::move_supported() => Install::Copy,
+            install => install,
+        };
+        self
+    }
+
+    /// Decides what happens to source pages that do not match the
+    /// checksum the source stored them with; [`CorruptPolicy::Abort`]
+    /// unless set. Pages are checked before they are copied or written
+    /// into the memfd, whenever the source keeps checksums.
+    pub fn on_corrupt(mut self, policy: CorruptPolicy) -> Self {
+        self.filler_mut().on_corrupt = policy;
+        self
+    }
+
+    /// Resolves faults outside every region without a source instead of
+    /// failing them with [`Error::OutOfRegion`], as when the registrations
+    /// and the table disagree: a missing page reads as zeroes and anything
+    /// else is mapped as the kernel has it. Each one is counted in
+    /// [`EventStats::stray`].
+    pub fn serve_stray(mut self, serve: bool) -> Self {
+        self.filler_mut().serve_stray = serve;
+        self
+    }
+
+    /// Whether pages the [`CorruptPolicy`] withholds are poisoned; they
+    /// are unless set, on kernels with UFFDIO_POISON. Without it they read
+    /// as zeroes, as on older kernels.
+    pub fn use_poison(mut self, poison: bool) -> Self {
+        self.filler_mut().can_poison = poison && sys::poison_supported();
+        self
+    }
+
+    /// Feeds write-protect faults into `tracker`.
+    ///
+    /// Without a tracker, write-protect faults just lift the protection.
+    pub fn dirty_tracker(mut self, tracker: DirtyTracker) -> Self {
+        self.filler_mut().dirty = Some(tracker);
+        self
+    }
+
+    /// Records every resolved page fault in `trace`.
+    pub fn trace(mut self, trace: TraceRecorder) -> Self {
+        self.filler_mut().trace = Some(trace);
+        self
+    }
+
+    /// Resolves faults on `workers` threads, fed by a reader thread that
+    /// drains events in batches. Faults on a page already being resolved
+    /// are coalesced into it. With 1, the default, [`FaultHandler::run`]
+    /// handles everything on its own thread.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Reads up to `batch` messages per read of a uffd; all of them are
+    /// handled before polling again.
+    pub fn read_batch(mut self, batch: usize) -> Self {
+        self.events = EventBuffer::new(batch.max(1));
+        self
+    }
+
+    /// Prints every event as it is handled.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// The uffd the handler was created with.
+    pub fn uffd(&self) -> &Uffd {
+        &self.targets[0].uffd
+    }
+
+    /// Counts the events each read returns; take a handle before spawning.
+    pub fn event_stats(&self) -> EventStats {
+        self.stats.clone()
+    }
+
+    /// Counts what readahead resolved; take a handle before spawning.
+    pub fn readahead_stats(&self) -> ReadaheadStats {
+        self.filler.readahead_stats.clone()
+    }
+
+    /// Counts the source pages that were all zeroes and so were mapped
+    /// with UFFDIO_ZEROPAGE instead of copied.
+    pub fn zero_page_stats(&self) -> ZeroPageStats {
+        self.filler.zero_pages.clone()
+    }
+
+    /// Counts of source pages that failed their checksum or could not be
+    /// read, and of what the [`CorruptPolicy`] did with them.
+    pub fn integrity_stats(&self) -> IntegrityStats {
+        self.filler.integrity.clone()
+    }
+
+    /// Runs [`FaultHandler::run`] on a new thread.
+    pub fn spawn(mut self) -> JoinHandle<Result<()>> {
+        std::thread::spawn(move || self.run())
+    }
+
+    /// Polls every uffd and handles events until an error occurs.
+    ///
+    /// A target whose process has exited is dropped; the loop returns once
+    /// no target is left.
+    pub fn run(&mut self) -> Result<()> {
+        if self.workers > 1 {
+            let reader = pool::ReaderConfig {
+                events: &mut self.events,
+                stats: &self.stats,
+                verbose: self.verbose,
+            };
+            return pool::run(&mut self.targets, &self.filler, self.workers, reader);
+        }
+
+        while !self.targets.is_empty() {
+            // Wait for any fd to become available
+            let mut pollfds: Vec<PollFd> = self
+                .targets
+                .iter()
+                .map(|target| PollFd::new(target.uffd.as_raw_fd(), PollFlags::POLLIN))
+                .collect();
+            poll(&mut pollfds, -1)?;
+
+            // Walk backwards so dropping a target keeps the indices valid.
+            for index in (0..pollfds.len()).rev() {
+                let revents = pollfds[index].revents().unwrap_or_else(PollFlags::empty);
+
+                if revents.contains(PollFlags::POLLERR) {
+                    self.targets.remove(index);
+                    continue;
+                }
+                if !revents.contains(PollFlags::POLLIN) {
+                    continue;
+                }
+
+                // Resolve the whole batch before polling again.
+                let events = self.read_target_events(index)?;
+                let read = Instant::now();
+                for event in events {
+                    match self.handle_target_event(index, event, read) {
+                        Err(Error::Resolve {
+                            class: ErrorClass::TargetExited,
+                            ..
+                        }) => {
+                            // The rest of the batch was for the exited
+                            // process too.
+                            self.targets.remove(index);
+                            break;
+                        }
+                        result => result?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a single event read from the original uffd.
+    pub fn handle_event(&mut self, event: Event) -> Result<()> {
+        self.handle_target_event(0, event, Instant::now())
+    }
+
+    pub(crate) fn page_size(&self) -> usize {
+        self.filler.page_size()
+    }
+
+    /// The filler, for configuring before anything else shares it.
+    fn filler_mut(&mut self) -> &mut Filler {
+        Arc::get_mut(&mut self.filler).expect("handler configured after it was shared")
+    }
+
+    /// What a [`TracePrefetcher`](crate::prefetch::TracePrefetcher) or a
+    /// post-copy [`Prefetcher`](crate::migration::postcopy::Prefetcher)
+    /// needs to fill pages the way this handler does: a second descriptor for
+    /// the original uffd, its regions and the shared filler.
+    pub(crate) fn prefetch_target(&self) -> Result<(Uffd, Vec<Region>, Arc<Filler>)> {
+        let target = &self.targets[0];
+        let uffd = unsafe { Uffd::from_raw_fd(dup(target.uffd.as_raw_fd())?) };
+        let regions = target.regions.iter().map(|m| m.region).collect();
+        Ok((uffd, regions, self.filler.clone()))
+    }
+
+    pub(crate) fn target_count(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub(crate) fn target_fd(&self, index: usize) -> RawFd {
+        self.targets[index].uffd.as_raw_fd()
+    }
+
+    pub(crate) fn drop_target(&mut self, index: usize) {
+        self.targets.remove(index);
+    }
+
+    /// Backing file pages that resolving a `kind` fault at `addr` on
+    /// `targets[index]` will read from the source, so they can be fetched
+    /// ahead of time.
+    pub(crate) fn wanted_pages(&self, index: usize, kind: FaultKind, addr: usize) -> Vec<usize> {
+        let filler = &self.filler;
+        let reads = match kind {
+            FaultKind::WriteProtected => false,
+            FaultKind::Minor => filler.memfd.is_some(),
+            _ => true,
+        };
+        let mapped = self.targets[index]
+            .regions
+            .iter()
+            .find(|m| m.region.contains(addr));
+        let mapped = match mapped {
+            Some(mapped) if reads && filler.source.is_some() => mapped,
+            _ => return Vec::new(),
+        };
+
+        let page_size = filler.page_size();
+        let block = mapped.page_range(
+            filler.resolver.block(addr, mapped.region.range()),
+            page_size,
+        );
+        let mut ranges = vec![block];
+        ranges.extend(mapped.ahead(filler, addr, false));
+
+        let populated = mapped.populated.lock().unwrap();
+        let loaded = filler.loaded.lock().unwrap();
+        ranges
+            .into_iter()
+            .flat_map(|range| populated.clear_runs(range))
+            .flatten()
+            .map(|page| mapped.file_page(page, page_size))
+            .filter(|&page| !loaded.get(page))
+            .collect()
+    }
+
+    /// Reads every queued message on `targets[index]`, up to the batch
+    /// size, and counts the read if there were any.
+    pub(crate) fn read_target_events(&mut self, index: usize) -> Result<Vec<Event>> {
+        let events = self.targets[index]
+            .uffd
+            .read_events(&mut self.events)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if !events.is_empty() {
+            self.stats.record(events.len());
+        }
+        Ok(events)
+    }
+
+    /// Handles `event` from `targets[index]`, which was read at `read`.
+    pub(crate) fn handle_target_event(
+        &mut self,
+        index: usize,
+        event: Event,
+        read: Instant,
+    ) -> Result<()> {
+        if self.verbose {
+            println!("Event on uffd {}: {:?}", index, event);
+        }
+
+        match event {
+            Event::Pagefault { kind, rw, addr } => self.targets[index].handle_pagefault(
+                &self.filler,
+                &mut self.buf,
+                kind,
+                rw,
+                addr as usize,
+                read,
+            ),
+            event => {
+                handle_change(&mut self.targets, index, event, self.filler.page_size());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Applies a non-cooperative event on `targets[index]` to the bookkeeping.
+pub(crate) fn handle_change(
+    targets: &mut Vec<Target>,
+    index: usize,
+    event: Event,
+    page_size: usize,
+) {
+    let target = &mut targets[index];
+    match event {
+        Event::Pagefault { .. } => unreachable!("page faults are not changes"),
+        Event::Fork { uffd } => {
+            // The child inherits the registrations at the same addresses
+            // but none of the parent's page table entries.
+            let regions = target.regions.iter().map(|m| &m.region);
+            let child = Target::new(uffd, regions, page_size);
+            targets.push(child);
+        }
+        Event::Remap { from, to, len } => {
+            let regions = std::mem::take(&mut target.regions);
+            target.regions = regions
+                .into_iter()
+                .flat_map(|mapped| mapped.remap(from as usize, to as usize, len, page_size))
+                .collect();
+        }
+        Event::Remove { start, end } => {
+            for mapped in &mut target.regions {
+                mapped.forget(start as usize..end as usize, page_size);
+            }
+        }
+        Event::Unmap { start, end } => {
+            let (start, end) = (start as usize, end as usize);
+            // Regions unmapped whole are gone from this uffd; a child
+            // keeps its own target until it exits.
+            target
+                .regions
+                .retain(|m| start > m.region.start || end < m.region.range().end);
+            for mapped in &mut target.regions {
+                mapped.forget(start..end, page_size);
+            }
+        }
+    }
+}
+
+impl Target {
+    fn new<'a>(uffd: Uffd, regions: impl Iterator<Item = &'a Region>, page_size: usize) -> Self {
+        Self {
+            id: NEXT_TARGET_ID.fetch_add(1, Ordering::Relaxed),
+            uffd,
+            regions: regions
+                .map(|region| Mapped {
+                    region: *region,
+                    local: false,
+                    populated: Mutex::new(PageBitmap::new(region.len / page_size)),
+                    stream: Mutex::default(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Resolves a fault at `addr`, read off the uffd at `read`, using `buf`
+    /// to stage source pages.
+    pub(crate) fn handle_pagefault(
+        &self,
+        filler: &Filler,
+        buf: &mut [u8],
+        kind: FaultKind,
+        rw: ReadWrite,
+        addr: usize,
+        read: Instant,
+    ) -> Result<()> {
+        let mapped = match self.regions.iter().find(|m| m.region.contains(addr)) {
+            Some(mapped) => mapped,
+            None if filler.serve_stray => return self.resolve_stray(filler, kind, addr),
+            None => return Err(Error::OutOfRegion { addr }),
+        };
+        mapped.handle_pagefault(&self.uffd, filler, buf, kind, rw, addr)?;
+
+        if let Some(trace) = &filler.trace {
+            let page = addr - addr % filler.page_size();
+            trace.record(kind, rw, mapped.region.file_offset(page), read)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves a fault at `addr` that no region covers, so its thread is
+    /// not left hanging; see [`FaultHandler::serve_stray`].
+    fn resolve_stray(&self, filler: &Filler, kind: FaultKind, addr: usize) -> Result<()> {
+        filler.events.record_stray();
+        let page_size = filler.page_size();
+        let page = (addr - addr % page_size) as *mut c_void;
+
+        let result = match kind {
+            FaultKind::Missing => unsafe { self.uffd.zeropage(page, page_size, true) },
+            FaultKind::Minor => self.uffd.uffd_continue(page, page_size, true),
+            FaultKind::WriteProtected => {
+                self.uffd.remove_write_protection(page, page_size, true)?;
+                return Ok(());
+            }
+        };
+        match result.map_err(Error::from) {
+            Ok(_) => Ok(()),
+            // Resolved meanwhile; the thread may still need waking.
+            Err(err) if ErrorClass::of(&err) == ErrorClass::AlreadyMapped => {
+                self.uffd.wake(page, page_size)?;
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Mapped {
+    /// Page indices covered by `range`, clamped to the region.
+    fn page_range(&self, range: Range<usize>, page_size: usize) -> Range<usize> {
+        let region = self.region.range();
+        let start = range.start.clamp(region.start, region.end) - region.start;
+        let end = range.end.clamp(region.start, region.end) - region.start;
+        start / page_size..end.div_ceil(page_size)
+    }
+
+    fn page_addr(&self, page: usize, page_size: usize) -> usize {
+        self.region.start + page * page_size
+    }
+
+    /// The backing file page holding `page` of the region.
+    fn file_page(&self, page: usize, page_size: usize) -> usize {
+        self.region.offset as usize / page_size + page
+    }
+
+    /// Drops what is known about the pages in `range`.
+    fn forget(&mut self, range: Range<usize>, page_size: usize) {
+        let pages = self.page_range(range, page_size);
+        self.populated.get_mut().unwrap().clear_range(pages);
+    }
+
+    /// Follows an mremap of `len` bytes from `from` to `to`, returning
+    /// what became of the region.
+    ///
+    /// The moved part takes its bookkeeping along, since page table
+    /// entries travel with the mapping. Whatever lies before or after it
+    /// stays put as a region of its own.
+    fn remap(self, from: usize, to: usize, len: usize, page_size: usize) -> Vec<Mapped> {
+        let range = self.region.range();
+        let moved = from.max(range.start)..from.saturating_add(len).min(range.end);
+        if moved.is_empty() {
+            return vec![self];
+        }
+
+        let parts = [
+            (range.start..moved.start, range.start),
+            (moved.clone(), to + (moved.start - from)),
+            (moved.end..range.end, moved.end),
+        ];
+        parts
+            .into_iter()
+            .filter(|(part, _)| !part.is_empty())
+            .map(|(part, start)| self.split(part, start, page_size))
+            .collect()
+    }
+
+    /// The part of the region at `part`, placed at `start`.
+    fn split(&self, part: Range<usize>, start: usize, page_size: usize) -> Mapped {
+        let first = (part.start - self.region.start) / page_size;
+        let pages = first..first + part.len() / page_size;
+        let mut populated = PageBitmap::new(pages.len());
+        for page in self.populated.lock().unwrap().iter_ones() {
+            if pages.contains(&page) {
+                populated.set(page - first);
+            }
+        }
+
+        Mapped {
+            region: Region {
+                start,
+                len: part.len(),
+                offset: self.region.offset + (part.start - self.region.start) as u64,
+                ..self.region
+            },
+            local: self.local,
+            populated: Mutex::new(populated),
+            // Block numbers no longer mean what the stream saw.
+            stream: Mutex::default(),
+        }
+    }
+
+    fn handle_pagefault(
+        &self,
+        uffd: &Uffd,
+        filler: &Filler,
+        buf: &mut [u8],
+        kind: FaultKind,
+        rw: ReadWrite,
+        addr: usize,
+    ) -> Result<()> {
+        let page_size = filler.resolver.page_size();
+        let fault_page = (addr - self.region.start) / page_size;
+
+        if kind == FaultKind::WriteProtected {
+            if let Some(tracker) = &filler.dirty {
+                if tracker.record(uffd, addr)? {
+                    return Ok(());
+                }
+            }
+            let page = self.page_addr(fault_page, page_size) as *mut c_void;
+            uffd.remove_write_protection(page, page_size, true)?;
+            return Ok(());
+        }
+
+        let block = self.page_range(filler.resolver.block(addr, self.region.range()), page_size);
+        // Another worker may be filling some of these pages too; whoever
+        // comes second sees EEXIST.
+        let runs = self.populated.lock().unwrap().clear_runs(block.clone());
+        for run in runs {
+            if kind == FaultKind::Minor {
+                let first = self.file_page(run.start, page_size);
+                filler.populate(first..first + run.len(), buf)?;
+            }
+            self.fill(uffd, filler, buf, kind, run, fault_page)?;
+        }
+
+        let ahead = self.ahead(filler, addr, true);
+        if !ahead.is_empty() {
+            self.read_ahead(uffd, filler, buf, kind, ahead, fault_page)?;
+        }
+
+        let start = self.page_addr(block.start, page_size);
+        let end = self.page_addr(block.end, page_size);
+        if let Some(tracker) = &filler.dirty {
+            tracker.after_fill(uffd, start..end, addr, rw)?;
+        }
+
+        // The fills above may not have woken anyone (dirty tracking or
+        // readahead), or the faulting page may have been skipped because
+        // another fault or the kernel populated it first; the faulting
+        // thread still needs its wake-up.
+        uffd.wake(start as *mut c_void, end - start)?;
+        Ok(())
+    }
+
+    /// Page ranges to resolve ahead of a fault at `addr`. Only with
+    /// `advance` does the fault count towards the stream, so the pages a
+    /// fault will read ahead can be looked up before handling it.
+    fn ahead(&self, filler: &Filler, addr: usize, advance: bool) -> Vec<Range<usize>> {
+        if filler.readahead == 0 {
+            return Vec::new();
+        }
+        let page_size = filler.page_size();
+        let block_size = filler.resolver.block_size();
+        let region = self.region.range();
+        let blocks = self.region.len.div_ceil(block_size);
+        let max_blocks = (filler.readahead * page_size / block_size).max(1);
+        let block = (addr - region.start) / block_size;
+
+        let ahead = if advance {
+            let ahead = self
+                .stream
+                .lock()
+                .unwrap()
+                .advance(block, blocks, max_blocks);
+            filler.readahead_stats.record(0, ahead.hits, ahead.wasted);
+            ahead
+        } else {
+            let mut stream = self.stream.lock().unwrap().clone();
+            stream.advance(block, blocks, max_blocks)
+        };
+        ahead
+            .blocks
+            .into_iter()
+            .map(|block| {
+                let start = region.start + block * block_size;
+                self.page_range(filler.resolver.block(start, region.clone()), page_size)
+            })
+            .collect()
+    }
+
+    /// Fills the unpopulated pages of `ranges` for a `kind` fault on
+    /// `fault_page`, which lies outside them, and counts them as read
+    /// ahead.
+    fn read_ahead(
+        &self,
+        uffd: &Uffd,
+        filler: &Filler,
+        buf: &mut [u8],
+        kind: FaultKind,
+        ranges: Vec<Range<usize>>,
+        fault_page: usize,
+    ) -> Result<()> {
+        let page_size = filler.page_size();
+        let mut issued = 0;
+        for range in ranges {
+            let runs = self.populated.lock().unwrap().clear_runs(range);
+            for run in runs {
+                if kind == FaultKind::Minor {
+                    let first = self.file_page(run.start, page_size);
+                    filler.populate(first..first + run.len(), buf)?;
+                }
+                self.fill(uffd, filler, buf, kind, run.clone(), fault_page)?;
+                issued += {
+                    let populated = self.populated.lock().unwrap();
+                    run.clone().filter(|&page| populated.get(page)).count()
+                };
+
+                // Nobody wrote to these yet.
+                if let Some(tracker) = &filler.dirty {
+                    let start = self.page_addr(run.start, page_size);
+                    let end = self.page_addr(run.end, page_size);
+                    tracker.after_fill(uffd, start..end, start, ReadWrite::Read)?;
+                }
+            }
+        }
+        self.stream.lock().unwrap().issued(issued);
+        filler.readahead_stats.record(issued, 0, 0);
+        Ok(())
+    }
+
+    /// Fills the pages in `pages`, retrying failures per the retry policy
+    /// and carrying on with the rest of the run after each page.
+    ///
+    /// Only `fault_page` must be resolved; its neighbours are filled on a
+    /// best-effort basis and are skipped rather than falling back.
+    fn fill(
+        &self,
+        uffd: &Uffd,
+        filler: &Filler,
+        buf: &mut [u8],
+        kind: FaultKind,
+        pages: Range<usize>,
+        fault_page: usize,
+    ) -> Result<()> {
+        let page_size = filler.resolver.page_size();
+        let has_source = filler.source.is_some();
+        let wake = filler.wakes();
+        let mut page = pages.start;
+        let mut attempts = 0;
+
+        while page < pages.end {
+            let addr = self.page_addr(page, page_size) as *mut c_void;
+            let len = (pages.end - page) * page_size;
+            let file_page = self.file_page(page, page_size);
+            let result = match kind {
+                FaultKind::Minor => {
+                    match filler.unpoisoned(file_page..file_page + pages.end - page) {
+                        0 => None,
+                        clean => Some(sys::uffd_continue(uffd, addr, clean * page_size, wake)),
+                    }
+                }
+                _ => match filler.read(file_page, buf)? {
+                    Fetched::Data(data) => Some(self.install(uffd, filler, data, addr, wake)),
+                    Fetched::Zero if has_source => Some(sys::zeropage(uffd, addr, page_size, wake)),
+                    Fetched::Zero => Some(sys::zeropage(uffd, addr, len, wake)),
+                    Fetched::Poison => None,
+                },
+            };
+            // None: the page is to be poisoned rather than filled.
+            let result = match result {
+                Some(result) => result,
+                None => {
+                    self.poison(uffd, filler, page)?;
+                    page += 1;
+                    attempts = 0;
+                    continue;
+                }
+            };
+            attempts += 1;
+
+            let class = match result {
+                // Possibly fewer pages than asked for, if the ioctl stopped
+                // part way; the rest are retried from the next one.
+                Ok(filled) => {
+                    let done = ((filled / page_size).max(1)).min(pages.end - page);
+                    self.populated.lock().unwrap().set_range(page..page + done);
+                    filler
+                        .loaded
+                        .lock()
+                        .unwrap()
+                        .set_range(file_page..file_page + done);
+                    page += done;
+                    attempts = 0;
+                    continue;
+                }
+                Err(err) => ErrorClass::of(&err),
+            };
+
+            let rule = filler.retry.rule(class);
+            if attempts < rule.max_attempts {
+                std::thread::sleep(rule.delay(attempts));
+                continue;
+            }
+
+            // For CONTINUE, EEXIST means the page is already mapped here. For
+            // ZEROPAGE it only means the page cache has it, which leaves a
+            // MINOR fault still to come.
+            if class == ErrorClass::AlreadyMapped && kind == FaultKind::Minor {
+                self.populated.lock().unwrap().set(page);
+            }
+
+            let fallback = if page == fault_page {
+                rule.fallback
+            } else if class == ErrorClass::TargetExited {
+                Fallback::GiveUp
+            } else {
+                Fallback::Skip
+            };
+            self.fall_back(uffd, filler, buf, fallback, page, class, attempts)?;
+            page += 1;
+            attempts = 0;
+        }
+        Ok(())
+    }
+
+    /// Applies `fallback` to `page` after `class` failures used up its
+    /// retries.
+    #[allow(clippy::too_many_arguments)]
+    fn fall_back(
+        &self,
+        uffd: &Uffd,
+        filler: &Filler,
+        buf: &mut [u8],
+        fallback: Fallback,
+        page: usize,
+        class: ErrorClass,
+        attempts: u32,
+    ) -> Result<()> {
+        let page_size = filler.resolver.page_size();
+        let wake = filler.wakes();
+        let addr = self.page_addr(page, page_size);
+        let file_page = self.file_page(page, page_size);
+        let give_up = |class, attempts| Error::Resolve {
+            addr,
+            class,
+            attempts,
+        };
+
+        let result = match fallback {
+            Fallback::Skip => return Ok(()),
+            Fallback::GiveUp => return Err(give_up(class, attempts)),
+            Fallback::Zeropage => unsafe { uffd.zeropage(addr as *mut c_void, page_size, wake) },
+            Fallback::Copy => {
+                match filler.read(file_page, buf)? {
+                    Fetched::Data(_) => {}
+                    Fetched::Zero => buf.fill(0),
+                    Fetched::Poison => return self.poison(uffd, filler, page),
+                }
+                unsafe {
+                    uffd.copy(
+                        buf.as_ptr() as *const c_void,
+                        addr as *mut c_void,
+                        page_size,
+                        wake,
+                    )
+                }
+            }
+        };
+
+        match result.map_err(Error::from) {
+            Ok(_) => {
+                self.populated.lock().unwrap().set(page);
+                filler.loaded.lock().unwrap().set(file_page);
+                Ok(())
+            }
+            Err(err) => match ErrorClass::of(&err) {
+                ErrorClass::AlreadyMapped => Ok(()),
+                class => Err(give_up(class, attempts + 1)),
+            },
+        }
+    }
+
+    /// Installs `data`, a source page read into a [`Scratch`] buffer, at
+    /// `addr`.
+    fn install(
+        &self,
+        uffd: &Uffd,
+        filler: &Filler,
+        data: &[u8],
+        addr: *mut c_void,
+        wake: bool,
+    ) -> Result<usize> {
+        let src = data.as_ptr() as *mut c_void;
+        if self.local && filler.install == Install::Move {
+            // Anything but a private anonymous region, or a scratch page
+            // the kernel will not hand over, fails without moving.
+            if let Ok(moved) = sys::move_pages(uffd, addr, src, data.len(), wake) {
+                return Ok(moved);
+            }
+        }
+        sys::copy(uffd, addr, src, data.len(), wake)
+    }
+
+    /// Poisons `page`, whose contents could not be fetched, so touching it
+    /// raises SIGBUS instead of faulting again.
+    fn poison(&self, uffd: &Uffd, filler: &Filler, page: usize) -> Result<()> {
+        let page_size = filler.resolver.page_size();
+        let addr = self.page_addr(page, page_size) as *mut c_void;
+        match sys::poison(uffd, addr, page_size, filler.wakes()) {
+            Ok(_) => {}
+            // Mapped before it was found to be bad.
+            Err(err) if ErrorClass::of(&err) == ErrorClass::AlreadyMapped => {}
+            Err(err) => return Err(err),
+        }
+        self.populated.lock().unwrap().set(page);
+        Ok(())
+    }
+}
+
+impl Filler {
+    pub(crate) fn page_size(&self) -> usize {
+        self.resolver.page_size()
+    }
+
+    pub(crate) fn has_memfd(&self) -> bool {
+        self.memfd.is_some()
+    }
+
+    /// Whether fills wake the faulting thread themselves. Otherwise it is
+    /// woken once its pages are protected and any readahead is done.
+    fn wakes(&self) -> bool {
+        self.dirty.is_none() && self.readahead == 0
+    }
+
+    pub(crate) fn dirty(&self) -> Option<&DirtyTracker> {
+        self.dirty.as_ref()
+    }
+
+    /// Notes that `page` now holds what the source had for it.
+    pub(crate) fn mark_loaded(&self, page: usize) {
+        self.loaded.lock().unwrap().set(page);
+    }
+
+    /// Whether `page` was already taken from the source.
+    pub(crate) fn is_loaded(&self, page: usize) -> bool {
+        self.loaded.lock().unwrap().get(page)
+    }
+
+    /// Whether `page` could not be fetched and is to be poisoned.
+    pub(crate) fn is_poisoned(&self, page: usize) -> bool {
+        self.poisoned.lock().unwrap().get(page)
+    }
+
+    /// How many pages at the start of `pages` are not poisoned.
+    fn unpoisoned(&self, pages: Range<usize>) -> usize {
+        let poisoned = self.poisoned.lock().unwrap();
+        pages.take_while(|&page| !poisoned.get(page)).count()
+    }
+
+    /// Reads `page` from the source into `buf`, unless it was already
+    /// loaded or the source has nothing but zeroes for it, in which case
+    /// the caller maps the zero page instead, or could not be fetched, in
+    /// which case the caller poisons it.
+    pub(crate) fn read<'a>(&self, page: usize, buf: &'a mut [u8]) -> Result<Fetched<'a>> {
+        let source = match &self.source {
+            Some(source) if !self.loaded.lock().unwrap().get(page) => source,
+            _ => return Ok(Fetched::Zero),
+        };
+        if self.is_poisoned(page) {
+            return Ok(Fetched::Poison);
+        }
+        match source.read_page(page, buf) {
+            Ok(Page::Data) if !self.verify(source.as_ref(), page, buf)? => Ok(self.poison(page)),
+            Ok(Page::Data) if !is_zero(buf) => Ok(Fetched::Data(buf)),
+            Ok(Page::Data | Page::Zero) => {
+                self.zero_pages.record(buf.len());
+                Ok(Fetched::Zero)
+            }
+            Ok(Page::Absent) => Ok(Fetched::Zero),
+            Err(err) => {
+                self.unreadable(page, err)?;
+                Ok(self.poison(page))
+            }
+        }
+    }
+
+    /// Gives up on `page`: with UFFDIO_POISON it is poisoned from now on,
+    /// otherwise it reads as zeroes. Either way its contents never get
+    /// mapped.
+    fn poison(&self, page: usize) -> Fetched<'static> {
+        self.integrity.record_withheld();
+        if !self.can_poison {
+            return Fetched::Zero;
+        }
+        self.poisoned.lock().unwrap().set(page);
+        Fetched::Poison
+    }
+
+    /// Applies the policy to a read of `page` that failed with `err`.
+    /// Unless that aborts, the page is to be poisoned: there is nothing to
+    /// map.
+    fn unreadable(&self, page: usize, err: io::Error) -> Result<()> {
+        self.integrity.record_unreadable();
+        match self.on_corrupt {
+            CorruptPolicy::Abort => Err(err.into()),
+            CorruptPolicy::Poison => Ok(()),
+            CorruptPolicy::Log => {
+                println!("Withholding page {}: {}", page, Error::Io(err));
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks `data`, just read from `source` for `page`, against the
+    /// checksum it was stored with. Returns false if the page is to be
+    /// poisoned rather than mapped.
+    fn verify(&self, source: &dyn PageSource, page: usize, data: &[u8]) -> Result<bool> {
+        let expected = match source.checksum(page) {
+            Some(expected) => expected,
+            None => return Ok(true),
+        };
+        let actual = checksum(data);
+        if actual == expected {
+            return Ok(true);
+        }
+        self.integrity.record_mismatch(Mismatch {
+            page,
+            expected,
+            actual,
+        });
+        let err = Error::Corrupt {
+            page,
+            expected,
+            actual,
+        };
+        match self.on_corrupt {
+            CorruptPolicy::Abort => Err(err),
+            CorruptPolicy::Poison => Ok(false),
+            CorruptPolicy::Log => {
+                println!("Mapping anyway: {}", err);
+                self.integrity.record_served();
+                Ok(true)
+            }
+        }
+    }
+
+    /// Writes source contents for the not yet loaded pages in `pages`
+    /// into the memfd, so UFFDIO_CONTINUE maps them.
+    pub(crate) fn populate(&self, pages: Range<usize>, buf: &mut [u8]) -> Result<()> {
+        let (source, memfd) = match (&self.source, &self.memfd) {
+            (Some(source), Some(memfd)) => (source, memfd),
+            _ => return Ok(()),
+        };
+        let page_size = self.resolver.page_size();
+
+        for page in pages {
+            if self.loaded.lock().unwrap().get(page) || self.is_poisoned(page) {
+                continue;
+            }
+            let valid = match source.read_page(page, buf) {
+                Ok(Page::Data) => self.verify(source.as_ref(), page, buf)?,
+                Ok(Page::Zero) => {
+                    buf.fill(0);
+                    true
+                }
+                // Keep whatever the page cache already holds.
+                Ok(Page::Absent) => continue,
+                Err(err) => {
+                    self.unreadable(page, err)?;
+                    false
+                }
+            };
+            if !valid {
+                match self.poison(page) {
+                    // Left out of the memfd; CONTINUE stops short of it.
+                    Fetched::Poison => continue,
+                    _ => buf.fill(0),
+                }
+            }
+            memfd.write_all_at(buf, (page * page_size) as u64)?;
+            self.loaded.lock().unwrap().set(page);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+//! Installing source pages into a faulting range.
+
+use std::{
+    ffi::c_void,
+    ops::{Deref, DerefMut},
+};
+
+use nix::sys::mman::{self, MmapAdvise};
+
+/// How MISSING faults get the pages read from a source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Install {
+    /// UFFDIO_COPY from the handler's buffer into the faulting range.
+    #[default]
+    Copy,
+    /// UFFDIO_MOVE (Linux 6.8): the page the source was read into is
+    /// remapped into the faulting range, with no copy. Only possible into
+    /// private anonymous memory of the handler's own process; anywhere
+    /// else pages are copied.
+    Move,
+}
+
+/// A page-aligned buffer in an anonymous mapping of its own, which pages
+/// are read into before they are installed. Being a whole anonymous page,
+/// it can be handed to UFFDIO_MOVE; the kernel maps a fresh one in its
+/// place the next time it is written.
+pub(crate) struct Scratch {
+    addr: *mut c_void,
+    len: usize,
+}
+
+unsafe impl Send for Scratch {}
+
+impl Scratch {
+    /// Maps `len` bytes. As with any allocation, running out of memory
+    /// here is fatal.
+    pub(crate) fn new(len: usize) -> Self {
+        let addr = unsafe {
+            mman::mmap(
+                std::ptr::null_mut(),
+                len,
+                mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE,
+                mman::MapFlags::MAP_PRIVATE | mman::MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        }
+        .expect("mapping a scratch buffer");
+        // Shared copy-on-write with a forked child, the pages could no
+        // longer be moved.
+        let _ = unsafe { mman::madvise(addr, len, MmapAdvise::MADV_DONTFORK) };
+        Self { addr, len }
+    }
+}
+
+impl Deref for Scratch {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl DerefMut for Scratch {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.addr as *mut u8, self.len) }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = unsafe { mman::munmap(self.addr, self.len) };
+    }
+}
@@ -0,0 +1,26 @@
+//! Checking pages against the checksums their source stored them with, so
+//! a corrupt snapshot fails loudly instead of handing the guest garbage.
+
+/// CRC-32C of `page`, as kept in snapshot metadata.
+pub fn checksum(page: &[u8]) -> u32 {
+    crc32c::crc32c(page)
+}
+
+/// What the handler does with a page that fails its checksum, or that the
+/// source fails to read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptPolicy {
+    /// Fail the fault with [`Error::Corrupt`](crate::Error::Corrupt) or
+    /// the read error, which stops the handler.
+    #[default]
+    Abort,
+    /// Never map the contents. The page is poisoned with UFFDIO_POISON, so
+    /// whoever touches it gets SIGBUS while the handler carries on; see
+    /// [`poison_supported`](crate::poison_supported). Older kernels get
+    /// the zero page instead.
+    Poison,
+    /// Print the problem to stdout, count it in the handler's
+    /// [`IntegrityStats`](crate::IntegrityStats) and map the contents
+    /// anyway. Pages that could not be read are poisoned.
+    Log,
+}
@@ -0,0 +1,47 @@
+//! Userfaultfd fault handling for memfd-backed guest memory.
+//!
+//! A [`MemfdRegion`] owns the memfd and a host-side mapping of it. The
+//! guest-side [`VmRegion`] maps the same memfd again and is registered with
+//! a uffd; a [`FaultHandler`] then resolves MISSING and MINOR faults on it,
+//! optionally filling pages from a [`PageSource`].
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+mod bitmap;
+pub mod compressed;
+pub mod dedup;
+mod dirty;
+mod error;
+pub mod firecracker;
+mod handler;
+mod install;
+mod integrity;
+pub mod migration;
+mod pool;
+pub mod prefetch;
+pub mod probe;
+mod readahead;
+mod region;
+mod resolver;
+mod retry;
+pub mod scenario;
+pub mod snapshot;
+mod source;
+mod stats;
+mod sys;
+pub mod trace;
+
+pub use bitmap::PageBitmap;
+pub use dirty::DirtyTracker;
+pub use error::{Error, Result};
+pub use handler::{
+    available_features, create_uffd, memfd_register_mode, required_features, FaultHandler,
+};
+pub use install::Install;
+pub use integrity::{checksum, CorruptPolicy};
+pub use region::{page_size, MemfdRegion, Region, RegionTable, VmRegion};
+pub use resolver::Resolver;
+pub use retry::{ErrorClass, Fallback, Retry, RetryPolicy};
+pub use source::{is_zero, MemorySource, Page, PageSource, SnapshotFile, ZeroSource};
+pub use stats::{EventStats, IntegrityStats, Mismatch, ReadaheadStats, ZeroPageStats};
+pub use sys::{move_supported, poison_supported};
@@ -1,154 +1,434 @@
 use std::{
-    ffi::{c_void, CString},
-    fs::File,
-    os::unix::prelude::{AsRawFd, FromRawFd},
-    sync::mpsc::{self, Sender},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::ExitCode,
+    time::Duration,
 };
 
-use nix::{
-    poll::{poll, PollFd, PollFlags},
-    sys::{
-        memfd::{memfd_create, MemFdCreateFlag},
-        mman,
+use uffd_bug::{
+    compressed::{self, DEFAULT_BLOCK_SIZE},
+    create_uffd,
+    dedup::{self, PageStore},
+    firecracker, memfd_register_mode,
+    migration::{
+        postcopy::{Destination, PageServer},
+        precopy::{self, Outcome as PrecopyOutcome, PrecopyConfig, Received},
     },
+    probe::Capabilities,
+    scenario::{self, Outcome, Scenario},
+    snapshot::{self, Header, LayerKind, SnapshotChain},
+    trace::Trace,
+    DirtyTracker, FaultHandler, MemfdRegion, PageBitmap, SnapshotFile,
 };
-use userfaultfd::{FaultKind, FeatureFlags, RegisterMode, Uffd, UffdBuilder};
-
-fn main() {
-    let mem_size = 4096 * 4;
-    let (file, mem_addr) = create_uffd_mapping(mem_size);
-    let vm_addr = create_vm_mapping(file.as_raw_fd(), mem_size as _);
-
-    let uffd = UffdBuilder::new()
-        .user_mode_only(false)
-        .non_blocking(false)
-        .require_features(
-            FeatureFlags::EVENT_REMOVE
-                | FeatureFlags::EVENT_REMAP
-                | FeatureFlags::EVENT_FORK
-                | FeatureFlags::EVENT_UNMAP
-                | FeatureFlags::MISSING_SHMEM
-                | FeatureFlags::MINOR_SHMEM
-                | FeatureFlags::PAGEFAULT_FLAG_WP,
-        )
-        .create()
-        .unwrap();
-
-    uffd.register_with_mode(
-        vm_addr,
-        mem_size as _,
-        RegisterMode::MISSING | RegisterMode::MODE_MINOR | RegisterMode::WRITE_PROTECT,
-    )
-    .unwrap();
-
-    let (tx, rx) = mpsc::channel();
-    std::thread::spawn({
-        let uffd_copy = unsafe { Uffd::from_raw_fd(uffd.as_raw_fd()) };
-        let vm_addr = vm_addr as u64;
-
-        move || {
-            handle_uffd_events(uffd_copy, mem_size, vm_addr, tx);
-        }
-    });
-    let time = std::time::Instant::now();
-
-    // First write to the _underlying_ memory
-    write_to_pointer(mem_addr, &[1, 2, 3]);
-
-    // Then read from VM armed memory, this should trigger a minor fault
-    read_from_pointer(vm_addr, 3);
-
-    println!("Done, took {:?}", time.elapsed());
-}
-
-fn create_uffd_mapping(size: u64) -> (File, *mut c_void) {
-    let name = CString::new("mapping").unwrap();
-    let memfd = memfd_create(&name, MemFdCreateFlag::empty()).unwrap();
-    let file = unsafe { File::from_raw_fd(memfd) };
-    file.set_len(size).unwrap();
-
-    let mapping = unsafe {
-        mman::mmap(
-            std::ptr::null_mut(),
-            size as usize,
-            mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE,
-            mman::MapFlags::MAP_SHARED,
-            memfd,
-            0,
-        )
-        .unwrap()
+use userfaultfd::RegisterMode;
+
+const USAGE: &str = "\
+usage: uffd-bug list
+       uffd-bug probe
+       uffd-bug run <scenario|all> [--timeout SECS]
+       uffd-bug serve --socket PATH --snapshot FILE [--trace FILE]
+       uffd-bug snapshot --memfd PATH --out FILE [--parent LAYER]...
+       uffd-bug compress --memfd PATH --out FILE [--block-size BYTES]
+       uffd-bug dedup --memfd PATH --store FILE --manifest FILE
+       uffd-bug restore --out FILE [--prefetch TRACE] LAYER...
+       uffd-bug migrate-source --memfd PATH --listen ADDR
+       uffd-bug migrate-dest --connect ADDR --out FILE [--prefetch-batch PAGES]
+       uffd-bug precopy-send --memfd PATH --connect ADDR [--threshold PAGES]
+       uffd-bug precopy-receive --listen ADDR --out FILE [--postcopy ADDR]
+       uffd-bug trace dump FILE [--format csv|json]";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["list"] => {
+            for scenario in scenario::scenarios() {
+                println!("{:<28} {}", scenario.name, scenario.description);
+            }
+            ExitCode::SUCCESS
+        }
+        ["probe"] => report("probe", probe()),
+        ["run", name, rest @ ..] => {
+            let timeout = match parse_timeout(rest) {
+                Some(timeout) => timeout,
+                None => return usage(),
+            };
+            let selected: Vec<&Scenario> = if *name == "all" {
+                scenario::scenarios().iter().collect()
+            } else {
+                match scenario::find(name) {
+                    Some(scenario) => vec![scenario],
+                    None => {
+                        eprintln!("unknown scenario: {}", name);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            };
+            run(&selected, timeout)
+        }
+        ["serve", "--socket", socket, "--snapshot", snapshot, rest @ ..] => {
+            let trace = match rest {
+                [] => None,
+                ["--trace", path] => Some(Path::new(*path)),
+                _ => return usage(),
+            };
+            report(
+                "serve",
+                firecracker::serve(Path::new(socket), Path::new(snapshot), trace),
+            )
+        }
+        ["snapshot", rest @ ..] => {
+            let (options, parents) = match parse_options(rest, &["--memfd", "--out"], "--parent") {
+                Some(parsed) => parsed,
+                None => return usage(),
+            };
+            report("snapshot", snapshot(options[0], options[1], &parents))
+        }
+        ["compress", "--memfd", memfd, "--out", out, rest @ ..] => {
+            let block_size = match rest {
+                [] => DEFAULT_BLOCK_SIZE,
+                ["--block-size", bytes] => match bytes.parse::<usize>() {
+                    Ok(bytes) if bytes > 0 && bytes % uffd_bug::page_size() == 0 => bytes,
+                    _ => return usage(),
+                },
+                _ => return usage(),
+            };
+            report("compress", compress(memfd, out, block_size))
+        }
+        ["dedup", "--memfd", memfd, "--store", store, "--manifest", manifest] => {
+            report("dedup", dedup(memfd, store, manifest))
+        }
+        ["restore", "--out", out, "--prefetch", trace, layers @ ..] if !layers.is_empty() => {
+            report("restore", restore(out, Some(trace), layers))
+        }
+        ["restore", "--out", out, layers @ ..] if !layers.is_empty() => {
+            report("restore", restore(out, None, layers))
+        }
+        ["migrate-source", "--memfd", memfd, "--listen", listen] => {
+            report("migrate-source", migrate_source(memfd, listen))
+        }
+        ["migrate-dest", "--connect", addr, "--out", out, rest @ ..] => {
+            let prefetch_batch = match rest {
+                [] => None,
+                ["--prefetch-batch", pages] => match pages.parse::<usize>() {
+                    Ok(pages) if pages > 0 => Some(pages),
+                    _ => return usage(),
+                },
+                _ => return usage(),
+            };
+            report("migrate-dest", migrate_dest(addr, out, prefetch_batch))
+        }
+        ["precopy-send", "--memfd", memfd, "--connect", addr, rest @ ..] => {
+            let mut config = PrecopyConfig::default();
+            match rest {
+                [] => {}
+                ["--threshold", pages] => match pages.parse() {
+                    Ok(pages) => config.threshold = pages,
+                    Err(_) => return usage(),
+                },
+                _ => return usage(),
+            }
+            report("precopy-send", precopy_send(memfd, addr, config))
+        }
+        ["precopy-receive", "--listen", listen, "--out", out, rest @ ..] => {
+            let postcopy = match rest {
+                [] => None,
+                ["--postcopy", addr] => Some(*addr),
+                _ => return usage(),
+            };
+            report("precopy-receive", precopy_receive(listen, out, postcopy))
+        }
+        ["trace", "dump", path, rest @ ..] => {
+            let json = match rest {
+                [] | ["--format", "csv"] => false,
+                ["--format", "json"] => true,
+                _ => return usage(),
+            };
+            report("trace", dump_trace(path, json))
+        }
+        _ => usage(),
+    }
+}
+
+/// Splits `args` into the values of the required `names`, in order, and
+/// every value of the repeatable `multi` option.
+fn parse_options<'a>(
+    args: &[&'a str],
+    names: &[&str],
+    multi: &str,
+) -> Option<(Vec<&'a str>, Vec<&'a str>)> {
+    let mut values = vec![None; names.len()];
+    let mut repeated = Vec::new();
+
+    for pair in args.chunks(2) {
+        let (name, value) = match pair {
+            [name, value] => (*name, *value),
+            _ => return None,
+        };
+        if name == multi {
+            repeated.push(value);
+        } else {
+            let index = names.iter().position(|n| *n == name)?;
+            values[index] = Some(value);
+        }
+    }
+
+    let values = values.into_iter().collect::<Option<Vec<_>>>()?;
+    Some((values, repeated))
+}
+
+fn report(command: &str, result: uffd_bug::Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}: {}", command, err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Prints what the kernel's userfaultfd supports.
+fn probe() -> uffd_bug::Result<()> {
+    print!("{}", Capabilities::probe()?);
+    Ok(())
+}
+
+fn snapshot(memfd: &str, out: &str, parents: &[&str]) -> uffd_bug::Result<()> {
+    let memory = File::open(memfd)?;
+    let len = memory.metadata()?.len() as usize;
+    let page_size = uffd_bug::page_size();
+
+    let (header, present) = if parents.is_empty() {
+        let header = Header::flat(LayerKind::Base, len, page_size);
+        let mut present = PageBitmap::new(header.total_pages);
+        present.set_range(0..header.total_pages);
+        (header, present)
+    } else {
+        let chain = SnapshotChain::open(parents)?;
+        let changed = chain.changed_pages(&memory)?;
+        (Header::flat(LayerKind::Diff, len, page_size), changed)
+    };
+
+    let mut out = BufWriter::new(File::create(out)?);
+    let stats = snapshot::write_layer(&mut out, &header, &memory, &present)?;
+    out.flush()?;
+    println!(
+        "wrote {:?} layer with {} of {} pages, {} of them zero ({} bytes saved)",
+        header.kind,
+        present.count_ones(),
+        header.total_pages,
+        stats.zero_pages,
+        stats.bytes_saved()
+    );
+    Ok(())
+}
+
+fn compress(memfd: &str, out: &str, block_size: usize) -> uffd_bug::Result<()> {
+    let memory = File::open(memfd)?;
+    let len = memory.metadata()?.len();
+    let page_size = uffd_bug::page_size();
+
+    let mut out = BufWriter::new(File::create(out)?);
+    let stats = compressed::write_compressed(&mut out, &memory, len, page_size, block_size)?;
+    out.flush()?;
+    println!(
+        "compressed {} bytes into {} ({} blocks, {} zero), ratio {:.2}",
+        stats.raw_bytes,
+        stats.stored_bytes,
+        stats.blocks,
+        stats.zero_blocks,
+        stats.ratio()
+    );
+    Ok(())
+}
+
+fn dedup(memfd: &str, store: &str, manifest: &str) -> uffd_bug::Result<()> {
+    let memory = File::open(memfd)?;
+    let len = memory.metadata()?.len() as usize;
+    let store = if Path::new(store).exists() {
+        PageStore::open(store)?
+    } else {
+        PageStore::create(store, uffd_bug::page_size())?
     };
 
-    (file, mapping)
+    let (pages, stats) = dedup::write_snapshot(&store, &memory, len)?;
+    pages.save(manifest)?;
+    println!(
+        "{} pages: {} new, {} already stored, {} zero; store holds {}",
+        pages.hashes.len(),
+        stats.stored,
+        stats.shared,
+        stats.zero,
+        store.len()
+    );
+    Ok(())
 }
 
-fn create_vm_mapping(fd: i32, size: usize) -> *mut c_void {
-    unsafe {
-        mman::mmap(
-            std::ptr::null_mut(),
-            size,
-            mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE,
-            mman::MapFlags::MAP_SHARED,
-            fd,
-            0,
-        )
-        .unwrap()
+fn restore(out: &str, prefetch: Option<&str>, layers: &[&str]) -> uffd_bug::Result<()> {
+    let chain = SnapshotChain::open(layers)?;
+    let working_set = prefetch.map(Trace::open).transpose()?;
+    let restored = snapshot::restore_with(chain, working_set.as_ref())?;
+
+    // Touch every page through the registered mapping so each one is
+    // served by the handler or was prefetched.
+    let data = restored.vm.read(0, restored.vm.len());
+    std::fs::write(out, data)?;
+    println!(
+        "mapped {} zero pages without copying ({} bytes saved)",
+        restored.zero_pages.pages(),
+        restored.zero_pages.bytes_saved()
+    );
+
+    if let Some(prefetcher) = restored.prefetcher {
+        let stats = prefetcher.join()?;
+        println!(
+            "prefetched {} pages, {} faulted first, hit rate {:.1}%",
+            stats.installed,
+            stats.already_present,
+            stats.hit_rate() * 100.0
+        );
     }
+    Ok(())
 }
 
-fn handle_uffd_events(uffd: Uffd, mem_size: u64, vm_addr: u64, tx: Sender<()>) {
-    let (file, mem_addr) = create_uffd_mapping(mem_size);
-    // Loop, handling incoming events on the userfaultfd file descriptor.
-    let pollfd = PollFd::new(uffd.as_raw_fd(), PollFlags::POLLIN);
+/// Serves the memory in `memfd` to post-copy destinations until killed.
+fn migrate_source(memfd: &str, listen: &str) -> uffd_bug::Result<()> {
+    let len = std::fs::metadata(memfd)?.len() as usize;
+    let page_size = uffd_bug::page_size();
+    let total_pages = len.div_ceil(page_size);
+    let source = SnapshotFile::open(memfd)?;
 
-    loop {
-        println!("Checking");
-        // Wait for fd to become available
-        poll(&mut [pollfd], -1).unwrap();
-        let revents = pollfd.revents().unwrap();
+    let listener = TcpListener::bind(listen)?;
+    println!(
+        "serving {} pages on {}",
+        total_pages,
+        listener.local_addr()?
+    );
+    PageServer::new(source, page_size, total_pages)
+        .verbose(true)
+        .serve(listener)
+}
 
-        if revents.contains(PollFlags::POLLERR) {
-            panic!("poll returned POLLERR");
-        }
+/// Resumes on memory pulled from the source at `addr` as it faults and
+/// writes all of it to `out`.
+fn migrate_dest(addr: &str, out: &str, prefetch_batch: Option<usize>) -> uffd_bug::Result<()> {
+    let mut dest = Destination::connect(addr, prefetch_batch)?;
 
-        // Read an event from the userfaultfd.
-        let event = uffd.read_event().expect("Failed to read uffd_msg");
+    // Touch every page so each one is fetched on demand or was
+    // prefetched.
+    let data = dest.vm.read(0, dest.vm.len());
+    std::fs::write(out, data)?;
+    println!("received {} bytes", dest.vm.len());
 
-        match event {
-            Some(userfaultfd::Event::Pagefault { kind, rw, addr }) => {
-                println!("Pagefault event: {:?}", event);
-                let relative_addr = (addr as u64) - vm_addr;
+    if let Some(prefetcher) = dest.prefetcher.take() {
+        let stats = prefetcher.join()?;
+        println!(
+            "prefetched {} pages, {} faulted first",
+            stats.installed, stats.already_present
+        );
+    }
+    Ok(())
+}
 
-                if kind == FaultKind::Missing {
-                    // Missing event
-                    unsafe { uffd.zeropage(addr, mem_size as _, true).unwrap() };
-                } else if kind == FaultKind::Minor {
-                    // Minor event
-                    while let Err(err) = uffd.uffd_continue(addr, mem_size as _, true) {
-                        println!("uffd_continue failed: {:?}", err);
-                    }
-                }
-            }
-            Some(userfaultfd::Event::Remove { .. }) => {
-                println!("Remove event: {:?}", event);
-            }
-            ev => {
-                panic!("Unexpected event: {:?}", ev);
-            }
+/// Sends the memory in `memfd` to the destination at `addr` in pre-copy
+/// rounds. Only writes through this process's tracked mapping count as
+/// dirtying a page, so the memory should not change elsewhere meanwhile.
+fn precopy_send(memfd: &str, addr: &str, config: PrecopyConfig) -> uffd_bug::Result<()> {
+    let file = OpenOptions::new().read(true).write(true).open(memfd)?;
+    let memory = MemfdRegion::from_file(file)?;
+    let vm = memory.map_vm()?;
+    let uffd = create_uffd()?;
+    vm.register(&uffd, memfd_register_mode()? | RegisterMode::WRITE_PROTECT)?;
+    let tracker = DirtyTracker::new(&uffd, &vm)?;
+    FaultHandler::new(uffd, &vm)
+        .dirty_tracker(tracker.clone())
+        .spawn();
+
+    let stream = TcpStream::connect(addr)?;
+    let report = precopy::send(stream, &memory, &tracker, config, || {})?;
+    for round in &report.rounds {
+        println!(
+            "round {}: sent {} pages in {:?}, {} dirtied meanwhile",
+            round.round, round.pages_sent, round.duration, round.dirtied
+        );
+    }
+    match report.outcome {
+        PrecopyOutcome::Converged => println!("converged"),
+        PrecopyOutcome::PostCopy { remaining } => println!(
+            "switched to post-copy with {} pages left; serve them with migrate-source",
+            remaining.count_ones()
+        ),
+    }
+    Ok(())
+}
+
+/// Receives a pre-copy migration on `listen` and writes the memory to
+/// `out`, fetching from the page server at `postcopy` whatever the source
+/// left for post-copy.
+fn precopy_receive(listen: &str, out: &str, postcopy: Option<&str>) -> uffd_bug::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    println!("waiting on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+
+    let data = match precopy::receive(stream)? {
+        Received::Complete(memory) => memory.read(0, memory.len()),
+        Received::PostCopy { memory, remaining } => {
+            let addr = postcopy.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "source switched to post-copy; pass --postcopy",
+                )
+            })?;
+            println!("fetching {} pages post-copy", remaining.count_ones());
+            let dest = Destination::resume(memory, addr, None)?;
+            dest.vm.read(0, dest.vm.len())
         }
+    };
+    std::fs::write(out, &data)?;
+    println!("received {} bytes", data.len());
+    Ok(())
+}
+
+fn dump_trace(path: &str, json: bool) -> uffd_bug::Result<()> {
+    let trace = Trace::open(path)?;
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    if json {
+        trace.write_json(&mut out)?;
+    } else {
+        trace.write_csv(&mut out)?;
     }
+    out.flush()?;
+    Ok(())
 }
 
-fn write_to_pointer(ptr: *mut c_void, data: &[u8]) {
-    unsafe {
-        std::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+fn parse_timeout(args: &[&str]) -> Option<Duration> {
+    match args {
+        [] => Some(Duration::from_secs(5)),
+        ["--timeout", secs] => secs.parse().ok().map(Duration::from_secs),
+        _ => None,
     }
 }
 
-fn read_from_pointer(ptr: *mut c_void, size: usize) -> Vec<u8> {
-    let mut data = vec![0; size];
-    unsafe {
-        std::ptr::copy_nonoverlapping(ptr as *const u8, data.as_mut_ptr(), size);
+fn run(selected: &[&Scenario], timeout: Duration) -> ExitCode {
+    println!("kernel {}", nix::sys::utsname::uname().release());
+
+    let mut failed = false;
+    for scenario in selected {
+        let outcome = scenario.run(timeout);
+        println!("{:<28} {}", scenario.name, outcome);
+        // Scenarios the kernel cannot run are skipped, not failed.
+        failed |= matches!(outcome, Outcome::Fail(_) | Outcome::Hang(_));
     }
-    data
+
+    // A hung scenario's thread is still blocked in a page fault, so leave
+    // through exit() instead of waiting on it.
+    std::process::exit(if failed { 1 } else { 0 })
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from(2)
 }
@@ -0,0 +1,7 @@
+//! Live migration of memfd-backed memory between two handler processes.
+
+pub mod postcopy;
+pub mod precopy;
+mod protocol;
+
+pub use protocol::Hello;
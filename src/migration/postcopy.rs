@@ -0,0 +1,425 @@
+//! Post-copy: the destination resumes first and pulls pages as it faults.
+
+use std::{
+    ffi::c_void,
+    io::{self, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use userfaultfd::{ReadWrite, Uffd};
+
+use super::protocol::{self, Hello, Request};
+use crate::{
+    create_uffd, handler::Filler, memfd_register_mode, Error, ErrorClass, FaultHandler,
+    MemfdRegion, Page, PageSource, Region, Result, VmRegion,
+};
+
+/// Serves pages of frozen source memory to destinations.
+pub struct PageServer {
+    source: Arc<dyn PageSource>,
+    hello: Hello,
+    stats: ServerStats,
+    verbose: bool,
+}
+
+/// Connections a [`PageServer`] accepted and how many of them ended in an
+/// error, shared with whoever watches it.
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    inner: Arc<ServerCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ServerCounters {
+    connections: AtomicU64,
+    failed: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl ServerStats {
+    fn record_connection(&self) {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_failed(&self, err: &io::Error) {
+        self.inner.failed.fetch_add(1, Ordering::Relaxed);
+        *self.inner.last_error.lock().unwrap() = Some(err.to_string());
+    }
+
+    /// Connections accepted.
+    pub fn connections(&self) -> u64 {
+        self.inner.connections.load(Ordering::Relaxed)
+    }
+
+    /// Connections dropped because of an error, such as a malformed
+    /// request, rather than closed by the destination.
+    pub fn failed(&self) -> u64 {
+        self.inner.failed.load(Ordering::Relaxed)
+    }
+
+    /// What the most recent failed connection ended with.
+    pub fn last_error(&self) -> Option<String> {
+        self.inner.last_error.lock().unwrap().clone()
+    }
+}
+
+impl PageServer {
+    pub fn new(source: impl PageSource + 'static, page_size: usize, total_pages: usize) -> Self {
+        Self {
+            source: Arc::new(source),
+            hello: Hello {
+                page_size,
+                total_pages,
+            },
+            stats: ServerStats::default(),
+            verbose: false,
+        }
+    }
+
+    /// Prints each connection that fails.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Counts connections and their failures; take a handle before
+    /// serving.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.clone()
+    }
+
+    /// Accepts connections on `listener` forever, serving each on its own
+    /// thread.
+    ///
+    /// A connection that fails is dropped without stopping the others; it
+    /// is counted in the [`ServerStats`], and printed if verbose.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            self.stats.record_connection();
+            let source = self.source.clone();
+            let (hello, stats, verbose) = (self.hello, self.stats.clone(), self.verbose);
+            std::thread::spawn(move || {
+                let peer = stream.peer_addr();
+                if let Err(err) = serve_connection(&*source, hello, stream) {
+                    if verbose {
+                        match peer {
+                            Ok(peer) => println!("Connection from {} failed: {}", peer, err),
+                            Err(_) => println!("Connection failed: {}", err),
+                        }
+                    }
+                    stats.record_failed(&err);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Answers requests on `stream` until the destination says goodbye or
+/// hangs up.
+fn serve_connection(source: &dyn PageSource, hello: Hello, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    let mut buf = vec![0; hello.page_size];
+
+    hello.write_to(&mut out)?;
+    out.flush()?;
+
+    loop {
+        let request = match Request::read_from(&mut input) {
+            Ok(request) => request,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        match request {
+            Request::Pages { first, count } => {
+                let end = first
+                    .checked_add(count)
+                    .filter(|&end| end <= hello.total_pages)
+                    .ok_or_else(|| protocol::invalid("page request past the end of memory"))?;
+                for index in first..end {
+                    protocol::write_page(&mut out, source, index, &mut buf)?;
+                }
+                out.flush()?;
+            }
+            Request::Bye => return Ok(()),
+        }
+    }
+}
+
+/// Pages fetched over the network from a [`PageServer`] as faults need
+/// them.
+pub struct RemoteSource {
+    conn: Mutex<Connection>,
+    hello: Hello,
+    fetched: AtomicU64,
+}
+
+struct Connection {
+    input: BufReader<TcpStream>,
+    out: BufWriter<TcpStream>,
+}
+
+impl RemoteSource {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut input = BufReader::new(stream.try_clone()?);
+        let hello = Hello::read_from(&mut input)?;
+
+        Ok(Self {
+            conn: Mutex::new(Connection {
+                input,
+                out: BufWriter::new(stream),
+            }),
+            hello,
+            fetched: AtomicU64::new(0),
+        })
+    }
+
+    pub fn hello(&self) -> Hello {
+        self.hello
+    }
+
+    /// Pages fetched so far.
+    pub fn fetched(&self) -> u64 {
+        self.fetched.load(Ordering::Relaxed)
+    }
+
+    /// Fetches `count` pages starting at `first` into `buf`, which holds
+    /// `count` pages, and returns what was found for each.
+    pub fn fetch(&self, first: usize, count: usize, buf: &mut [u8]) -> io::Result<Vec<Page>> {
+        let page_size = self.hello.page_size;
+        let mut conn = self.conn.lock().unwrap();
+
+        Request::Pages { first, count }.write_to(&mut conn.out)?;
+        conn.out.flush()?;
+        let pages = buf
+            .chunks_exact_mut(page_size)
+            .take(count)
+            .map(|page| protocol::read_page(&mut conn.input, page))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        self.fetched.fetch_add(count as u64, Ordering::Relaxed);
+        Ok(pages)
+    }
+}
+
+impl Drop for RemoteSource {
+    fn drop(&mut self) {
+        if let Ok(conn) = self.conn.get_mut() {
+            let _ = Request::Bye.write_to(&mut conn.out);
+            let _ = conn.out.flush();
+        }
+    }
+}
+
+impl PageSource for RemoteSource {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        Ok(self.fetch(index, 1, buf)?[0])
+    }
+}
+
+/// What the background pre-fetcher did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrefetchStats {
+    /// Pages the pre-fetcher installed.
+    pub installed: u64,
+    /// Pages a fault had already brought in first.
+    pub already_present: u64,
+}
+
+/// Streams every page from the source into the registered range in the
+/// background, racing the demand handler.
+///
+/// Installed pages are marked loaded in the handler, as its own fills
+/// are, so they are never fetched again; pages the handler already
+/// loaded or poisoned are left alone.
+pub struct Prefetcher {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<PrefetchStats>>,
+}
+
+impl Prefetcher {
+    /// Starts fetching `batch` pages at a time over its own connection and
+    /// installing them into `handler`'s regions. The handler is spawned
+    /// afterwards, since the two share its bookkeeping.
+    ///
+    /// A batch must hold at least one page.
+    pub fn spawn(addr: impl ToSocketAddrs, handler: &FaultHandler, batch: usize) -> Result<Self> {
+        if batch == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "prefetch batch must hold at least one page",
+            )
+            .into());
+        }
+        let remote = RemoteSource::connect(addr)?;
+        let (uffd, regions, filler) = handler.prefetch_target()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || prefetch(&remote, &uffd, &regions, &filler, batch, &stop)
+        });
+        Ok(Self { stop, thread })
+    }
+
+    /// Asks the pre-fetcher to stop after the current batch.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits for the pre-fetcher to finish.
+    pub fn join(self) -> Result<PrefetchStats> {
+        self.thread.join().expect("prefetcher panicked")
+    }
+}
+
+fn prefetch(
+    remote: &RemoteSource,
+    uffd: &Uffd,
+    regions: &[Region],
+    filler: &Filler,
+    batch: usize,
+    stop: &AtomicBool,
+) -> Result<PrefetchStats> {
+    let Hello {
+        page_size,
+        total_pages,
+    } = remote.hello();
+    let mut stats = PrefetchStats::default();
+    let mut buf = vec![0; batch * page_size];
+    // With dirty tracking, pages are protected before anyone is woken.
+    let wake = filler.dirty().is_none();
+
+    for first in (0..total_pages).step_by(batch) {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let count = batch.min(total_pages - first);
+        let pages = remote.fetch(first, count, &mut buf)?;
+
+        for (i, page) in pages.into_iter().enumerate() {
+            let file_page = first + i;
+            if filler.is_loaded(file_page) {
+                stats.already_present += 1;
+                continue;
+            }
+            if filler.is_poisoned(file_page) {
+                continue;
+            }
+            let offset = (file_page * page_size) as u64;
+            let region = regions
+                .iter()
+                .find(|r| (r.offset..r.offset + r.len as u64).contains(&offset));
+            let addr = match region {
+                Some(region) => region.start + (offset - region.offset) as usize,
+                None => continue,
+            };
+            let dst = addr as *mut c_void;
+            let result = match page {
+                Page::Data => unsafe {
+                    let src = buf[i * page_size..].as_ptr() as *const c_void;
+                    uffd.copy(src, dst, page_size, wake)
+                },
+                Page::Zero => unsafe { uffd.zeropage(dst, page_size, wake) },
+                Page::Absent => continue,
+            };
+            match result.map_err(Error::from) {
+                Ok(_) => {
+                    filler.mark_loaded(file_page);
+                    stats.installed += 1;
+                    if let Some(tracker) = filler.dirty() {
+                        tracker.after_fill(uffd, addr..addr + page_size, addr, ReadWrite::Read)?;
+                    }
+                }
+                // The guest faulted it in first.
+                Err(err) if ErrorClass::of(&err) == ErrorClass::AlreadyMapped => {
+                    stats.already_present += 1
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// The destination side of a post-copy migration.
+pub struct Destination {
+    pub memory: MemfdRegion,
+    pub vm: VmRegion,
+    pub handler: JoinHandle<Result<()>>,
+    pub prefetcher: Option<Prefetcher>,
+}
+
+impl Destination {
+    /// Sets up empty memory matching the source at `addr`, serves its
+    /// faults from the source and, with `prefetch_batch`, streams the rest
+    /// in the background, that many pages at a time. A batch of 0 is
+    /// rejected.
+    pub fn connect(
+        addr: impl ToSocketAddrs + Clone,
+        prefetch_batch: Option<usize>,
+    ) -> Result<Self> {
+        let remote = RemoteSource::connect(addr.clone())?;
+        let memory = MemfdRegion::new(remote.hello().memory_len())?;
+        Self::attach(memory, remote, addr, prefetch_batch)
+    }
+
+    /// Like [`Destination::connect`], but resumes on `memory` that already
+    /// holds some pages, such as after an unfinished pre-copy. Only pages
+    /// missing from its memfd are fetched.
+    pub fn resume(
+        memory: MemfdRegion,
+        addr: impl ToSocketAddrs + Clone,
+        prefetch_batch: Option<usize>,
+    ) -> Result<Self> {
+        let remote = RemoteSource::connect(addr.clone())?;
+        Self::attach(memory, remote, addr, prefetch_batch)
+    }
+
+    fn attach(
+        memory: MemfdRegion,
+        remote: RemoteSource,
+        addr: impl ToSocketAddrs,
+        prefetch_batch: Option<usize>,
+    ) -> Result<Self> {
+        let hello = remote.hello();
+        if hello.page_size != crate::page_size() || hello.memory_len() != memory.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "source memory layout differs from ours",
+            )
+            .into());
+        }
+
+        let vm = memory.map_vm()?;
+        let uffd = create_uffd()?;
+        vm.register(&uffd, memfd_register_mode()?)?;
+
+        // Pages missing from the memfd arrive through UFFDIO_COPY, so a
+        // MINOR fault means the page cache is already right; no memfd to
+        // populate.
+        let handler = FaultHandler::new(uffd, &vm).page_source(remote, None);
+        let prefetcher = match prefetch_batch {
+            Some(batch) => Some(Prefetcher::spawn(addr, &handler, batch)?),
+            None => None,
+        };
+        let handler = handler.spawn();
+
+        Ok(Self {
+            memory,
+            vm,
+            handler,
+            prefetcher,
+        })
+    }
+}
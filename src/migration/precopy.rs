@@ -0,0 +1,232 @@
+//! Pre-copy: copy all memory while the guest runs, re-send what it
+//! dirtied in rounds, then pause it for a short final copy.
+
+use std::{
+    io::{self, BufReader, BufWriter, Write},
+    net::TcpStream,
+    os::unix::{fs::FileExt, prelude::AsRawFd},
+    time::{Duration, Instant},
+};
+
+use nix::fcntl::{fallocate, FallocateFlags};
+
+use super::protocol::{self, Hello, Push};
+use crate::{DirtyTracker, MemfdRegion, Page, PageBitmap, Result, SnapshotFile};
+
+/// When to stop iterating.
+#[derive(Debug, Clone, Copy)]
+pub struct PrecopyConfig {
+    /// Pause and finish once a round leaves at most this many dirty pages.
+    pub threshold: usize,
+    /// Give up and switch to post-copy after this many rounds.
+    pub max_rounds: usize,
+    /// Also switch once this many rounds in a row fail to shrink the
+    /// dirty set.
+    pub stalled_rounds: usize,
+}
+
+impl Default for PrecopyConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 64,
+            max_rounds: 30,
+            stalled_rounds: 3,
+        }
+    }
+}
+
+/// What one round did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoundStats {
+    pub round: usize,
+    pub pages_sent: usize,
+    pub duration: Duration,
+    /// Pages the guest dirtied while the round was being sent.
+    pub dirtied: usize,
+    /// `dirtied` per second of the round.
+    pub dirty_rate: f64,
+}
+
+impl RoundStats {
+    /// Whether this round left fewer dirty pages than it sent.
+    pub fn converging(&self) -> bool {
+        self.dirtied < self.pages_sent
+    }
+}
+
+/// How the migration ended on the source side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The destination has every page.
+    Converged,
+    /// The destination must fetch `remaining` post-copy; keep serving the
+    /// paused memory with a [`super::postcopy::PageServer`].
+    PostCopy { remaining: PageBitmap },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Every round sent, ending with the one sent while the guest was
+    /// paused if the migration converged.
+    pub rounds: Vec<RoundStats>,
+    pub outcome: Outcome,
+}
+
+/// Migrates `memory` to the destination on `stream`.
+///
+/// `tracker` must track the guest's mapping of `memory`. `pause` is called
+/// once, when the guest has to stop writing for the final round.
+pub fn send(
+    stream: TcpStream,
+    memory: &MemfdRegion,
+    tracker: &DirtyTracker,
+    config: PrecopyConfig,
+    pause: impl FnOnce(),
+) -> Result<Report> {
+    let page_size = crate::page_size();
+    let total_pages = memory.len() / page_size;
+    let source = SnapshotFile::from_file(memory.file().try_clone()?)?;
+    let mut out = BufWriter::new(stream);
+    let mut buf = vec![0; page_size];
+
+    Hello {
+        page_size,
+        total_pages,
+    }
+    .write_to(&mut out)?;
+
+    // Round 0 sends everything; whatever was dirtied before it is covered.
+    tracker.take()?;
+    let mut to_send = PageBitmap::new(total_pages);
+    to_send.set_range(0..total_pages);
+
+    let mut rounds: Vec<RoundStats> = Vec::new();
+    let mut stalled = 0;
+    loop {
+        let started = Instant::now();
+        send_pages(&mut out, &source, &to_send, &mut buf)?;
+        out.flush()?;
+        let dirty = tracker.take()?;
+        let duration = started.elapsed();
+
+        let stats = RoundStats {
+            round: rounds.len(),
+            pages_sent: to_send.count_ones(),
+            duration,
+            dirtied: dirty.count_ones(),
+            dirty_rate: dirty.count_ones() as f64 / duration.as_secs_f64().max(1e-9),
+        };
+        stalled = if stats.converging() { 0 } else { stalled + 1 };
+        rounds.push(stats);
+        to_send = dirty;
+
+        let converged = to_send.count_ones() <= config.threshold;
+        let give_up = rounds.len() >= config.max_rounds || stalled >= config.stalled_rounds;
+        if !converged && !give_up {
+            continue;
+        }
+
+        // Stop the guest; anything it wrote since the last take is the
+        // final dirty set.
+        pause();
+        to_send.union_with(&tracker.take()?);
+
+        let outcome = if converged {
+            let started = Instant::now();
+            send_pages(&mut out, &source, &to_send, &mut buf)?;
+            Push::Done.write_to(&mut out)?;
+            // Nothing runs to dirty pages during the final round.
+            rounds.push(RoundStats {
+                round: rounds.len(),
+                pages_sent: to_send.count_ones(),
+                duration: started.elapsed(),
+                dirtied: 0,
+                dirty_rate: 0.0,
+            });
+            Outcome::Converged
+        } else {
+            Push::Switch {
+                remaining: to_send.clone(),
+            }
+            .write_to(&mut out)?;
+            Outcome::PostCopy { remaining: to_send }
+        };
+        out.flush()?;
+        return Ok(Report { rounds, outcome });
+    }
+}
+
+fn send_pages(
+    out: &mut impl Write,
+    source: &SnapshotFile,
+    pages: &PageBitmap,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    Push::Pages {
+        count: pages.count_ones(),
+    }
+    .write_to(out)?;
+    for index in pages.iter_ones() {
+        protocol::write_index(out, index)?;
+        protocol::write_page(out, source, index, buf)?;
+    }
+    Ok(())
+}
+
+/// What the destination ended up with.
+pub enum Received {
+    /// Every page arrived; the guest can start on `memory` as is.
+    Complete(MemfdRegion),
+    /// The source switched to post-copy. The `remaining` pages were punched
+    /// out of `memory`; resume with
+    /// [`super::postcopy::Destination::resume`] to fetch them on demand.
+    PostCopy {
+        memory: MemfdRegion,
+        remaining: PageBitmap,
+    },
+}
+
+/// Receives a pre-copy migration on `stream` into fresh memory.
+pub fn receive(stream: TcpStream) -> Result<Received> {
+    let mut input = BufReader::new(stream);
+    let hello = Hello::read_from(&mut input)?;
+    if hello.page_size != crate::page_size() {
+        return Err(protocol::invalid("source page size differs from ours").into());
+    }
+
+    let memory = MemfdRegion::new(hello.memory_len())?;
+    let memfd = memory.file();
+    let mut buf = vec![0; hello.page_size];
+
+    loop {
+        match Push::read_from(&mut input, hello.total_pages)? {
+            Push::Pages { count } => {
+                for _ in 0..count {
+                    let index = protocol::read_index(&mut input)?;
+                    if index >= hello.total_pages {
+                        return Err(protocol::invalid("page index out of range").into());
+                    }
+                    match protocol::read_page(&mut input, &mut buf)? {
+                        Page::Data => {}
+                        Page::Zero => buf.fill(0),
+                        Page::Absent => continue,
+                    }
+                    memfd.write_all_at(&buf, (index * hello.page_size) as u64)?;
+                }
+            }
+            Push::Done => return Ok(Received::Complete(memory)),
+            Push::Switch { remaining } => {
+                for index in remaining.iter_ones() {
+                    let offset = (index * hello.page_size) as i64;
+                    fallocate(
+                        memfd.as_raw_fd(),
+                        FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                        offset,
+                        hello.page_size as i64,
+                    )?;
+                }
+                return Ok(Received::PostCopy { memory, remaining });
+            }
+        }
+    }
+}
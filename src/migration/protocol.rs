@@ -0,0 +1,201 @@
+//! Wire format shared by the migration modes.
+//!
+//! A connection opens with the source sending a [`Hello`]. After that the
+//! destination sends requests and the source answers each in order:
+//!
+//! ```text
+//! hello    magic u32, page_size u32, total_pages u64
+//! request  op u8 (1 = pages, 0 = bye), first u64, count u32
+//! reply    count * (tag u8 (0 = data, 1 = zero, 2 = absent), page if data)
+//! ```
+//!
+//! Pre-copy turns this around: the source pushes messages after its
+//! hello and the destination only listens:
+//!
+//! ```text
+//! pages    msg u8 = 1, count u32, count * (index u64, tag u8, page if data)
+//! done     msg u8 = 2
+//! switch   msg u8 = 3, bitmap of pages the destination must still fetch
+//! ```
+//!
+//! All integers are little-endian.
+
+use std::io::{self, Read, Write};
+
+use crate::{Page, PageBitmap, PageSource};
+
+const MAGIC: u32 = 0x5546_4644;
+
+const OP_BYE: u8 = 0;
+const OP_PAGES: u8 = 1;
+
+const TAG_DATA: u8 = 0;
+const TAG_ZERO: u8 = 1;
+const TAG_ABSENT: u8 = 2;
+
+/// What the source announces about the memory it migrates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub page_size: usize,
+    pub total_pages: usize,
+}
+
+impl Hello {
+    pub fn memory_len(&self) -> usize {
+        self.page_size * self.total_pages
+    }
+
+    pub(crate) fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&MAGIC.to_le_bytes())?;
+        out.write_all(&(self.page_size as u32).to_le_bytes())?;
+        out.write_all(&(self.total_pages as u64).to_le_bytes())
+    }
+
+    pub(crate) fn read_from(input: &mut impl Read) -> io::Result<Self> {
+        if read_u32(input)? != MAGIC {
+            return Err(invalid("peer is not a migration source"));
+        }
+        Ok(Self {
+            page_size: read_u32(input)? as usize,
+            total_pages: read_u64(input)? as usize,
+        })
+    }
+}
+
+/// A destination's request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Request {
+    Pages { first: usize, count: usize },
+    Bye,
+}
+
+impl Request {
+    pub(crate) fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        let (op, first, count) = match *self {
+            Request::Pages { first, count } => (OP_PAGES, first, count),
+            Request::Bye => (OP_BYE, 0, 0),
+        };
+        out.write_all(&[op])?;
+        out.write_all(&(first as u64).to_le_bytes())?;
+        out.write_all(&(count as u32).to_le_bytes())
+    }
+
+    pub(crate) fn read_from(input: &mut impl Read) -> io::Result<Self> {
+        let mut op = [0];
+        input.read_exact(&mut op)?;
+        let first = read_u64(input)? as usize;
+        let count = read_u32(input)? as usize;
+        match op[0] {
+            OP_PAGES => Ok(Request::Pages { first, count }),
+            OP_BYE => Ok(Request::Bye),
+            _ => Err(invalid("unknown request")),
+        }
+    }
+}
+
+/// A message pushed by a pre-copy source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Push {
+    /// `count` pages follow, each as an index and a reply page.
+    Pages { count: usize },
+    /// Every page has been sent; the destination is complete.
+    Done,
+    /// The source gave up converging; these pages are stale at the
+    /// destination and must be fetched post-copy.
+    Switch { remaining: PageBitmap },
+}
+
+const PUSH_PAGES: u8 = 1;
+const PUSH_DONE: u8 = 2;
+const PUSH_SWITCH: u8 = 3;
+
+impl Push {
+    pub(crate) fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Push::Pages { count } => {
+                out.write_all(&[PUSH_PAGES])?;
+                out.write_all(&(*count as u32).to_le_bytes())
+            }
+            Push::Done => out.write_all(&[PUSH_DONE]),
+            Push::Switch { remaining } => {
+                out.write_all(&[PUSH_SWITCH])?;
+                out.write_all(&remaining.to_bytes())
+            }
+        }
+    }
+
+    pub(crate) fn read_from(input: &mut impl Read, total_pages: usize) -> io::Result<Self> {
+        let mut msg = [0];
+        input.read_exact(&mut msg)?;
+        match msg[0] {
+            PUSH_PAGES => Ok(Push::Pages {
+                count: read_u32(input)? as usize,
+            }),
+            PUSH_DONE => Ok(Push::Done),
+            PUSH_SWITCH => {
+                let mut bytes = vec![0; total_pages.div_ceil(64) * 8];
+                input.read_exact(&mut bytes)?;
+                let remaining = PageBitmap::from_bytes(total_pages, &bytes)
+                    .ok_or_else(|| invalid("bad remaining bitmap"))?;
+                Ok(Push::Switch { remaining })
+            }
+            _ => Err(invalid("unknown pre-copy message")),
+        }
+    }
+}
+
+pub(crate) fn write_index(out: &mut impl Write, index: usize) -> io::Result<()> {
+    out.write_all(&(index as u64).to_le_bytes())
+}
+
+pub(crate) fn read_index(input: &mut impl Read) -> io::Result<usize> {
+    Ok(read_u64(input)? as usize)
+}
+
+/// Writes one page of a reply, read from `source`.
+pub(crate) fn write_page(
+    out: &mut impl Write,
+    source: &dyn PageSource,
+    index: usize,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    match source.read_page(index, buf)? {
+        Page::Data => {
+            out.write_all(&[TAG_DATA])?;
+            out.write_all(buf)
+        }
+        Page::Zero => out.write_all(&[TAG_ZERO]),
+        Page::Absent => out.write_all(&[TAG_ABSENT]),
+    }
+}
+
+/// Reads one page of a reply into `buf`.
+pub(crate) fn read_page(input: &mut impl Read, buf: &mut [u8]) -> io::Result<Page> {
+    let mut tag = [0];
+    input.read_exact(&mut tag)?;
+    match tag[0] {
+        TAG_DATA => {
+            input.read_exact(buf)?;
+            Ok(Page::Data)
+        }
+        TAG_ZERO => Ok(Page::Zero),
+        TAG_ABSENT => Ok(Page::Absent),
+        _ => Err(invalid("unknown page tag")),
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
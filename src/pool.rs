@@ -0,0 +1,286 @@
+//! Multi-threaded fault handling: one reader drains events in batches and
+//! hands page faults to a pool of workers.
+
+use std::{
+    collections::HashMap,
+    os::unix::prelude::AsRawFd,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use userfaultfd::{Event, EventBuffer, FaultKind, ReadWrite};
+
+use crate::{
+    handler::{handle_change, Filler, Target},
+    install::Scratch,
+    Error, ErrorClass, EventStats, Result,
+};
+
+/// How often the reader looks for finished jobs while faults are in
+/// flight and no new events arrive.
+const DONE_POLL: Duration = Duration::from_millis(1);
+
+/// A page fault handed to a worker.
+struct Job {
+    /// The [`Target::id`] of the uffd the fault was read from.
+    target: u64,
+    kind: FaultKind,
+    rw: ReadWrite,
+    addr: usize,
+    /// When the fault was read off the uffd.
+    read: Instant,
+}
+
+/// A finished job, reported back to the reader.
+struct Done {
+    target: u64,
+    page: usize,
+    result: Result<()>,
+}
+
+/// The reader's share of the handler.
+pub(crate) struct ReaderConfig<'a> {
+    pub(crate) events: &'a mut EventBuffer,
+    pub(crate) stats: &'a EventStats,
+    pub(crate) verbose: bool,
+}
+
+/// Runs the reader on the calling thread and `workers` workers until no
+/// target is left or an error occurs.
+pub(crate) fn run(
+    targets: &mut Vec<Target>,
+    filler: &Filler,
+    workers: usize,
+    config: ReaderConfig,
+) -> Result<()> {
+    let shared = RwLock::new(std::mem::take(targets));
+    let (job_tx, job_rx) = mpsc::channel::<Job>();
+    let (done_tx, done_rx) = mpsc::channel::<Done>();
+    let job_rx = Mutex::new(job_rx);
+
+    let result = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                let done_tx = done_tx.clone();
+                let (shared, job_rx) = (&shared, &job_rx);
+                s.spawn(move || work(shared, filler, job_rx, done_tx))
+            })
+            .collect();
+        drop(done_tx);
+
+        let mut reader = Reader {
+            targets: &shared,
+            page_size: filler.page_size(),
+            jobs: job_tx,
+            done: done_rx,
+            in_flight: HashMap::new(),
+            events: config.events,
+            stats: config.stats,
+            verbose: config.verbose,
+        };
+        let result = reader.run();
+        // Dropping the reader closes the job queue and lets the workers
+        // finish. Joining them here keeps a panicked one from panicking
+        // the scope.
+        drop(reader);
+        let panicked = handles
+            .into_iter()
+            .fold(false, |panicked, handle| handle.join().is_err() || panicked);
+        match result {
+            Ok(()) if panicked => Err(Error::WorkersExited),
+            result => result,
+        }
+    });
+
+    *targets = shared.into_inner().unwrap();
+    result
+}
+
+fn work(
+    targets: &RwLock<Vec<Target>>,
+    filler: &Filler,
+    jobs: &Mutex<Receiver<Job>>,
+    done: Sender<Done>,
+) {
+    let mut buf = Scratch::new(filler.page_size());
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let result = {
+            let targets = targets.read().unwrap();
+            match targets.iter().find(|t| t.id == job.target) {
+                Some(target) => {
+                    target.handle_pagefault(filler, &mut buf, job.kind, job.rw, job.addr, job.read)
+                }
+                // Dropped while the job was queued.
+                None => Ok(()),
+            }
+        };
+        let page = job.addr / filler.page_size();
+        if done
+            .send(Done {
+                target: job.target,
+                page,
+                result,
+            })
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+struct Reader<'a> {
+    targets: &'a RwLock<Vec<Target>>,
+    page_size: usize,
+    jobs: Sender<Job>,
+    done: Receiver<Done>,
+    /// Pages being resolved, with the latest fault that arrived for each
+    /// meanwhile. That fault is handled once the first one is done, which
+    /// for a page that is mapped by then only wakes its thread.
+    in_flight: HashMap<(u64, usize), Option<Job>>,
+    events: &'a mut EventBuffer,
+    stats: &'a EventStats,
+    verbose: bool,
+}
+
+impl Reader<'_> {
+    fn run(&mut self) -> Result<()> {
+        while !self.targets.read().unwrap().is_empty() {
+            let (ids, mut pollfds): (Vec<u64>, Vec<PollFd>) = self
+                .targets
+                .read()
+                .unwrap()
+                .iter()
+                .map(|t| (t.id, PollFd::new(t.uffd.as_raw_fd(), PollFlags::POLLIN)))
+                .unzip();
+            let timeout = if self.in_flight.is_empty() {
+                -1
+            } else {
+                DONE_POLL.as_millis() as i32
+            };
+            poll(&mut pollfds, timeout)?;
+
+            for (id, pollfd) in ids.into_iter().zip(&pollfds) {
+                let revents = pollfd.revents().unwrap_or_else(PollFlags::empty);
+                if revents.contains(PollFlags::POLLERR) {
+                    self.drop_target(id);
+                } else if revents.contains(PollFlags::POLLIN) {
+                    self.drain(id)?;
+                }
+            }
+            self.collect(false)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a batch of events from target `id` and dispatches them all.
+    fn drain(&mut self, id: u64) -> Result<()> {
+        let events = {
+            let targets = self.targets.read().unwrap();
+            let target = match targets.iter().find(|t| t.id == id) {
+                Some(target) => target,
+                None => return Ok(()),
+            };
+            target
+                .uffd
+                .read_events(&mut *self.events)?
+                .collect::<std::result::Result<Vec<_>, _>>()?
+        };
+        if events.is_empty() {
+            return Ok(());
+        }
+        let read = Instant::now();
+        self.stats.record(events.len());
+
+        for event in events {
+            if self.verbose {
+                println!("Event on target {}: {:?}", id, event);
+            }
+            self.dispatch(id, event, read)?;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, id: u64, event: Event, read: Instant) -> Result<()> {
+        match event {
+            Event::Pagefault { kind, rw, addr } => {
+                let job = Job {
+                    target: id,
+                    kind,
+                    rw,
+                    addr: addr as usize,
+                    read,
+                };
+                let key = (id, job.addr / self.page_size);
+                match self.in_flight.get_mut(&key) {
+                    Some(pending) => *pending = Some(job),
+                    None => {
+                        self.in_flight.insert(key, None);
+                        self.send(job)?;
+                    }
+                }
+                Ok(())
+            }
+            event => {
+                // Changes apply to the mappings the queued faults were
+                // raised on, so let those finish first.
+                while !self.in_flight.is_empty() {
+                    self.collect(true)?;
+                }
+                let mut targets = self.targets.write().unwrap();
+                if let Some(index) = targets.iter().position(|t| t.id == id) {
+                    handle_change(&mut targets, index, event, self.page_size);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn send(&self, job: Job) -> Result<()> {
+        // The workers only hang up early if they panic.
+        self.jobs.send(job).map_err(|_| Error::WorkersExited)
+    }
+
+    /// Processes finished jobs, waiting for at least one if `block`.
+    fn collect(&mut self, block: bool) -> Result<()> {
+        let mut next = if block {
+            match self.done.recv_timeout(DONE_POLL) {
+                Ok(done) => Some(done),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::WorkersExited),
+            }
+        } else {
+            self.done.try_recv().ok()
+        };
+
+        while let Some(done) = next {
+            let key = (done.target, done.page);
+            if let Some(Some(job)) = self.in_flight.remove(&key) {
+                self.in_flight.insert(key, None);
+                self.send(job)?;
+            }
+            match done.result {
+                Err(Error::Resolve {
+                    class: ErrorClass::TargetExited,
+                    ..
+                }) => self.drop_target(done.target),
+                result => result?,
+            }
+            next = self.done.try_recv().ok();
+        }
+        Ok(())
+    }
+
+    fn drop_target(&mut self, id: u64) {
+        self.targets.write().unwrap().retain(|t| t.id != id);
+        self.in_flight.retain(|&(target, _), _| target != id);
+    }
+}
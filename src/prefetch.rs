@@ -0,0 +1,171 @@
+//! Working-set prefetch: replays a recorded fault trace so the pages a
+//! previous run touched are mapped before the guest faults on them.
+
+use std::{
+    collections::HashSet,
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use userfaultfd::{ReadWrite, RegisterMode, Uffd};
+
+use crate::{
+    handler::{Fetched, Filler},
+    trace::{Trace, TraceKind},
+    Error, ErrorClass, FaultHandler, Region, Result,
+};
+
+/// What the trace prefetcher did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TracePrefetchStats {
+    /// Pages the prefetcher mapped first: faults the guest was spared.
+    pub installed: u64,
+    /// Pages a demand fault had already mapped.
+    pub already_present: u64,
+    /// Traced pages outside the regions, busy while the mappings were
+    /// changing, or poisoned.
+    pub skipped: u64,
+}
+
+impl TracePrefetchStats {
+    /// Share of the pages that reached the guest which the prefetcher
+    /// mapped before any fault on them.
+    pub fn hit_rate(&self) -> f64 {
+        match self.installed + self.already_present {
+            0 => 0.0,
+            total => self.installed as f64 / total as f64,
+        }
+    }
+}
+
+/// Maps the pages of a trace, in trace order, on a background thread
+/// while the handler serves demand faults.
+///
+/// Pages are filled exactly as the handler would fill them, from the same
+/// source: regions registered for MINOR faults with a memfd have it
+/// populated and are mapped with UFFDIO_CONTINUE, the rest with
+/// UFFDIO_COPY. Losing a race to the demand handler shows up as EEXIST and
+/// is counted, not treated as an error.
+pub struct TracePrefetcher {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<TracePrefetchStats>>,
+}
+
+impl TracePrefetcher {
+    /// Starts prefetching the pages of `trace` into `handler`'s regions.
+    /// Call before the handler is spawned, once it is fully configured.
+    pub fn spawn(handler: &FaultHandler, trace: &Trace) -> Result<Self> {
+        let (uffd, regions, filler) = handler.prefetch_target()?;
+        let page_size = filler.page_size();
+
+        // Only the first fault on a page matters, and write-protect faults
+        // are on pages that were mapped already.
+        let mut seen = HashSet::new();
+        let offsets: Vec<u64> = trace
+            .records
+            .iter()
+            .filter(|r| r.kind != TraceKind::WriteProtect)
+            .map(|r| r.offset - r.offset % page_size as u64)
+            .filter(|&offset| seen.insert(offset))
+            .collect();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || prefetch(&uffd, &regions, &filler, &offsets, &stop)
+        });
+        Ok(Self { stop, thread })
+    }
+
+    /// Asks the prefetcher to stop after the current page.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits for the prefetcher to finish.
+    pub fn join(self) -> Result<TracePrefetchStats> {
+        self.thread.join().expect("prefetcher panicked")
+    }
+}
+
+fn prefetch(
+    uffd: &Uffd,
+    regions: &[Region],
+    filler: &Filler,
+    offsets: &[u64],
+    stop: &AtomicBool,
+) -> Result<TracePrefetchStats> {
+    let page_size = filler.page_size();
+    let mut stats = TracePrefetchStats::default();
+    let mut buf = vec![0; page_size];
+    // With dirty tracking, pages are protected before anyone is woken.
+    let wake = filler.dirty().is_none();
+
+    for &offset in offsets {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let region = regions
+            .iter()
+            .find(|r| (r.offset..r.offset + r.len as u64).contains(&offset));
+        let region = match region {
+            Some(region) => region,
+            None => {
+                stats.skipped += 1;
+                continue;
+            }
+        };
+        let addr = region.start + (offset - region.offset) as usize;
+        let dst = addr as *mut c_void;
+        let file_page = offset as usize / page_size;
+
+        let minor = region.mode.contains(RegisterMode::MODE_MINOR) && filler.has_memfd();
+        let result = if minor {
+            filler.populate(file_page..file_page + 1, &mut buf)?;
+            // Poisoned when, and if, the guest touches it.
+            if filler.is_poisoned(file_page) {
+                stats.skipped += 1;
+                continue;
+            }
+            uffd.uffd_continue(dst, page_size, wake)
+        } else {
+            let result = match filler.read(file_page, &mut buf)? {
+                Fetched::Data(data) => unsafe {
+                    uffd.copy(data.as_ptr() as *const c_void, dst, page_size, wake)
+                },
+                Fetched::Zero => unsafe { uffd.zeropage(dst, page_size, wake) },
+                Fetched::Poison => {
+                    stats.skipped += 1;
+                    continue;
+                }
+            };
+            if result.is_ok() {
+                filler.mark_loaded(file_page);
+            }
+            result
+        };
+
+        match result.map_err(Error::from) {
+            Ok(_) => {
+                stats.installed += 1;
+                if let Some(tracker) = filler.dirty() {
+                    tracker.after_fill(uffd, addr..addr + page_size, addr, ReadWrite::Read)?;
+                }
+            }
+            Err(err) => match ErrorClass::of(&err) {
+                // The guest faulted it in first.
+                ErrorClass::AlreadyMapped => stats.already_present += 1,
+                ErrorClass::MappingChanging | ErrorClass::NotInPageCache => stats.skipped += 1,
+                // Nothing in the page cache to map: the source lacks it.
+                ErrorClass::BadAddress if minor => stats.skipped += 1,
+                ErrorClass::TargetExited => break,
+                _ => return Err(err),
+            },
+        }
+    }
+    Ok(stats)
+}
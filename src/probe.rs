@@ -0,0 +1,175 @@
+//! What the running kernel's userfaultfd can do.
+//!
+//! [`create_uffd`](crate::create_uffd) asks only for the features that are
+//! there and [`memfd_register_mode`](crate::memfd_register_mode) picks the
+//! registration to match, so an older kernel gets a handler that does less
+//! rather than none. [`Capabilities`] spells out what was found.
+
+use std::fmt;
+
+use nix::sys::utsname::uname;
+use userfaultfd::{FeatureFlags, RegisterMode};
+
+use crate::{
+    handler::memfd_mode, page_size, required_features, sys, MemfdRegion, Result, VmRegion,
+};
+
+/// UFFD_FEATURE_* flags by name, in bit order.
+pub const FEATURES: &[(&str, u64)] = &[
+    ("PAGEFAULT_FLAG_WP", 1 << 0),
+    ("EVENT_FORK", 1 << 1),
+    ("EVENT_REMAP", 1 << 2),
+    ("EVENT_REMOVE", 1 << 3),
+    ("MISSING_HUGETLBFS", 1 << 4),
+    ("MISSING_SHMEM", 1 << 5),
+    ("EVENT_UNMAP", 1 << 6),
+    ("SIGBUS", 1 << 7),
+    ("THREAD_ID", 1 << 8),
+    ("MINOR_HUGETLBFS", 1 << 9),
+    ("MINOR_SHMEM", 1 << 10),
+    ("EXACT_ADDRESS", 1 << 11),
+    ("WP_HUGETLBFS_SHMEM", 1 << 12),
+    ("WP_UNPOPULATED", 1 << 13),
+    ("POISON", sys::FEATURE_POISON),
+    ("WP_ASYNC", 1 << 15),
+    ("MOVE", sys::FEATURE_MOVE),
+];
+
+/// Ioctls by name, as the `1 << _UFFDIO_*` bits the kernel reports them
+/// in.
+pub const IOCTLS: &[(&str, u64)] = &[
+    ("UFFDIO_API", 1 << 0x3f),
+    ("UFFDIO_REGISTER", 1 << 0x00),
+    ("UFFDIO_UNREGISTER", 1 << 0x01),
+    ("UFFDIO_WAKE", 1 << 0x02),
+    ("UFFDIO_COPY", 1 << 0x03),
+    ("UFFDIO_ZEROPAGE", 1 << 0x04),
+    ("UFFDIO_MOVE", 1 << 0x05),
+    ("UFFDIO_WRITEPROTECT", 1 << 0x06),
+    ("UFFDIO_CONTINUE", 1 << 0x07),
+    ("UFFDIO_POISON", 1 << 0x08),
+];
+
+/// The userfaultfd features and ioctls of the running kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The kernel release, as `uname -r` prints it.
+    pub kernel: String,
+    /// UFFD_FEATURE_* bits offered by the API handshake.
+    pub features: u64,
+    /// Ioctls on the uffd itself.
+    pub ioctls: u64,
+    /// Ioctls on a private anonymous range, registered for MISSING and,
+    /// where possible, write-protect faults.
+    pub anon_ioctls: u64,
+    /// Ioctls on a shared memfd range, registered for MISSING and, where
+    /// possible, MINOR and write-protect faults.
+    pub shmem_ioctls: u64,
+}
+
+impl Capabilities {
+    /// Opens a uffd, completes the API handshake and registers a page of
+    /// each kind of memory with it to see what is allowed.
+    pub fn probe() -> Result<Self> {
+        let handshake = sys::handshake()?;
+        let uffd = &handshake.uffd;
+        let len = page_size();
+
+        // Registering fails outright for a mode the kernel lacks, so step
+        // down until one works.
+        let range_ioctls = |addr, modes: &[RegisterMode]| {
+            modes
+                .iter()
+                .find_map(|&mode| sys::register(uffd, addr, len, mode).ok())
+                .unwrap_or(0)
+        };
+        let anon = VmRegion::anonymous(len)?;
+        let anon_ioctls = range_ioctls(
+            anon.as_ptr(),
+            &[
+                RegisterMode::MISSING | RegisterMode::WRITE_PROTECT,
+                RegisterMode::MISSING,
+            ],
+        );
+        let memory = MemfdRegion::new(len)?;
+        let shmem = memory.map_vm()?;
+        let shmem_ioctls = range_ioctls(
+            shmem.as_ptr(),
+            &[
+                RegisterMode::MISSING | RegisterMode::MODE_MINOR | RegisterMode::WRITE_PROTECT,
+                RegisterMode::MISSING | RegisterMode::MODE_MINOR,
+                RegisterMode::MISSING,
+            ],
+        );
+
+        Ok(Self {
+            kernel: uname().release().to_string(),
+            features: handshake.features,
+            ioctls: handshake.ioctls,
+            anon_ioctls,
+            shmem_ioctls,
+        })
+    }
+
+    /// Whether the kernel offers `feature`, a bit from [`FEATURES`].
+    pub fn has(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    /// The [`required_features`] the kernel offers, which is what
+    /// [`create_uffd`](crate::create_uffd) asks for along with POISON and
+    /// MOVE, where offered.
+    pub fn available_features(&self) -> FeatureFlags {
+        required_features() & FeatureFlags::from_bits_truncate(self.features)
+    }
+
+    /// How memfd-backed regions are best registered on this kernel.
+    pub fn memfd_mode(&self) -> RegisterMode {
+        memfd_mode(self.available_features())
+    }
+}
+
+impl fmt::Display for Capabilities {
+    /// A table of every known feature and ioctl, and the strategy the
+    /// handler picks from them.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes = |supported| if supported { "yes" } else { "-" };
+
+        writeln!(f, "kernel {}", self.kernel)?;
+        writeln!(f)?;
+        writeln!(f, "feature                  supported")?;
+        for (name, bit) in FEATURES {
+            writeln!(f, "{:<24} {}", name, yes(self.has(*bit)))?;
+        }
+        let known = FEATURES.iter().fold(0, |known, (_, bit)| known | bit);
+        let unknown = self.features & !known;
+        for bit in (0..64).filter(|bit| unknown & (1 << bit) != 0) {
+            writeln!(f, "{:<24} yes", format!("unknown bit {}", bit))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "ioctl                    uffd   anonymous  shmem")?;
+        for (name, bit) in IOCTLS {
+            writeln!(
+                f,
+                "{:<24} {:<6} {:<10} {}",
+                name,
+                yes(self.ioctls & bit != 0),
+                yes(self.anon_ioctls & bit != 0),
+                yes(self.shmem_ioctls & bit != 0)
+            )?;
+        }
+
+        writeln!(f)?;
+        let missing = required_features() - self.available_features();
+        if !missing.is_empty() {
+            writeln!(f, "handler runs without {:?}", missing)?;
+        }
+        let strategy = if self.memfd_mode().contains(RegisterMode::MODE_MINOR) {
+            "MISSING and MINOR faults"
+        } else {
+            "MISSING faults only"
+        };
+        writeln!(f, "memfd regions: {}", strategy)
+    }
+}
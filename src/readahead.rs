@@ -0,0 +1,91 @@
+//! Readahead: spotting faults that walk a region at a fixed stride and
+//! resolving the blocks the walk reaches next before it faults on them.
+//!
+//! Everything here counts in resolver blocks, so a stream of faults a
+//! whole block apart reads as sequential whatever the block size.
+
+/// Blocks read ahead once a stride first repeats.
+const INITIAL_WINDOW: usize = 2;
+
+/// The access pattern seen on one mapped region.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Stream {
+    /// Block of the last fault.
+    last: Option<usize>,
+    /// Blocks between the last two faults that started or continued the
+    /// stream.
+    stride: usize,
+    /// Blocks read ahead of the last fault; zero while no stride repeats.
+    window: usize,
+    /// Where a stream that went through the whole window faults next.
+    next: usize,
+    /// Pages read ahead for the current window that no fault has shown to
+    /// be used yet.
+    pending: usize,
+}
+
+/// What a fault does to a [`Stream`].
+#[derive(Debug, Default)]
+pub(crate) struct Ahead {
+    /// Blocks to resolve ahead of the fault, nearest first.
+    pub(crate) blocks: Vec<usize>,
+    /// Pages of the previous window the stream went through.
+    pub(crate) hits: usize,
+    /// Pages of the previous window the stream left behind.
+    pub(crate) wasted: usize,
+}
+
+impl Stream {
+    /// Notes a fault on `block`, of `blocks` in the region, and returns
+    /// what to read ahead of it, at most `max_blocks` blocks.
+    ///
+    /// A fault where the stream was expected next doubles the window, as
+    /// does one inside the window, which the guest reached before it was
+    /// filled. Any other fault starts over, reading ahead again only once
+    /// the distance to the previous fault repeats. Pages of an abandoned
+    /// window count as wasted, though the guest may have touched some
+    /// before moving on.
+    pub(crate) fn advance(&mut self, block: usize, blocks: usize, max_blocks: usize) -> Ahead {
+        let mut ahead = Ahead::default();
+        if self.continues(block) {
+            ahead.hits = self.pending;
+            self.window = (self.window * 2).min(max_blocks);
+        } else {
+            ahead.wasted = self.pending;
+            let stride = match self.last {
+                Some(last) if block > last => block - last,
+                _ => 0,
+            };
+            self.window = if stride != 0 && stride == self.stride {
+                INITIAL_WINDOW.min(max_blocks)
+            } else {
+                0
+            };
+            self.stride = stride;
+        }
+
+        self.pending = 0;
+        self.last = Some(block);
+        self.next = block + self.stride * (self.window + 1);
+        ahead.blocks = (1..=self.window)
+            .map(|k| block + k * self.stride)
+            .take_while(|&b| b < blocks)
+            .collect();
+        ahead
+    }
+
+    fn continues(&self, block: usize) -> bool {
+        match self.last {
+            Some(last) if self.window > 0 => {
+                block > last && block <= self.next && (block - last) % self.stride == 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Counts `pages` read ahead for the window [`Stream::advance`] just
+    /// returned.
+    pub(crate) fn issued(&mut self, pages: usize) {
+        self.pending += pages;
+    }
+}
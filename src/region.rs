@@ -0,0 +1,371 @@
+use std::{
+    ffi::{c_void, CString},
+    fs::File,
+    ops::Range,
+    os::unix::prelude::{AsRawFd, FromRawFd, RawFd},
+};
+
+use nix::{
+    sys::{
+        memfd::{memfd_create, MemFdCreateFlag},
+        mman,
+    },
+    unistd::{sysconf, SysconfVar},
+};
+use userfaultfd::{RegisterMode, Uffd};
+
+use crate::{Error, Result};
+
+/// Size of a base page on this system.
+pub fn page_size() -> usize {
+    sysconf(SysconfVar::PAGE_SIZE)
+        .ok()
+        .flatten()
+        .map(|size| size as usize)
+        .unwrap_or(4096)
+}
+
+/// A memfd together with a shared mapping of it in this process.
+///
+/// Writes through this mapping go straight into the page cache and never
+/// fault through a userfaultfd registration, which is what makes a later
+/// access through a [`VmRegion`] a MINOR fault.
+pub struct MemfdRegion {
+    file: File,
+    addr: *mut c_void,
+    len: usize,
+}
+
+// The mapping is plain shared memory; synchronisation is up to the caller.
+unsafe impl Send for MemfdRegion {}
+unsafe impl Sync for MemfdRegion {}
+
+impl MemfdRegion {
+    /// Creates a memfd of `len` bytes and maps it shared.
+    pub fn new(len: usize) -> Result<Self> {
+        let name = CString::new("mapping").unwrap();
+        let memfd = memfd_create(&name, MemFdCreateFlag::empty())?;
+        let file = unsafe { File::from_raw_fd(memfd) };
+        file.set_len(len as u64)?;
+        let addr = map_shared(memfd, len)?;
+
+        Ok(Self { file, addr, len })
+    }
+
+    /// Maps all of an existing memfd, or any file, shared. It must be open
+    /// for reading and writing.
+    pub fn from_file(file: File) -> Result<Self> {
+        let len = file.metadata()?.len() as usize;
+        let addr = map_shared(file.as_raw_fd(), len)?;
+
+        Ok(Self { file, addr, len })
+    }
+
+    /// Maps the same memfd a second time, to be registered with a uffd.
+    pub fn map_vm(&self) -> Result<VmRegion> {
+        VmRegion::map(self.file.as_raw_fd(), self.len)
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies `data` into the mapping at `offset`.
+    pub fn write(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.len, "write past end of region");
+        unsafe { write_to_pointer(self.addr.add(offset), data) }
+    }
+
+    /// Reads `len` bytes from the mapping at `offset`.
+    pub fn read(&self, offset: usize, len: usize) -> Vec<u8> {
+        assert!(offset + len <= self.len, "read past end of region");
+        unsafe { read_from_pointer(self.addr.add(offset), len) }
+    }
+}
+
+impl Drop for MemfdRegion {
+    fn drop(&mut self) {
+        let _ = unsafe { mman::munmap(self.addr, self.len) };
+    }
+}
+
+/// The guest-visible mapping of a memfd, the range registered with a uffd.
+///
+/// Every access through this mapping may fault and block until the
+/// handler resolves it.
+pub struct VmRegion {
+    addr: *mut c_void,
+    len: usize,
+}
+
+unsafe impl Send for VmRegion {}
+unsafe impl Sync for VmRegion {}
+
+impl VmRegion {
+    /// Maps `len` bytes of `fd` shared.
+    pub fn map(fd: RawFd, len: usize) -> Result<Self> {
+        let addr = map_shared(fd, len)?;
+        Ok(Self { addr, len })
+    }
+
+    /// Maps `len` bytes of private anonymous memory, the way Firecracker
+    /// maps guest memory.
+    pub fn anonymous(len: usize) -> Result<Self> {
+        let addr = unsafe {
+            mman::mmap(
+                std::ptr::null_mut(),
+                len,
+                mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE,
+                mman::MapFlags::MAP_PRIVATE | mman::MapFlags::MAP_ANONYMOUS,
+                -1,
+                0,
+            )?
+        };
+        Ok(Self { addr, len })
+    }
+
+    /// Describes the mapping as a region table entry whose contents start
+    /// at `offset` in the backing file.
+    pub fn region(&self, offset: u64, mode: RegisterMode) -> Region {
+        Region {
+            start: self.addr as usize,
+            len: self.len,
+            offset,
+            mode,
+        }
+    }
+
+    /// Registers the whole mapping with `uffd`.
+    pub fn register(&self, uffd: &Uffd, mode: RegisterMode) -> Result<()> {
+        uffd.register_with_mode(self.addr, self.len, mode)?;
+        Ok(())
+    }
+
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Moves the mapping to a fresh address with `mremap`, taking the uffd
+    /// registration along with it.
+    pub fn remap(&mut self) -> Result<()> {
+        self.addr = move_fresh(self.addr, self.len)?;
+        Ok(())
+    }
+
+    /// Moves `len` bytes at `offset` to a fresh address with `mremap`,
+    /// leaving a hole behind, and returns the moved part. Its uffd
+    /// registration moves along with it.
+    pub fn remap_range(&mut self, offset: usize, len: usize) -> Result<VmRegion> {
+        assert!(offset + len <= self.len, "remap past end of region");
+        let addr = move_fresh(unsafe { self.addr.add(offset) }, len)?;
+        Ok(VmRegion { addr, len })
+    }
+
+    /// Unmaps the last `len` bytes of the mapping.
+    pub fn unmap_tail(&mut self, len: usize) -> Result<()> {
+        let len = len.min(self.len);
+        let keep = self.len - len;
+        unsafe { mman::munmap(self.addr.add(keep), len)? };
+        self.len = keep;
+        Ok(())
+    }
+
+    /// Copies `data` into the mapping at `offset`, faulting as needed.
+    pub fn write(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.len, "write past end of region");
+        unsafe { write_to_pointer(self.addr.add(offset), data) }
+    }
+
+    /// Reads `len` bytes from the mapping at `offset`, faulting as needed.
+    pub fn read(&self, offset: usize, len: usize) -> Vec<u8> {
+        assert!(offset + len <= self.len, "read past end of region");
+        unsafe { read_from_pointer(self.addr.add(offset), len) }
+    }
+}
+
+impl Drop for VmRegion {
+    fn drop(&mut self) {
+        let _ = unsafe { mman::munmap(self.addr, self.len) };
+    }
+}
+
+/// A registered guest address range and where its contents live in the
+/// backing file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub len: usize,
+    /// Offset of the region's first byte in the backing file.
+    pub offset: u64,
+    pub mode: RegisterMode,
+}
+
+impl Region {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.start + self.len
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        self.range().contains(&addr)
+    }
+
+    /// Backing file offset of `addr`, which must lie inside the region.
+    pub fn file_offset(&self, addr: usize) -> u64 {
+        debug_assert!(self.contains(addr));
+        self.offset + (addr - self.start) as u64
+    }
+}
+
+/// The guest memory slots a handler serves, e.g. below and above the PCI
+/// hole, kept sorted by address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegionTable {
+    regions: Vec<Region>,
+}
+
+impl RegionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `region`, which must be page-aligned and not overlap any
+    /// region already present.
+    pub fn insert(&mut self, region: Region) -> Result<()> {
+        if region.len == 0 {
+            return Err(Error::InvalidRegion(format!(
+                "empty region at {:#x}",
+                region.start
+            )));
+        }
+        let page_size = page_size();
+        if region.start % page_size != 0
+            || region.len % page_size != 0
+            || region.offset % page_size as u64 != 0
+        {
+            return Err(Error::InvalidRegion(format!(
+                "region {:#x}..{:#x} at offset {:#x} is not page-aligned",
+                region.start,
+                region.range().end,
+                region.offset
+            )));
+        }
+        let at = self.regions.partition_point(|r| r.start < region.start);
+        let overlaps_prev = at > 0 && self.regions[at - 1].range().end > region.start;
+        let overlaps_next = at < self.regions.len() && self.regions[at].start < region.range().end;
+        if overlaps_prev || overlaps_next {
+            return Err(Error::InvalidRegion(format!(
+                "region {:#x}..{:#x} overlaps another",
+                region.start,
+                region.range().end
+            )));
+        }
+        self.regions.insert(at, region);
+        Ok(())
+    }
+
+    /// The region holding `addr`.
+    pub fn lookup(&self, addr: usize) -> Result<&Region> {
+        let at = self.regions.partition_point(|r| r.start <= addr);
+        match at.checked_sub(1).map(|i| &self.regions[i]) {
+            Some(region) if region.contains(addr) => Ok(region),
+            _ => Err(Error::OutOfRegion { addr }),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Registers every region with `uffd` in its own mode.
+    pub fn register(&self, uffd: &Uffd) -> Result<()> {
+        for region in &self.regions {
+            uffd.register_with_mode(region.start as *mut c_void, region.len, region.mode)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes of backing file the regions reach into.
+    pub fn backing_len(&self) -> u64 {
+        self.regions
+            .iter()
+            .map(|r| r.offset + r.len as u64)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Moves the `len` bytes mapped at `addr` to an address nothing else uses.
+fn move_fresh(addr: *mut c_void, len: usize) -> Result<*mut c_void> {
+    let target = unsafe {
+        mman::mmap(
+            std::ptr::null_mut(),
+            len,
+            mman::ProtFlags::PROT_NONE,
+            mman::MapFlags::MAP_PRIVATE | mman::MapFlags::MAP_ANONYMOUS,
+            -1,
+            0,
+        )?
+    };
+    let addr = unsafe {
+        mman::mremap(
+            addr,
+            len,
+            len,
+            mman::MRemapFlags::MREMAP_MAYMOVE | mman::MRemapFlags::MREMAP_FIXED,
+            Some(target),
+        )?
+    };
+    Ok(addr)
+}
+
+fn map_shared(fd: RawFd, len: usize) -> Result<*mut c_void> {
+    let addr = unsafe {
+        mman::mmap(
+            std::ptr::null_mut(),
+            len,
+            mman::ProtFlags::PROT_READ | mman::ProtFlags::PROT_WRITE,
+            mman::MapFlags::MAP_SHARED,
+            fd,
+            0,
+        )?
+    };
+    Ok(addr)
+}
+
+unsafe fn write_to_pointer(ptr: *mut c_void, data: &[u8]) {
+    std::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+}
+
+unsafe fn read_from_pointer(ptr: *const c_void, size: usize) -> Vec<u8> {
+    let mut data = vec![0; size];
+    std::ptr::copy_nonoverlapping(ptr as *const u8, data.as_mut_ptr(), size);
+    data
+}
@@ -0,0 +1,48 @@
+use std::ops::Range;
+
+/// Decides which part of a region is filled in response to one fault.
+///
+/// A fault is aligned down to the start of its block of `block_pages`
+/// pages, counted from the region start, and the block is clamped to the
+/// region end so a resolve never runs past the registration.
+#[derive(Debug, Clone, Copy)]
+pub struct Resolver {
+    page_size: usize,
+    block_pages: usize,
+}
+
+impl Resolver {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            page_size,
+            block_pages: 1,
+        }
+    }
+
+    /// Resolves `block_pages` pages per fault instead of one.
+    pub fn block_pages(mut self, block_pages: usize) -> Self {
+        assert!(block_pages > 0, "block must hold at least one page");
+        self.block_pages = block_pages;
+        self
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Bytes resolved per fault.
+    pub fn block_size(&self) -> usize {
+        self.block_pages * self.page_size
+    }
+
+    /// Page-aligned address range to fill for a fault at `addr` inside
+    /// `region`.
+    pub fn block(&self, addr: usize, region: Range<usize>) -> Range<usize> {
+        debug_assert!(region.contains(&addr));
+        let block_size = self.block_size();
+        let offset = addr - region.start;
+        let start = region.start + offset / block_size * block_size;
+        let end = start.saturating_add(block_size).min(region.end);
+        start..end
+    }
+}
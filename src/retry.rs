@@ -0,0 +1,144 @@
+use std::{fmt, time::Duration};
+
+use nix::libc;
+
+use crate::{error::uffd_errno, Error};
+
+/// Why a uffd ioctl failed, grouped by how the handler can react.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// EAGAIN: the mapping is changing under us (remap, remove, fork in
+    /// progress); the same call may succeed shortly.
+    MappingChanging,
+    /// EEXIST: the page is already mapped or already in the page cache.
+    AlreadyMapped,
+    /// ENOENT: UFFDIO_CONTINUE found no page in the page cache.
+    NotInPageCache,
+    /// EFAULT: the source buffer or target range is not accessible.
+    BadAddress,
+    /// ESRCH: the process owning the registered range has exited.
+    TargetExited,
+    /// Any other errno.
+    Other(i32),
+}
+
+impl ErrorClass {
+    pub fn from_errno(errno: i32) -> Self {
+        match errno {
+            libc::EAGAIN => ErrorClass::MappingChanging,
+            libc::EEXIST => ErrorClass::AlreadyMapped,
+            libc::ENOENT => ErrorClass::NotInPageCache,
+            libc::EFAULT => ErrorClass::BadAddress,
+            libc::ESRCH => ErrorClass::TargetExited,
+            errno => ErrorClass::Other(errno),
+        }
+    }
+
+    /// Classifies a failed uffd call. Must be called right after it.
+    pub fn of(err: &Error) -> Self {
+        uffd_errno(err).map_or(ErrorClass::Other(0), ErrorClass::from_errno)
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorClass::MappingChanging => write!(f, "mapping changing (EAGAIN)"),
+            ErrorClass::AlreadyMapped => write!(f, "already mapped (EEXIST)"),
+            ErrorClass::NotInPageCache => write!(f, "not in page cache (ENOENT)"),
+            ErrorClass::BadAddress => write!(f, "bad address (EFAULT)"),
+            ErrorClass::TargetExited => write!(f, "target exited (ESRCH)"),
+            ErrorClass::Other(errno) => write!(f, "errno {}", errno),
+        }
+    }
+}
+
+/// What to do with a page once its retries are used up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    /// Leave the page as it is and move on.
+    Skip,
+    /// Fill the page with UFFDIO_ZEROPAGE.
+    Zeropage,
+    /// Fill the page with UFFDIO_COPY.
+    Copy,
+    /// Stop and report [`crate::Error::Resolve`].
+    GiveUp,
+}
+
+/// How often to retry one class of failure before falling back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the second attempt; doubled for each one after.
+    pub backoff: Duration,
+    pub fallback: Fallback,
+}
+
+impl Retry {
+    /// Falls back straight away without retrying.
+    pub const fn once(fallback: Fallback) -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::ZERO,
+            fallback,
+        }
+    }
+
+    /// Delay to wait after failed attempt number `attempt` (1-based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff * 2u32.saturating_pow(attempt.saturating_sub(1).min(16))
+    }
+}
+
+/// Per-class retry rules for resolving a fault.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    rules: Vec<(ErrorClass, Retry)>,
+    default: Retry,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                (
+                    ErrorClass::MappingChanging,
+                    Retry {
+                        max_attempts: 8,
+                        backoff: Duration::from_micros(50),
+                        fallback: Fallback::GiveUp,
+                    },
+                ),
+                (ErrorClass::AlreadyMapped, Retry::once(Fallback::Skip)),
+                (ErrorClass::NotInPageCache, Retry::once(Fallback::Zeropage)),
+                (ErrorClass::BadAddress, Retry::once(Fallback::GiveUp)),
+                (ErrorClass::TargetExited, Retry::once(Fallback::GiveUp)),
+            ],
+            default: Retry::once(Fallback::GiveUp),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Overrides the rule for `class`.
+    pub fn with(mut self, class: ErrorClass, retry: Retry) -> Self {
+        self.rules.retain(|(c, _)| *c != class);
+        self.rules.push((class, retry));
+        self
+    }
+
+    /// Rule for any class without one of its own.
+    pub fn with_default(mut self, retry: Retry) -> Self {
+        self.default = retry;
+        self
+    }
+
+    pub fn rule(&self, class: ErrorClass) -> Retry {
+        self.rules
+            .iter()
+            .find(|(c, _)| *c == class)
+            .map_or(self.default, |(_, retry)| *retry)
+    }
+}
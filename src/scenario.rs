@@ -0,0 +1,352 @@
+//! Named reproductions of uffd behaviour, run against the library handler.
+//!
+//! Each scenario sets up its own memfd, registration and handler thread,
+//! touches memory in a specific pattern and checks what it reads back. A
+//! scenario whose faulting thread never returns is reported as a hang,
+//! which is how the `uffd_continue` bug shows up on affected kernels.
+
+use std::{
+    any::Any,
+    fmt,
+    os::unix::prelude::{AsRawFd, FromRawFd},
+    sync::mpsc,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use nix::{
+    sys::{
+        mman::{madvise, MmapAdvise},
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{dup, fork, ForkResult},
+};
+use userfaultfd::{RegisterMode, Uffd};
+
+use crate::{
+    create_uffd, memfd_register_mode, page_size, FaultHandler, MemfdRegion, Result, VmRegion,
+};
+
+type ScenarioResult = std::result::Result<(), Stop>;
+
+/// Why a scenario stopped short of passing.
+enum Stop {
+    Failed(String),
+    /// The running kernel lacks something the scenario needs.
+    Unsupported(String),
+}
+
+impl From<String> for Stop {
+    fn from(reason: String) -> Self {
+        Stop::Failed(reason)
+    }
+}
+
+/// A registered reproduction.
+pub struct Scenario {
+    pub name: &'static str,
+    pub description: &'static str,
+    run: fn() -> ScenarioResult,
+}
+
+/// How a scenario ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The scenario did not finish within the timeout.
+    Hang(Duration),
+    /// The running kernel cannot run the scenario, so it was skipped.
+    Unsupported(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(reason) => write!(f, "fail: {}", reason),
+            Outcome::Hang(timeout) => write!(f, "hang (no progress after {:?})", timeout),
+            Outcome::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+        }
+    }
+}
+
+static SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "minor-after-write",
+        description: "write through the memfd mapping, then read through the registered mapping",
+        run: minor_after_write,
+    },
+    Scenario {
+        name: "missing-on-fresh-page",
+        description: "read a page past the first one that was never written anywhere",
+        run: missing_on_fresh_page,
+    },
+    Scenario {
+        name: "write-protect-then-write",
+        description: "write-protect a mapped page, then write to it through the registered mapping",
+        run: write_protect_then_write,
+    },
+    Scenario {
+        name: "remove-during-fault",
+        description: "MADV_REMOVE the registered range while another thread keeps faulting it",
+        run: remove_during_fault,
+    },
+    Scenario {
+        name: "fork-with-registered-range",
+        description: "fork and touch the registered range from the child",
+        run: fork_with_registered_range,
+    },
+    Scenario {
+        name: "remap-registered-range",
+        description: "mremap the registered range elsewhere, then fault it at the new address",
+        run: remap_registered_range,
+    },
+    Scenario {
+        name: "remap-part-of-range",
+        description: "mremap the middle of the registered range, then fault each part left",
+        run: remap_part_of_range,
+    },
+    Scenario {
+        name: "unmap-part-of-range",
+        description: "munmap the tail of the registered range, then fault what is left",
+        run: unmap_part_of_range,
+    },
+];
+
+/// All known scenarios, in listing order.
+pub fn scenarios() -> &'static [Scenario] {
+    SCENARIOS
+}
+
+/// Looks up a scenario by name.
+pub fn find(name: &str) -> Option<&'static Scenario> {
+    SCENARIOS.iter().find(|scenario| scenario.name == name)
+}
+
+impl Scenario {
+    /// Runs the scenario on its own thread and waits at most `timeout`.
+    ///
+    /// A hung scenario leaves its thread blocked in the fault; callers are
+    /// expected to exit the process rather than join it.
+    pub fn run(&self, timeout: Duration) -> Outcome {
+        let (tx, rx) = mpsc::channel();
+        let run = self.run;
+        std::thread::spawn(move || {
+            let result = std::panic::catch_unwind(run);
+            let _ = tx.send(result);
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok(Ok(Ok(()))) => Outcome::Pass,
+            Ok(Ok(Err(Stop::Failed(reason)))) => Outcome::Fail(reason),
+            Ok(Ok(Err(Stop::Unsupported(reason)))) => Outcome::Unsupported(reason),
+            Ok(Err(panic)) => Outcome::Fail(panic_message(panic)),
+            Err(_) => Outcome::Hang(timeout),
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        format!("panicked: {}", msg)
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        format!("panicked: {}", msg)
+    } else {
+        "panicked".to_string()
+    }
+}
+
+/// A memfd, its registered mapping and a running handler.
+struct Fixture {
+    memory: MemfdRegion,
+    vm: VmRegion,
+    /// A second descriptor for the same uffd, for ioctls issued by the
+    /// scenario itself while the handler owns the original.
+    control: Uffd,
+    _handler: JoinHandle<Result<()>>,
+}
+
+impl Fixture {
+    /// Registers MISSING faults, MINOR ones where the kernel has them, and
+    /// `extra`.
+    fn new(pages: usize, extra: RegisterMode) -> std::result::Result<Self, String> {
+        let mode = memfd_register_mode().map_err(|e| e.to_string())? | extra;
+        let memory = MemfdRegion::new(pages * page_size()).map_err(|e| e.to_string())?;
+        let vm = memory.map_vm().map_err(|e| e.to_string())?;
+        let uffd = create_uffd().map_err(|e| e.to_string())?;
+        vm.register(&uffd, mode).map_err(|e| e.to_string())?;
+
+        let control_fd = dup(uffd.as_raw_fd()).map_err(|e| e.to_string())?;
+        let control = unsafe { Uffd::from_raw_fd(control_fd) };
+        let handler = FaultHandler::new(uffd, &vm).spawn();
+
+        Ok(Self {
+            memory,
+            vm,
+            control,
+            _handler: handler,
+        })
+    }
+}
+
+fn expect_eq(what: &str, actual: Vec<u8>, expected: &[u8]) -> ScenarioResult {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("{}: read {:?}, expected {:?}", what, actual, expected).into())
+    }
+}
+
+/// Stops a scenario that is only meaningful with MINOR faults on a kernel
+/// without them.
+fn require_minor() -> ScenarioResult {
+    let mode = memfd_register_mode().map_err(|e| e.to_string())?;
+    if mode.contains(RegisterMode::MODE_MINOR) {
+        Ok(())
+    } else {
+        Err(Stop::Unsupported(
+            "the kernel has no MINOR faults on shmem".to_string(),
+        ))
+    }
+}
+
+fn minor_after_write() -> ScenarioResult {
+    require_minor()?;
+    let fx = Fixture::new(4, RegisterMode::empty())?;
+
+    fx.memory.write(0, &[1, 2, 3]);
+
+    expect_eq("registered mapping", fx.vm.read(0, 3), &[1, 2, 3])
+}
+
+fn missing_on_fresh_page() -> ScenarioResult {
+    let fx = Fixture::new(4, RegisterMode::empty())?;
+    let offset = 2 * page_size();
+
+    expect_eq("fresh page", fx.vm.read(offset, 8), &[0; 8])
+}
+
+fn write_protect_then_write() -> ScenarioResult {
+    let fx = Fixture::new(4, RegisterMode::WRITE_PROTECT)?;
+
+    fx.memory.write(0, &[1, 2, 3]);
+    expect_eq("before protect", fx.vm.read(0, 3), &[1, 2, 3])?;
+
+    fx.control
+        .write_protect(fx.vm.as_ptr(), page_size())
+        .map_err(|e| format!("write_protect: {}", e))?;
+    fx.vm.write(0, &[4, 5, 6]);
+
+    expect_eq("memfd after write", fx.memory.read(0, 3), &[4, 5, 6])
+}
+
+fn remove_during_fault() -> ScenarioResult {
+    let pages = 16;
+    let fx = Fixture::new(pages, RegisterMode::empty())?;
+    let len = pages * page_size();
+
+    for page in 0..pages {
+        fx.memory.write(page * page_size(), &[1]);
+    }
+
+    std::thread::scope(|s| {
+        let reader = s.spawn(|| {
+            for _ in 0..64 {
+                for page in 0..pages {
+                    fx.vm.read(page * page_size(), 1);
+                }
+            }
+        });
+
+        for _ in 0..16 {
+            unsafe { madvise(fx.vm.as_ptr(), len, MmapAdvise::MADV_REMOVE) }
+                .map_err(|e| format!("madvise: {}", e))?;
+        }
+
+        reader.join().map_err(panic_message)
+    })?;
+
+    // Everything was punched out of the memfd, so faults now read zeroes.
+    unsafe { madvise(fx.vm.as_ptr(), len, MmapAdvise::MADV_REMOVE) }
+        .map_err(|e| format!("madvise: {}", e))?;
+    expect_eq("after remove", fx.vm.read(0, 1), &[0])
+}
+
+fn fork_with_registered_range() -> ScenarioResult {
+    let fx = Fixture::new(4, RegisterMode::empty())?;
+
+    fx.memory.write(0, &[7]);
+    let first = fx.vm.as_ptr() as *const u8;
+    let second = unsafe { first.add(page_size()) };
+
+    match unsafe { fork() }.map_err(|e| format!("fork: {}", e))? {
+        ForkResult::Child => {
+            // The child may not allocate, so it reads straight from the
+            // mapping rather than through `VmRegion::read`.
+            let ok = unsafe { first.read_volatile() == 7 && second.read_volatile() == 0 };
+            unsafe { nix::libc::_exit(if ok { 0 } else { 1 }) }
+        }
+        ForkResult::Parent { child } => match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+            Ok(status) => Err(format!("child ended with {:?}", status).into()),
+            Err(err) => Err(format!("waitpid: {}", err).into()),
+        },
+    }
+}
+
+fn remap_registered_range() -> ScenarioResult {
+    let mut fx = Fixture::new(4, RegisterMode::empty())?;
+
+    fx.memory.write(0, &[1]);
+    fx.memory.write(page_size(), &[2]);
+    expect_eq("before remap", fx.vm.read(0, 1), &[1])?;
+
+    fx.vm.remap().map_err(|e| format!("mremap: {}", e))?;
+
+    expect_eq("mapped page after remap", fx.vm.read(0, 1), &[1])?;
+    expect_eq("minor fault after remap", fx.vm.read(page_size(), 1), &[2])?;
+    expect_eq(
+        "missing fault after remap",
+        fx.vm.read(3 * page_size(), 1),
+        &[0],
+    )
+}
+
+fn remap_part_of_range() -> ScenarioResult {
+    let mut fx = Fixture::new(4, RegisterMode::empty())?;
+
+    for page in 0..4 {
+        fx.memory.write(page * page_size(), &[page as u8 + 1]);
+    }
+    expect_eq("before remap", fx.vm.read(page_size(), 1), &[2])?;
+
+    // Pages 1 and 2 move away, splitting the region in three.
+    let moved = fx
+        .vm
+        .remap_range(page_size(), 2 * page_size())
+        .map_err(|e| format!("mremap: {}", e))?;
+
+    expect_eq("mapped page after remap", moved.read(0, 1), &[2])?;
+    expect_eq("fault in moved part", moved.read(page_size(), 1), &[3])?;
+    expect_eq("fault before moved part", fx.vm.read(0, 1), &[1])?;
+    expect_eq(
+        "fault after moved part",
+        fx.vm.read(3 * page_size(), 1),
+        &[4],
+    )
+}
+
+fn unmap_part_of_range() -> ScenarioResult {
+    let mut fx = Fixture::new(4, RegisterMode::empty())?;
+
+    fx.memory.write(0, &[1]);
+    expect_eq("before unmap", fx.vm.read(0, 1), &[1])?;
+
+    fx.vm
+        .unmap_tail(2 * page_size())
+        .map_err(|e| format!("munmap: {}", e))?;
+
+    expect_eq("kept page", fx.vm.read(0, 1), &[1])?;
+    expect_eq("fault after unmap", fx.vm.read(page_size(), 1), &[0])
+}
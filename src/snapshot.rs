@@ -0,0 +1,484 @@
+//! Layered memory snapshots: a base image plus diffs of changed pages.
+//!
+//! A layer file is laid out as:
+//!
+//! ```text
+//! magic        8 bytes  "UFFDSNAP"
+//! version      u32
+//! kind         u32      0 = base, 1 = diff
+//! page_size    u32
+//! region_count u32
+//! total_pages  u64
+//! regions      region_count * (guest_addr u64, len u64)
+//! presence     one bit per page, as little-endian u64 words
+//! zero         one bit per page, like presence: present pages that are
+//!              all zeroes
+//! padding      up to the next page_size boundary
+//! data         every present page that is not zero, in index order
+//! checksums    u32 CRC-32C of each page in data, in the same order
+//! ```
+//!
+//! All integers are little-endian. Pages are numbered across the regions
+//! in table order. A diff only holds the pages changed since the layer
+//! below it; reading a page goes to the newest layer that has it.
+//!
+//! Older layers are still read: version 2 ones have no checksums, and
+//! version 1 ones no zero bitmap either, storing every present page.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::fs::FileExt,
+    path::Path,
+    thread::JoinHandle,
+};
+
+use crate::{
+    checksum, create_uffd, is_zero, memfd_register_mode, prefetch::TracePrefetcher, trace::Trace,
+    Error, FaultHandler, MemfdRegion, Page, PageBitmap, PageSource, Result, VmRegion,
+    ZeroPageStats,
+};
+
+const MAGIC: &[u8; 8] = b"UFFDSNAP";
+const VERSION: u32 = 3;
+
+/// Whether a layer holds every page or only changed ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    Base,
+    Diff,
+}
+
+/// A guest memory range covered by a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotRegion {
+    pub guest_addr: u64,
+    pub len: u64,
+}
+
+/// The fixed part of a layer file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub kind: LayerKind,
+    pub page_size: usize,
+    pub regions: Vec<SnapshotRegion>,
+    pub total_pages: usize,
+}
+
+impl Header {
+    /// A header covering `len` bytes of flat memory as one region at guest
+    /// address 0.
+    pub fn flat(kind: LayerKind, len: usize, page_size: usize) -> Self {
+        Self {
+            kind,
+            page_size,
+            regions: vec![SnapshotRegion {
+                guest_addr: 0,
+                len: len as u64,
+            }],
+            total_pages: len / page_size,
+        }
+    }
+
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        let kind: u32 = match self.kind {
+            LayerKind::Base => 0,
+            LayerKind::Diff => 1,
+        };
+        out.write_all(&kind.to_le_bytes())?;
+        out.write_all(&(self.page_size as u32).to_le_bytes())?;
+        out.write_all(&(self.regions.len() as u32).to_le_bytes())?;
+        out.write_all(&(self.total_pages as u64).to_le_bytes())?;
+        for region in &self.regions {
+            out.write_all(&region.guest_addr.to_le_bytes())?;
+            out.write_all(&region.len.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads a header and the version of the layer it starts.
+    fn read_from(input: &mut impl Read) -> io::Result<(Self, u32)> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot layer"));
+        }
+        let version = read_u32(input)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid("unsupported snapshot version"));
+        }
+        let kind = match read_u32(input)? {
+            0 => LayerKind::Base,
+            1 => LayerKind::Diff,
+            _ => return Err(invalid("unknown layer kind")),
+        };
+        let page_size = read_u32(input)? as usize;
+        if !page_size.is_power_of_two() {
+            return Err(invalid("page size is not a power of two"));
+        }
+        let region_count = read_u32(input)?;
+        let total_pages = read_u64(input)? as usize;
+        let regions = (0..region_count)
+            .map(|_| {
+                Ok(SnapshotRegion {
+                    guest_addr: read_u64(input)?,
+                    len: read_u64(input)?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        let header = Self {
+            kind,
+            page_size,
+            regions,
+            total_pages,
+        };
+        Ok((header, version))
+    }
+
+    /// Bytes taken by the header and region table.
+    fn encoded_len(&self) -> usize {
+        8 + 4 * 4 + 8 + self.regions.len() * 16
+    }
+}
+
+/// What [`write_layer`] stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerStats {
+    /// Pages stored with their contents.
+    pub data_pages: usize,
+    /// Pages stored as a zero bit only.
+    pub zero_pages: usize,
+    pub page_size: usize,
+}
+
+impl LayerStats {
+    /// Bytes the zero pages would have taken up.
+    pub fn bytes_saved(&self) -> u64 {
+        (self.zero_pages * self.page_size) as u64
+    }
+}
+
+/// Writes a layer holding the `present` pages of `memory`, a flat image
+/// such as a memfd, to `out`. Pages that are all zeroes are only marked in
+/// the zero bitmap; the rest are stored with their checksum.
+pub fn write_layer(
+    out: &mut impl Write,
+    header: &Header,
+    memory: &File,
+    present: &PageBitmap,
+) -> io::Result<LayerStats> {
+    assert_eq!(present.len(), header.total_pages);
+
+    let mut buf = vec![0; header.page_size];
+    let mut zero = PageBitmap::new(header.total_pages);
+    for page in present.iter_ones() {
+        memory.read_exact_at(&mut buf, (page * header.page_size) as u64)?;
+        if is_zero(&buf) {
+            zero.set(page);
+        }
+    }
+
+    header.write_to(out)?;
+    let presence = present.to_bytes();
+    out.write_all(&presence)?;
+    out.write_all(&zero.to_bytes())?;
+    let written = header.encoded_len() + 2 * presence.len();
+    out.write_all(&vec![0; padding(written, header.page_size)])?;
+
+    let mut checksums = Vec::new();
+    for page in present.iter_ones().filter(|&page| !zero.get(page)) {
+        memory.read_exact_at(&mut buf, (page * header.page_size) as u64)?;
+        out.write_all(&buf)?;
+        checksums.extend_from_slice(&checksum(&buf).to_le_bytes());
+    }
+    out.write_all(&checksums)?;
+
+    let zero_pages = zero.count_ones();
+    Ok(LayerStats {
+        data_pages: present.count_ones() - zero_pages,
+        zero_pages,
+        page_size: header.page_size,
+    })
+}
+
+/// Writes every page of `memory` as a base layer.
+pub fn write_base(out: &mut impl Write, memory: &MemfdRegion) -> io::Result<LayerStats> {
+    let header = Header::flat(LayerKind::Base, memory.len(), crate::page_size());
+    let mut present = PageBitmap::new(header.total_pages);
+    present.set_range(0..header.total_pages);
+    write_layer(out, &header, memory.file(), &present)
+}
+
+/// Writes the `dirty` pages of `memory` as a diff layer, typically from
+/// [`crate::DirtyTracker::take`].
+pub fn write_diff(
+    out: &mut impl Write,
+    memory: &MemfdRegion,
+    dirty: &PageBitmap,
+) -> io::Result<LayerStats> {
+    let header = Header::flat(LayerKind::Diff, memory.len(), crate::page_size());
+    write_layer(out, &header, memory.file(), dirty)
+}
+
+/// One layer file opened for reading.
+pub struct Layer {
+    header: Header,
+    present: PageBitmap,
+    /// Present pages that are all zeroes and have no data.
+    zero: PageBitmap,
+    /// Present pages with data, in the order it is laid out.
+    stored: PageBitmap,
+    ranks: Vec<u64>,
+    data_offset: u64,
+    /// Checksum of each stored page, in data order; empty before
+    /// version 3.
+    checksums: Vec<u32>,
+    file: File,
+}
+
+impl Layer {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_file(File::open(path)?)
+    }
+
+    pub fn from_file(mut file: File) -> io::Result<Self> {
+        let (header, version) = Header::read_from(&mut file)?;
+        let file_len = file.metadata()?.len();
+        let truncated = || invalid("layer is shorter than its header says");
+
+        // Check the sizes the header implies against the file before
+        // allocating anything for them.
+        let bitmaps = if version >= 2 { 2 } else { 1 };
+        let bitmap_len = (header.total_pages as u64).div_ceil(64) * 8;
+        let written = bitmap_len
+            .checked_mul(bitmaps)
+            .and_then(|len| len.checked_add(header.encoded_len() as u64))
+            .filter(|&len| len <= file_len)
+            .ok_or_else(truncated)?;
+
+        let mut read_bitmap = |what| {
+            let mut bytes = vec![0; bitmap_len as usize];
+            file.read_exact(&mut bytes)?;
+            PageBitmap::from_bytes(header.total_pages, &bytes).ok_or_else(|| invalid(what))
+        };
+        let present = read_bitmap("bad presence bitmap")?;
+        let zero = if version >= 2 {
+            read_bitmap("bad zero bitmap")?
+        } else {
+            PageBitmap::new(header.total_pages)
+        };
+
+        let mut stored = present.clone();
+        for page in zero.iter_ones() {
+            stored.clear(page);
+        }
+
+        let written = written as usize;
+        let data_offset = (written + padding(written, header.page_size)) as u64;
+        let checksum_len = if version >= 3 { 4 } else { 0 };
+        (stored.count_ones() as u64)
+            .checked_mul(header.page_size as u64 + checksum_len)
+            .and_then(|len| len.checked_add(data_offset))
+            .filter(|&end| end <= file_len)
+            .ok_or_else(truncated)?;
+
+        let checksums = if version >= 3 {
+            let data_len = (stored.count_ones() * header.page_size) as u64;
+            let mut bytes = vec![0; stored.count_ones() * 4];
+            file.read_exact_at(&mut bytes, data_offset + data_len)?;
+            bytes
+                .chunks_exact(4)
+                .map(|sum| u32::from_le_bytes(sum.try_into().unwrap()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            ranks: stored.word_ranks(),
+            stored,
+            header,
+            present,
+            zero,
+            data_offset,
+            checksums,
+            file,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Pages stored in this layer.
+    pub fn present(&self) -> &PageBitmap {
+        &self.present
+    }
+}
+
+impl PageSource for Layer {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        if !self.present.get(index) {
+            return Ok(Page::Absent);
+        }
+        if self.zero.get(index) {
+            return Ok(Page::Zero);
+        }
+        let slot = self.stored.rank(&self.ranks, index);
+        let offset = self.data_offset + slot * self.header.page_size as u64;
+        self.file.read_exact_at(buf, offset)?;
+        Ok(Page::Data)
+    }
+
+    fn checksum(&self, index: usize) -> Option<u32> {
+        if !self.stored.get(index) {
+            return None;
+        }
+        let slot = self.stored.rank(&self.ranks, index);
+        self.checksums.get(slot as usize).copied()
+    }
+}
+
+/// A base layer with diffs on top, served as one [`PageSource`].
+pub struct SnapshotChain {
+    /// Base first, newest diff last.
+    layers: Vec<Layer>,
+}
+
+impl SnapshotChain {
+    /// Opens `paths`, base first. Every layer must share the base's page
+    /// size and region table.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> io::Result<Self> {
+        let layers = paths.iter().map(Layer::open).collect::<io::Result<_>>()?;
+        Self::new(layers)
+    }
+
+    pub fn new(layers: Vec<Layer>) -> io::Result<Self> {
+        let base = layers.first().ok_or_else(|| invalid("empty chain"))?;
+        if base.header.kind != LayerKind::Base {
+            return Err(invalid("chain must start with a base layer"));
+        }
+        for layer in &layers[1..] {
+            if layer.header.kind != LayerKind::Diff
+                || layer.header.page_size != base.header.page_size
+                || layer.header.regions != base.header.regions
+            {
+                return Err(invalid("diff does not match its base"));
+            }
+        }
+        Ok(Self { layers })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.layers[0].header
+    }
+
+    /// Bytes of memory the chain describes.
+    pub fn memory_len(&self) -> usize {
+        self.header().total_pages * self.header().page_size
+    }
+
+    /// Pages of `memory` that differ from what the chain holds, for
+    /// writing a diff without a dirty tracker.
+    pub fn changed_pages(&self, memory: &File) -> io::Result<PageBitmap> {
+        let header = self.header();
+        let mut changed = PageBitmap::new(header.total_pages);
+        let mut ours = vec![0; header.page_size];
+        let mut theirs = vec![0; header.page_size];
+
+        for page in 0..header.total_pages {
+            memory.read_exact_at(&mut ours, (page * header.page_size) as u64)?;
+            match self.read_page(page, &mut theirs)? {
+                Page::Data if ours == theirs => {}
+                Page::Zero if ours.iter().all(|&b| b == 0) => {}
+                _ => changed.set(page),
+            }
+        }
+        Ok(changed)
+    }
+}
+
+impl PageSource for SnapshotChain {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        for layer in self.layers.iter().rev() {
+            match layer.read_page(index, buf)? {
+                Page::Absent => continue,
+                page => return Ok(page),
+            }
+        }
+        Ok(Page::Absent)
+    }
+
+    fn checksum(&self, index: usize) -> Option<u32> {
+        let layer = self.layers.iter().rev().find(|l| l.present.get(index))?;
+        layer.checksum(index)
+    }
+}
+
+/// Memory restored from a snapshot chain, filled on demand by a handler.
+pub struct Restored {
+    pub memory: MemfdRegion,
+    pub vm: VmRegion,
+    pub handler: JoinHandle<Result<()>>,
+    pub prefetcher: Option<TracePrefetcher>,
+    pub zero_pages: ZeroPageStats,
+}
+
+/// Sets up fresh memory whose pages are served from `chain` as they are
+/// first touched.
+pub fn restore(chain: SnapshotChain) -> Result<Restored> {
+    restore_with(chain, None)
+}
+
+/// Like [`restore`], but also maps the pages of `working_set`, a trace of
+/// an earlier run, in the background from the start.
+pub fn restore_with(chain: SnapshotChain, working_set: Option<&Trace>) -> Result<Restored> {
+    if chain.header().page_size != crate::page_size() {
+        return Err(Error::Io(invalid("snapshot page size differs from ours")));
+    }
+
+    let memory = MemfdRegion::new(chain.memory_len())?;
+    let vm = memory.map_vm()?;
+    let uffd = create_uffd()?;
+    vm.register(&uffd, memfd_register_mode()?)?;
+
+    let memfd = memory.file().try_clone()?;
+    let handler = FaultHandler::new(uffd, &vm).page_source(chain, Some(memfd));
+    let prefetcher = match working_set {
+        Some(trace) => Some(TracePrefetcher::spawn(&handler, trace)?),
+        None => None,
+    };
+    let zero_pages = handler.zero_page_stats();
+    let handler = handler.spawn();
+
+    Ok(Restored {
+        memory,
+        vm,
+        handler,
+        prefetcher,
+        zero_pages,
+    })
+}
+
+fn padding(len: usize, align: usize) -> usize {
+    (align - len % align) % align
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,137 @@
+use std::{collections::HashMap, fs::File, io, os::unix::fs::FileExt, path::Path};
+
+/// What [`PageSource::read_page`] found for a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    /// The buffer now holds the page contents.
+    Data,
+    /// The page is all zeroes; the buffer was left untouched.
+    Zero,
+    /// The source has nothing for this page; the buffer was left untouched.
+    Absent,
+}
+
+/// Whether `page` holds nothing but zeroes.
+///
+/// Words are ORed together a cache line at a time, which the compiler
+/// vectorizes, stopping at the first line with a bit set.
+pub fn is_zero(page: &[u8]) -> bool {
+    let mut lines = page.chunks_exact(64);
+    let tail = lines.remainder();
+    lines.all(|line| {
+        line.chunks_exact(8).fold(0, |acc, word| {
+            acc | u64::from_ne_bytes(word.try_into().unwrap())
+        }) == 0
+    }) && tail.iter().all(|&b| b == 0)
+}
+
+/// Where the handler gets page contents from.
+///
+/// Page indices are offsets into the memfd backing the registered
+/// regions, divided by the page size, so they match across regions that
+/// map the same file at different offsets.
+pub trait PageSource: Send + Sync {
+    /// Reads page `index` into `buf`, which is exactly one page long.
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page>;
+
+    /// The [`checksum`](crate::checksum) page `index` was stored with, for
+    /// sources that keep one. Only pages read as [`Page::Data`] have one.
+    fn checksum(&self, _index: usize) -> Option<u32> {
+        None
+    }
+}
+
+impl<S: PageSource + ?Sized> PageSource for Box<S> {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        (**self).read_page(index, buf)
+    }
+
+    fn checksum(&self, index: usize) -> Option<u32> {
+        (**self).checksum(index)
+    }
+}
+
+impl<S: PageSource + ?Sized> PageSource for std::sync::Arc<S> {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        (**self).read_page(index, buf)
+    }
+
+    fn checksum(&self, index: usize) -> Option<u32> {
+        (**self).checksum(index)
+    }
+}
+
+/// Every page is zero.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZeroSource;
+
+impl PageSource for ZeroSource {
+    fn read_page(&self, _index: usize, _buf: &mut [u8]) -> io::Result<Page> {
+        Ok(Page::Zero)
+    }
+}
+
+/// A flat memory image: page N lives at byte offset N * page size.
+///
+/// Pages past the end of the file are absent.
+#[derive(Debug)]
+pub struct SnapshotFile {
+    file: File,
+    len: u64,
+}
+
+impl SnapshotFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_file(File::open(path)?)
+    }
+
+    pub fn from_file(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+}
+
+impl PageSource for SnapshotFile {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        let offset = (index * buf.len()) as u64;
+        if offset >= self.len {
+            return Ok(Page::Absent);
+        }
+
+        let available = ((self.len - offset) as usize).min(buf.len());
+        self.file.read_exact_at(&mut buf[..available], offset)?;
+        buf[available..].fill(0);
+        Ok(Page::Data)
+    }
+}
+
+/// Pages held in memory, keyed by index.
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    pages: HashMap<usize, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `data` as page `index`; shorter data is zero-padded on read.
+    pub fn insert(&mut self, index: usize, data: Vec<u8>) {
+        self.pages.insert(index, data);
+    }
+}
+
+impl PageSource for MemorySource {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        match self.pages.get(&index) {
+            Some(data) => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                buf[len..].fill(0);
+                Ok(Page::Data)
+            }
+            None => Ok(Page::Absent),
+        }
+    }
+}
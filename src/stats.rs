@@ -0,0 +1,201 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// How many events each read of a uffd returned, and how many faults fell
+/// outside every region, shared between a handler and whoever watches it.
+#[derive(Debug, Clone, Default)]
+pub struct EventStats {
+    inner: Arc<EventCounters>,
+}
+
+#[derive(Debug, Default)]
+struct EventCounters {
+    reads: AtomicU64,
+    events: AtomicU64,
+    largest: AtomicU64,
+    stray: AtomicU64,
+}
+
+impl EventStats {
+    /// Counts one read that returned `events` events.
+    pub(crate) fn record(&self, events: usize) {
+        let inner = &self.inner;
+        inner.reads.fetch_add(1, Ordering::Relaxed);
+        inner.events.fetch_add(events as u64, Ordering::Relaxed);
+        inner.largest.fetch_max(events as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_stray(&self) {
+        self.inner.stray.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reads that returned at least one event.
+    pub fn reads(&self) -> u64 {
+        self.inner.reads.load(Ordering::Relaxed)
+    }
+
+    /// Events read in total.
+    pub fn events(&self) -> u64 {
+        self.inner.events.load(Ordering::Relaxed)
+    }
+
+    /// The most events a single read returned.
+    pub fn largest(&self) -> u64 {
+        self.inner.largest.load(Ordering::Relaxed)
+    }
+
+    /// Page faults at addresses no region covers, resolved because of
+    /// [`FaultHandler::serve_stray`](crate::FaultHandler::serve_stray).
+    pub fn stray(&self) -> u64 {
+        self.inner.stray.load(Ordering::Relaxed)
+    }
+
+    pub fn events_per_read(&self) -> f64 {
+        match self.reads() {
+            0 => 0.0,
+            reads => self.events() as f64 / reads as f64,
+        }
+    }
+}
+
+/// What readahead resolved and how much of it the guest went on to use.
+///
+/// A window counts as used once the fault stream it was read for carries
+/// on past it, and as wasted once the stream breaks off first.
+#[derive(Debug, Clone, Default)]
+pub struct ReadaheadStats {
+    inner: Arc<ReadaheadCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ReadaheadCounters {
+    pages: AtomicU64,
+    hits: AtomicU64,
+    wasted: AtomicU64,
+}
+
+impl ReadaheadStats {
+    pub(crate) fn record(&self, pages: usize, hits: usize, wasted: usize) {
+        let inner = &self.inner;
+        inner.pages.fetch_add(pages as u64, Ordering::Relaxed);
+        inner.hits.fetch_add(hits as u64, Ordering::Relaxed);
+        inner.wasted.fetch_add(wasted as u64, Ordering::Relaxed);
+    }
+
+    /// Pages mapped ahead of the faults that triggered readahead.
+    pub fn pages(&self) -> u64 {
+        self.inner.pages.load(Ordering::Relaxed)
+    }
+
+    /// Read-ahead pages the guest went on to use.
+    pub fn hits(&self) -> u64 {
+        self.inner.hits.load(Ordering::Relaxed)
+    }
+
+    /// Read-ahead pages the guest moved away from.
+    pub fn wasted(&self) -> u64 {
+        self.inner.wasted.load(Ordering::Relaxed)
+    }
+}
+
+/// Pages a source had only zeroes for, mapped with UFFDIO_ZEROPAGE instead
+/// of being copied.
+#[derive(Debug, Clone, Default)]
+pub struct ZeroPageStats {
+    inner: Arc<ZeroPageCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ZeroPageCounters {
+    pages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl ZeroPageStats {
+    /// Counts one zero page of `len` bytes.
+    pub(crate) fn record(&self, len: usize) {
+        self.inner.pages.fetch_add(1, Ordering::Relaxed);
+        self.inner.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.inner.pages.load(Ordering::Relaxed)
+    }
+
+    /// Bytes that did not have to be copied.
+    pub fn bytes_saved(&self) -> u64 {
+        self.inner.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Source pages that failed their checksum or could not be read, and what
+/// became of them under the handler's [`CorruptPolicy`](crate::CorruptPolicy).
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityStats {
+    inner: Arc<IntegrityCounters>,
+}
+
+#[derive(Debug, Default)]
+struct IntegrityCounters {
+    corrupt: AtomicU64,
+    unreadable: AtomicU64,
+    served: AtomicU64,
+    withheld: AtomicU64,
+    last: Mutex<Option<Mismatch>>,
+}
+
+/// A source page that did not match the checksum it was stored with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub page: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl IntegrityStats {
+    pub(crate) fn record_mismatch(&self, mismatch: Mismatch) {
+        self.inner.corrupt.fetch_add(1, Ordering::Relaxed);
+        *self.inner.last.lock().unwrap() = Some(mismatch);
+    }
+
+    pub(crate) fn record_unreadable(&self) {
+        self.inner.unreadable.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_served(&self) {
+        self.inner.served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_withheld(&self) {
+        self.inner.withheld.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Pages that failed their checksum.
+    pub fn corrupt(&self) -> u64 {
+        self.inner.corrupt.load(Ordering::Relaxed)
+    }
+
+    /// Pages the source failed to read.
+    pub fn unreadable(&self) -> u64 {
+        self.inner.unreadable.load(Ordering::Relaxed)
+    }
+
+    /// Corrupt pages mapped anyway, under
+    /// [`CorruptPolicy::Log`](crate::CorruptPolicy::Log).
+    pub fn served(&self) -> u64 {
+        self.inner.served.load(Ordering::Relaxed)
+    }
+
+    /// Pages whose contents were never mapped: poisoned, or zero-filled
+    /// where the kernel cannot poison.
+    pub fn withheld(&self) -> u64 {
+        self.inner.withheld.load(Ordering::Relaxed)
+    }
+
+    /// The most recent checksum failure.
+    pub fn last_mismatch(&self) -> Option<Mismatch> {
+        *self.inner.last.lock().unwrap()
+    }
+}
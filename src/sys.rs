@@ -0,0 +1,275 @@
+//! Parts of the userfaultfd ABI newer than the `userfaultfd` crate: the
+//! raw API handshake and registration, UFFDIO_POISON and UFFDIO_MOVE, and
+//! fill ioctls that report how far they got before failing.
+
+use std::{
+    ffi::c_void,
+    os::unix::prelude::{AsRawFd, FromRawFd},
+    sync::OnceLock,
+};
+
+use nix::{errno::Errno, libc};
+use userfaultfd::{RegisterMode, Uffd};
+
+use crate::{Error, Result};
+
+const UFFD_API: u64 = 0xaa;
+const UFFDIO: u8 = 0xaa;
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
+
+/// UFFD_FEATURE_POISON, Linux 6.6.
+pub(crate) const FEATURE_POISON: u64 = 1 << 14;
+/// UFFD_FEATURE_MOVE, Linux 6.8.
+pub(crate) const FEATURE_MOVE: u64 = 1 << 16;
+
+const COPY_MODE_DONTWAKE: u64 = 1 << 0;
+const ZEROPAGE_MODE_DONTWAKE: u64 = 1 << 0;
+const CONTINUE_MODE_DONTWAKE: u64 = 1 << 0;
+const POISON_MODE_DONTWAKE: u64 = 1 << 0;
+const MOVE_MODE_DONTWAKE: u64 = 1 << 0;
+
+#[repr(C)]
+pub(crate) struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+pub(crate) struct UffdioRegister {
+    start: u64,
+    len: u64,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+pub(crate) struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+/// The layout of UFFDIO_ZEROPAGE, UFFDIO_CONTINUE and UFFDIO_POISON alike:
+/// a range, a mode and how many bytes were done.
+#[repr(C)]
+pub(crate) struct UffdioRange {
+    start: u64,
+    len: u64,
+    mode: u64,
+    done: i64,
+}
+
+#[repr(C)]
+pub(crate) struct UffdioMove {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    moved: i64,
+}
+
+nix::ioctl_readwrite!(uffdio_register, UFFDIO, 0x00, UffdioRegister);
+nix::ioctl_readwrite!(uffdio_api, UFFDIO, 0x3f, UffdioApi);
+nix::ioctl_readwrite!(uffdio_copy, UFFDIO, 0x03, UffdioCopy);
+nix::ioctl_readwrite!(uffdio_zeropage, UFFDIO, 0x04, UffdioRange);
+nix::ioctl_readwrite!(uffdio_move, UFFDIO, 0x05, UffdioMove);
+nix::ioctl_readwrite!(uffdio_continue, UFFDIO, 0x07, UffdioRange);
+nix::ioctl_readwrite!(uffdio_poison, UFFDIO, 0x08, UffdioRange);
+
+/// A fresh uffd after an API handshake that asked for no features, and
+/// what the kernel offered in it.
+pub(crate) struct Handshake {
+    pub(crate) uffd: Uffd,
+    /// UFFD_FEATURE_* bits.
+    pub(crate) features: u64,
+    /// Ioctls on the uffd itself, as `1 << _UFFDIO_*` bits.
+    pub(crate) ioctls: u64,
+}
+
+pub(crate) fn handshake() -> nix::Result<Handshake> {
+    let uffd = open(libc::O_NONBLOCK)?;
+    let api = api(&uffd, 0)?;
+    Ok(Handshake {
+        uffd,
+        features: api.features,
+        ioctls: api.ioctls,
+    })
+}
+
+/// Opens a uffd, non-blocking if asked, whose API handshake asks for
+/// `features`. The kernel refuses the handshake unless it has them all.
+///
+/// Unlike `UffdBuilder`, this can ask for features newer than the
+/// `userfaultfd` crate, such as [`FEATURE_POISON`] and [`FEATURE_MOVE`].
+pub(crate) fn create(features: u64, non_blocking: bool) -> nix::Result<Uffd> {
+    let uffd = open(if non_blocking { libc::O_NONBLOCK } else { 0 })?;
+    api(&uffd, features)?;
+    Ok(uffd)
+}
+
+/// Opens a uffd with `flags`, falling back to user-mode faults only when
+/// refused: unprivileged callers may not handle kernel-mode ones.
+fn open(flags: libc::c_int) -> nix::Result<Uffd> {
+    match userfaultfd(flags) {
+        Err(Errno::EPERM) => userfaultfd(flags | UFFD_USER_MODE_ONLY),
+        uffd => uffd,
+    }
+}
+
+fn userfaultfd(flags: libc::c_int) -> nix::Result<Uffd> {
+    let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | flags) };
+    Ok(unsafe { Uffd::from_raw_fd(Errno::result(fd)? as libc::c_int) })
+}
+
+fn api(uffd: &Uffd, features: u64) -> nix::Result<UffdioApi> {
+    let mut api = UffdioApi {
+        api: UFFD_API,
+        features,
+        ioctls: 0,
+    };
+    unsafe { uffdio_api(uffd.as_raw_fd(), &mut api) }?;
+    Ok(api)
+}
+
+/// Every feature the running kernel offers, from the API handshake on a
+/// throwaway uffd.
+pub(crate) fn kernel_features() -> nix::Result<u64> {
+    Ok(handshake()?.features)
+}
+
+/// Registers the `len` bytes at `start` with `uffd` in `mode`, and returns
+/// the ioctls the kernel allows on them as `1 << _UFFDIO_*` bits.
+pub(crate) fn register(
+    uffd: &Uffd,
+    start: *mut c_void,
+    len: usize,
+    mode: RegisterMode,
+) -> Result<u64> {
+    let mut register = UffdioRegister {
+        start: start as u64,
+        len: len as u64,
+        mode: mode.bits(),
+        ioctls: 0,
+    };
+    unsafe { uffdio_register(uffd.as_raw_fd(), &mut register) }?;
+    Ok(register.ioctls)
+}
+
+/// [`kernel_features`], probed once. A failed probe fails every later
+/// call the same way.
+pub(crate) fn cached_features() -> nix::Result<u64> {
+    static FEATURES: OnceLock<nix::Result<u64>> = OnceLock::new();
+    *FEATURES.get_or_init(kernel_features)
+}
+
+/// Whether the running kernel supports UFFDIO_POISON (Linux 6.6), probed
+/// once; not if the probe fails. Without it, pages that cannot be fetched
+/// are zero-filled instead.
+pub fn poison_supported() -> bool {
+    cached_features().map_or(false, |features| features & FEATURE_POISON != 0)
+}
+
+/// Whether the running kernel supports UFFDIO_MOVE (Linux 6.8), probed
+/// once; not if the probe fails. Without it,
+/// [`Install::Move`](crate::Install::Move) copies.
+pub fn move_supported() -> bool {
+    cached_features().map_or(false, |features| features & FEATURE_MOVE != 0)
+}
+
+/// Marks `len` bytes at `start` as poisoned, so touching them raises
+/// SIGBUS, and returns how many bytes were.
+pub(crate) fn poison(uffd: &Uffd, start: *mut c_void, len: usize, wake: bool) -> Result<usize> {
+    let mut op = UffdioRange {
+        start: start as u64,
+        len: len as u64,
+        mode: if wake { 0 } else { POISON_MODE_DONTWAKE },
+        done: 0,
+    };
+    let result = unsafe { uffdio_poison(uffd.as_raw_fd(), &mut op) };
+    filled(result, op.done)
+}
+
+/// Moves the `len` bytes of anonymous pages at `src` to `dst`, leaving a
+/// hole behind, and returns how many bytes were.
+pub(crate) fn move_pages(
+    uffd: &Uffd,
+    dst: *mut c_void,
+    src: *mut c_void,
+    len: usize,
+    wake: bool,
+) -> Result<usize> {
+    let mut op = UffdioMove {
+        dst: dst as u64,
+        src: src as u64,
+        len: len as u64,
+        mode: if wake { 0 } else { MOVE_MODE_DONTWAKE },
+        moved: 0,
+    };
+    let result = unsafe { uffdio_move(uffd.as_raw_fd(), &mut op) };
+    filled(result, op.moved)
+}
+
+/// Copies `len` bytes from `src` into the missing pages at `dst`, and
+/// returns how many bytes were.
+pub(crate) fn copy(
+    uffd: &Uffd,
+    dst: *mut c_void,
+    src: *const c_void,
+    len: usize,
+    wake: bool,
+) -> Result<usize> {
+    let mut op = UffdioCopy {
+        dst: dst as u64,
+        src: src as u64,
+        len: len as u64,
+        mode: if wake { 0 } else { COPY_MODE_DONTWAKE },
+        copy: 0,
+    };
+    let result = unsafe { uffdio_copy(uffd.as_raw_fd(), &mut op) };
+    filled(result, op.copy)
+}
+
+/// Maps the zero page over the `len` bytes of missing pages at `start`,
+/// and returns how many bytes were.
+pub(crate) fn zeropage(uffd: &Uffd, start: *mut c_void, len: usize, wake: bool) -> Result<usize> {
+    let mut op = UffdioRange {
+        start: start as u64,
+        len: len as u64,
+        mode: if wake { 0 } else { ZEROPAGE_MODE_DONTWAKE },
+        done: 0,
+    };
+    let result = unsafe { uffdio_zeropage(uffd.as_raw_fd(), &mut op) };
+    filled(result, op.done)
+}
+
+/// Maps the page cache over the `len` bytes at `start` after MINOR
+/// faults, and returns how many bytes were.
+pub(crate) fn uffd_continue(
+    uffd: &Uffd,
+    start: *mut c_void,
+    len: usize,
+    wake: bool,
+) -> Result<usize> {
+    let mut op = UffdioRange {
+        start: start as u64,
+        len: len as u64,
+        mode: if wake { 0 } else { CONTINUE_MODE_DONTWAKE },
+        done: 0,
+    };
+    let result = unsafe { uffdio_continue(uffd.as_raw_fd(), &mut op) };
+    filled(result, op.done)
+}
+
+/// What a fill ioctl amounted to. One that fails part way through, with
+/// EAGAIN or EEXIST, still reports the bytes it did before; those count
+/// as done, and the caller retries from the first page that was not.
+fn filled(result: nix::Result<libc::c_int>, done: i64) -> Result<usize> {
+    match result {
+        Ok(_) => Ok(done as usize),
+        Err(_) if done > 0 => Ok(done as usize),
+        Err(errno) => Err(Error::Sys(errno)),
+    }
+}
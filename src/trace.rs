@@ -0,0 +1,236 @@
+//! Fault traces: which pages were faulted, in what order, and how long
+//! each took to resolve.
+//!
+//! A trace file is laid out as:
+//!
+//! ```text
+//! magic      8 bytes  "UFFDTRCE"
+//! version    u32
+//! page_size  u32
+//! records    28 bytes each, until the end of the file:
+//!   timestamp  u64  nanoseconds since the recorder was created
+//!   offset     u64  backing file offset of the faulting page
+//!   latency    u32  nanoseconds spent resolving, saturating
+//!   handler    u32  id of the handler thread that resolved the fault
+//!   kind       u8   0 = missing, 1 = minor, 2 = write-protect
+//!   write      u8   1 if the access was a write
+//!   padding    2 bytes
+//! ```
+//!
+//! All integers are little-endian. `handler` is the handler's own thread
+//! id, which tells workers apart; it says nothing about which thread of
+//! the faulting process took the fault.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::Serialize;
+use userfaultfd::{FaultKind, ReadWrite};
+
+const MAGIC: &[u8; 8] = b"UFFDTRCE";
+const VERSION: u32 = 1;
+const RECORD_LEN: usize = 28;
+
+/// What kind of fault a record is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceKind {
+    Missing,
+    Minor,
+    WriteProtect,
+}
+
+impl TraceKind {
+    fn of(kind: FaultKind) -> Self {
+        match kind {
+            FaultKind::Missing => TraceKind::Missing,
+            FaultKind::Minor => TraceKind::Minor,
+            FaultKind::WriteProtected => TraceKind::WriteProtect,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TraceKind::Missing => "missing",
+            TraceKind::Minor => "minor",
+            TraceKind::WriteProtect => "write-protect",
+        }
+    }
+}
+
+/// One resolved fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TraceRecord {
+    pub timestamp_ns: u64,
+    pub offset: u64,
+    pub kind: TraceKind,
+    pub write: bool,
+    /// Id of the handler thread that resolved the fault.
+    pub handler_thread: u32,
+    pub latency_ns: u32,
+}
+
+impl TraceRecord {
+    fn to_bytes(self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0; RECORD_LEN];
+        bytes[0..8].copy_from_slice(&self.timestamp_ns.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.latency_ns.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.handler_thread.to_le_bytes());
+        bytes[24] = match self.kind {
+            TraceKind::Missing => 0,
+            TraceKind::Minor => 1,
+            TraceKind::WriteProtect => 2,
+        };
+        bytes[25] = self.write as u8;
+        bytes
+    }
+}
+
+/// Appends a record for every fault a handler resolves, shared by all of
+/// its threads.
+#[derive(Clone)]
+pub struct TraceRecorder {
+    inner: Arc<Mutex<Recorder>>,
+}
+
+struct Recorder {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+impl TraceRecorder {
+    /// Starts a trace at `path` for faults in units of `page_size`.
+    pub fn create(path: impl AsRef<Path>, page_size: usize) -> io::Result<Self> {
+        Self::from_writer(BufWriter::new(File::create(path)?), page_size)
+    }
+
+    /// Starts a trace written to `out`.
+    pub fn from_writer(mut out: impl Write + Send + 'static, page_size: usize) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(page_size as u32).to_le_bytes())?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Recorder {
+                out: Box::new(out),
+                start: Instant::now(),
+            })),
+        })
+    }
+
+    /// Records a fault at backing file `offset` that was read off the uffd
+    /// at `started` and has just been resolved.
+    pub(crate) fn record(
+        &self,
+        kind: FaultKind,
+        rw: ReadWrite,
+        offset: u64,
+        started: Instant,
+    ) -> io::Result<()> {
+        let latency = started.elapsed().as_nanos();
+        let mut recorder = self.inner.lock().unwrap();
+        let record = TraceRecord {
+            timestamp_ns: started.saturating_duration_since(recorder.start).as_nanos() as u64,
+            offset,
+            kind: TraceKind::of(kind),
+            write: rw == ReadWrite::Write,
+            handler_thread: nix::unistd::gettid().as_raw() as u32,
+            latency_ns: latency.min(u32::MAX as u128) as u32,
+        };
+        recorder.out.write_all(&record.to_bytes())
+    }
+
+    /// Writes out buffered records.
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap().out.flush()
+    }
+}
+
+/// A trace read back from a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    pub page_size: usize,
+    pub records: Vec<TraceRecord>,
+}
+
+impl Trace {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn read_from(input: &mut impl Read) -> io::Result<Self> {
+        let mut header = [0; 16];
+        input.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not a fault trace"));
+        }
+        if u32::from_le_bytes(header[8..12].try_into().unwrap()) != VERSION {
+            return Err(invalid("unsupported trace version"));
+        }
+        let page_size = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        // A recorder killed mid-write leaves a partial last record.
+        let records = data
+            .chunks_exact(RECORD_LEN)
+            .map(parse_record)
+            .collect::<io::Result<_>>()?;
+        Ok(Self { page_size, records })
+    }
+
+    /// Writes the records as CSV with a header line.
+    pub fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "timestamp_ns,offset,kind,write,handler_thread,latency_ns"
+        )?;
+        for r in &self.records {
+            writeln!(
+                out,
+                "{},{},{},{},{},{}",
+                r.timestamp_ns,
+                r.offset,
+                r.kind.name(),
+                r.write as u8,
+                r.handler_thread,
+                r.latency_ns
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the records as a JSON array.
+    pub fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        serde_json::to_writer(&mut *out, &self.records)?;
+        writeln!(out)
+    }
+}
+
+fn parse_record(bytes: &[u8]) -> io::Result<TraceRecord> {
+    let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    let kind = match bytes[24] {
+        0 => TraceKind::Missing,
+        1 => TraceKind::Minor,
+        2 => TraceKind::WriteProtect,
+        _ => return Err(invalid("unknown fault kind")),
+    };
+    Ok(TraceRecord {
+        timestamp_ns: u64_at(0),
+        offset: u64_at(8),
+        kind,
+        write: bytes[25] != 0,
+        handler_thread: u32_at(20),
+        latency_ns: u32_at(16),
+    })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
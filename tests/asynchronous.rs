@@ -0,0 +1,194 @@
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use std::{fs::OpenOptions, io, os::unix::fs::FileExt, time::Duration};
+
+use common::{registered_with, scratch_path};
+use uffd_bug::{
+    asynchronous::{create_uffd, AsyncFaultHandler, AsyncPageSource, Blocking},
+    memfd_register_mode, page_size,
+    snapshot::{self, SnapshotChain},
+    trace::{Trace, TraceRecorder},
+    CorruptPolicy, Error, MemfdRegion, MemorySource, Page,
+};
+use userfaultfd::RegisterMode;
+
+/// Pages that take a while to arrive, as from the network.
+struct Delayed;
+
+impl AsyncPageSource for Delayed {
+    async fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        buf.fill(index as u8 + 1);
+        Ok(Page::Data)
+    }
+}
+
+/// As [`Delayed`], except that page 1 can no longer be read.
+struct Lost;
+
+impl AsyncPageSource for Lost {
+    async fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        if index == 1 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "source gone"));
+        }
+        Delayed.read_page(index, buf).await
+    }
+}
+
+fn setup(
+    pages: usize,
+    source: impl AsyncPageSource + 'static,
+) -> (MemfdRegion, uffd_bug::VmRegion) {
+    let (memory, vm, handler) = registered_with(
+        create_uffd().unwrap(),
+        pages,
+        memfd_register_mode().unwrap(),
+    );
+    tokio::spawn(AsyncFaultHandler::new(handler, source, None).run());
+    (memory, vm)
+}
+
+/// Touches the first byte of each page off the runtime, since a faulting
+/// thread blocks until the handler resolves it.
+async fn read_pages(vm: &uffd_bug::VmRegion, pages: usize) -> Vec<u8> {
+    let base = vm.as_ptr() as usize;
+    tokio::task::spawn_blocking(move || {
+        (0..pages)
+            .map(|page| unsafe { ((base + page * page_size()) as *const u8).read_volatile() })
+            .collect()
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_faults_await_the_source() {
+    let (_memory, vm) = setup(4, Delayed);
+
+    assert_eq!(read_pages(&vm, 4).await, vec![1, 2, 3, 4]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blocking_source_runs_off_the_runtime() {
+    let mut source = MemorySource::new();
+    source.insert(1, vec![7; 16]);
+    let (_memory, vm) = setup(3, Blocking(source.into()));
+
+    assert_eq!(read_pages(&vm, 3).await, vec![0, 7, 0]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn events_are_read_in_batches() {
+    let pages = 16;
+    let (_memory, vm, handler) =
+        registered_with(create_uffd().unwrap(), pages, RegisterMode::MISSING);
+    let handler = handler.read_batch(8);
+    let stats = handler.event_stats();
+    tokio::spawn(AsyncFaultHandler::new(handler, Delayed, None).run());
+
+    let base = vm.as_ptr() as usize;
+    let readers: Vec<_> = (0..4)
+        .map(|thread| {
+            tokio::task::spawn_blocking(move || {
+                for page in (thread..pages).step_by(4) {
+                    let byte = (base + page * page_size()) as *const u8;
+                    assert_eq!(unsafe { byte.read_volatile() }, page as u8 + 1);
+                }
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.await.unwrap();
+    }
+
+    // One MISSING fault per page, however the reads batched them.
+    assert_eq!(stats.events(), pages as u64);
+    assert!(stats.reads() >= 1 && stats.reads() <= stats.events());
+    assert!((1..=8).contains(&stats.largest()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn minor_faults_map_the_page_cache() {
+    let (memory, vm) = setup(2, Delayed);
+    memory.write(page_size(), &[9]);
+
+    // Page 1 is in the page cache, so its fault is MINOR and maps the
+    // memfd as is.
+    assert_eq!(read_pages(&vm, 2).await, vec![1, 9]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn staged_pages_are_verified() {
+    // A two page base layer whose page 1 was damaged after it was written.
+    let path = scratch_path("async.base");
+    let memory = MemfdRegion::new(2 * page_size()).unwrap();
+    memory.write(0, &[1; 8]);
+    memory.write(page_size(), &[2; 8]);
+    snapshot::write_base(&mut std::fs::File::create(&path).unwrap(), &memory).unwrap();
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[0xee], (2 * page_size() + 4) as u64)
+        .unwrap();
+
+    let (_memory, vm, handler) = registered_with(create_uffd().unwrap(), 2, RegisterMode::MISSING);
+    let chain = SnapshotChain::open(&[&path]).unwrap();
+    let handler = handler.on_corrupt(CorruptPolicy::Abort);
+    let run = tokio::spawn(AsyncFaultHandler::new(handler, Blocking(chain.into()), None).run());
+
+    // The handler gives up, closing the uffd, which lets the reader through.
+    let addr = vm.as_ptr() as usize + page_size();
+    let reader =
+        tokio::task::spawn_blocking(move || unsafe { (addr as *const u8).read_volatile() });
+    match run.await.unwrap() {
+        Err(Error::Corrupt { page: 1, .. }) => {}
+        result => panic!("expected a checksum failure, got {:?}", result),
+    }
+    reader.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_reads_follow_the_corrupt_policy() {
+    let (_memory, vm, handler) = registered_with(create_uffd().unwrap(), 3, RegisterMode::MISSING);
+    let handler = handler.on_corrupt(CorruptPolicy::Poison).use_poison(false);
+    tokio::spawn(AsyncFaultHandler::new(handler, Lost, None).run());
+
+    // The lost page is withheld, here as zeroes, and the handler goes on.
+    assert_eq!(read_pages(&vm, 3).await, vec![1, 0, 3]);
+
+    let (_memory, vm, handler) = registered_with(create_uffd().unwrap(), 3, RegisterMode::MISSING);
+    let handler = handler.on_corrupt(CorruptPolicy::Abort);
+    let run = tokio::spawn(AsyncFaultHandler::new(handler, Lost, None).run());
+
+    let addr = vm.as_ptr() as usize + page_size();
+    let reader =
+        tokio::task::spawn_blocking(move || unsafe { (addr as *const u8).read_volatile() });
+    match run.await.unwrap() {
+        Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+        result => panic!("expected the read error, got {:?}", result),
+    }
+    reader.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn traced_latency_includes_the_fetch() {
+    let path = scratch_path("async.trace");
+    let (_memory, vm, handler) = registered_with(create_uffd().unwrap(), 1, RegisterMode::MISSING);
+    let recorder = TraceRecorder::create(&path, page_size()).unwrap();
+    let handler = handler.trace(recorder.clone());
+    tokio::spawn(AsyncFaultHandler::new(handler, Delayed, None).run());
+
+    assert_eq!(read_pages(&vm, 1).await, vec![1]);
+
+    // The fault is recorded after its thread is woken.
+    for _ in 0..100 {
+        recorder.flush().unwrap();
+        if let [record] = Trace::open(&*path).unwrap().records[..] {
+            // Delayed takes a millisecond to fetch the page.
+            assert!(record.latency_ns >= 1_000_000, "{:?}", record);
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the fault was never recorded");
+}
@@ -0,0 +1,81 @@
+//! Setup shared by the integration tests. Each test binary uses only some
+//! of it.
+#![allow(dead_code)]
+
+use std::{
+    ffi::CString,
+    fs::File,
+    ops::Deref,
+    os::unix::prelude::FromRawFd,
+    path::{Path, PathBuf},
+};
+
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use uffd_bug::{create_uffd, memfd_register_mode, page_size, FaultHandler, MemfdRegion, VmRegion};
+use userfaultfd::{RegisterMode, Uffd};
+
+/// A file name in the temp directory, unique to this process; whatever
+/// ends up there is removed when the path is dropped.
+pub struct ScratchPath(PathBuf);
+
+pub fn scratch_path(name: &str) -> ScratchPath {
+    ScratchPath(std::env::temp_dir().join(format!("uffd-bug-{}-{}", std::process::id(), name)))
+}
+
+impl Deref for ScratchPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ScratchPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// An anonymous file, gone once closed.
+pub fn tempfile() -> File {
+    let name = CString::new("uffd-bug-test").unwrap();
+    let fd = memfd_create(&name, MemFdCreateFlag::empty()).unwrap();
+    unsafe { File::from_raw_fd(fd) }
+}
+
+/// A memfd of `pages` pages whose mapping is registered with a fresh uffd
+/// in `mode`, and a handler for it that has not been started.
+pub fn registered(pages: usize, mode: RegisterMode) -> (MemfdRegion, VmRegion, FaultHandler) {
+    registered_with(create_uffd().unwrap(), pages, mode)
+}
+
+/// As [`registered`], on `uffd`.
+pub fn registered_with(
+    uffd: Uffd,
+    pages: usize,
+    mode: RegisterMode,
+) -> (MemfdRegion, VmRegion, FaultHandler) {
+    let memory = MemfdRegion::new(pages * page_size()).unwrap();
+    let vm = memory.map_vm().unwrap();
+    vm.register(&uffd, mode).unwrap();
+    let handler = FaultHandler::new(uffd, &vm);
+    (memory, vm, handler)
+}
+
+/// Whether memfd mappings can take MINOR faults here. Tests that only
+/// mean something with them skip themselves otherwise.
+pub fn minor_supported() -> bool {
+    let supported = memfd_register_mode()
+        .unwrap()
+        .contains(RegisterMode::MODE_MINOR);
+    if !supported {
+        eprintln!("skipping: MINOR faults on shmem are not supported");
+    }
+    supported
+}
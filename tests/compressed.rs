@@ -0,0 +1,176 @@
+mod common;
+
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+use common::{registered, scratch_path};
+use uffd_bug::{
+    compressed::{self, CompressedFile},
+    page_size, MemfdRegion, Page, PageSource,
+};
+use userfaultfd::RegisterMode;
+
+/// Compresses `memory` in blocks of `block_pages` pages and opens the
+/// result.
+fn compress(memory: &MemfdRegion, name: &str, block_pages: usize) -> CompressedFile {
+    let path = scratch_path(name);
+    let mut out = File::create(&path).unwrap();
+    let stats = compressed::write_compressed(
+        &mut out,
+        memory.file(),
+        memory.len() as u64,
+        page_size(),
+        block_pages * page_size(),
+    )
+    .unwrap();
+    assert_eq!(stats.raw_bytes, memory.len() as u64);
+
+    CompressedFile::open(&path).unwrap()
+}
+
+#[test]
+fn pages_round_trip_through_blocks() {
+    let memory = MemfdRegion::new(10 * page_size()).unwrap();
+    for page in 0..10 {
+        memory.write(page * page_size() + 100, &[page as u8 + 1; 32]);
+    }
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let noise: Vec<u8> = (0..page_size())
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    memory.write(9 * page_size(), &noise);
+
+    // Page 9 alone in the last block does not compress and is stored as
+    // is.
+    let source = compress(&memory, "roundtrip.cmp", 3);
+    let mut buf = vec![0; page_size()];
+
+    for page in 0..9 {
+        assert_eq!(source.read_page(page, &mut buf).unwrap(), Page::Data);
+        assert_eq!(&buf[100..132], &[page as u8 + 1; 32]);
+        assert_eq!(buf[0], 0);
+    }
+    assert_eq!(source.read_page(9, &mut buf).unwrap(), Page::Data);
+    assert_eq!(buf, noise);
+    assert_eq!(source.read_page(10, &mut buf).unwrap(), Page::Absent);
+}
+
+#[test]
+fn zero_blocks_are_not_stored() {
+    let memory = MemfdRegion::new(8 * page_size()).unwrap();
+    memory.write(5 * page_size(), &[3; 8]);
+
+    let source = compress(&memory, "zero.cmp", 4);
+    let mut buf = vec![0xff; page_size()];
+
+    assert_eq!(source.read_page(1, &mut buf).unwrap(), Page::Zero);
+    assert_eq!(source.read_page(5, &mut buf).unwrap(), Page::Data);
+    assert_eq!(&buf[..8], &[3; 8]);
+    assert_eq!(source.blocks_decompressed(), 1);
+}
+
+#[test]
+fn cache_keeps_recent_blocks() {
+    let memory = MemfdRegion::new(12 * page_size()).unwrap();
+    for page in 0..12 {
+        memory.write(page * page_size(), &[page as u8 + 1]);
+    }
+    let source = compress(&memory, "cache.cmp", 4).cache_blocks(2);
+    let mut buf = vec![0; page_size()];
+
+    // Every page of a block comes from one decompression.
+    for page in 0..4 {
+        source.read_page(page, &mut buf).unwrap();
+    }
+    assert_eq!(source.blocks_decompressed(), 1);
+    assert_eq!(source.cache_hits(), 3);
+
+    // Blocks 1 and 2 push block 0 out.
+    source.read_page(4, &mut buf).unwrap();
+    source.read_page(8, &mut buf).unwrap();
+    source.read_page(0, &mut buf).unwrap();
+    assert_eq!(source.blocks_decompressed(), 4);
+    assert_eq!(buf[0], 1);
+}
+
+#[test]
+fn handler_fills_faults_from_compressed_image() {
+    let image = MemfdRegion::new(8 * page_size()).unwrap();
+    image.write(6 * page_size(), &[8; 8]);
+    let source = compress(&image, "handler.cmp", 4);
+
+    let (_memory, vm, handler) = registered(8, RegisterMode::MISSING);
+    handler.page_source(source, None).spawn();
+
+    assert_eq!(vm.read(6 * page_size(), 8), vec![8; 8]);
+    assert_eq!(vm.read(page_size(), 8), vec![0; 8]);
+}
+
+#[test]
+fn truncated_image_is_rejected() {
+    let path = scratch_path("truncated.cmp");
+    let memory = MemfdRegion::new(8 * page_size()).unwrap();
+    memory.write(0, &[1; 8]);
+    memory.write(4 * page_size(), &[2; 8]);
+    compressed::write_compressed(
+        &mut File::create(&path).unwrap(),
+        memory.file(),
+        memory.len() as u64,
+        page_size(),
+        4 * page_size(),
+    )
+    .unwrap();
+    let len = std::fs::metadata(&path).unwrap().len();
+
+    // Cut into the last block, then into the index.
+    for cut in [len - 1, 40 + 12 + 4] {
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(cut)
+            .unwrap();
+        let err = CompressedFile::open(&path).err().expect("image opened");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn oversized_header_is_rejected() {
+    let path = scratch_path("oversized.cmp");
+    let memory = MemfdRegion::new(4 * page_size()).unwrap();
+    memory.write(0, &[1; 8]);
+    compressed::write_compressed(
+        &mut File::create(&path).unwrap(),
+        memory.file(),
+        memory.len() as u64,
+        page_size(),
+        page_size(),
+    )
+    .unwrap();
+
+    // page_size sits at byte 12 of the header, len at 24 and block_count
+    // at 32.
+    let file = File::options().write(true).open(&path).unwrap();
+    let patch = |offset, bytes: &[u8]| file.write_all_at(bytes, offset).unwrap();
+    let rejected = || {
+        let err = CompressedFile::open(&path).err().expect("image opened");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    };
+    patch(12, &3000u32.to_le_bytes());
+    rejected();
+    patch(12, &(page_size() as u32).to_le_bytes());
+
+    // Lengths and block counts that agree, but that the file cannot hold.
+    let blocks = 1u64 << 40;
+    patch(24, &(blocks * page_size() as u64).to_le_bytes());
+    patch(32, &blocks.to_le_bytes());
+    rejected();
+    patch(24, &u64::MAX.to_le_bytes());
+    patch(32, &u64::MAX.div_ceil(page_size() as u64).to_le_bytes());
+    rejected();
+}
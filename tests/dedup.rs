@@ -0,0 +1,116 @@
+mod common;
+
+use std::{fs::OpenOptions, io, os::unix::fs::FileExt, sync::Arc};
+
+use common::{registered, scratch_path};
+use uffd_bug::{
+    dedup::{self, Manifest, PageStore, StoreSource},
+    page_size, MemfdRegion, Page, PageSource,
+};
+use userfaultfd::RegisterMode;
+
+/// Memory whose page N holds N + 1, except for the pages in `zero`.
+fn image(pages: usize, zero: &[usize]) -> MemfdRegion {
+    let memory = MemfdRegion::new(pages * page_size()).unwrap();
+    for page in (0..pages).filter(|page| !zero.contains(page)) {
+        memory.write(page * page_size(), &[page as u8 + 1; 8]);
+    }
+    memory
+}
+
+#[test]
+fn shared_pages_are_stored_once() {
+    let path = scratch_path("shared.store");
+    let store = PageStore::create(&path, page_size()).unwrap();
+
+    let first = image(8, &[7]);
+    let (_, stats) = dedup::write_snapshot(&store, first.file(), first.len()).unwrap();
+    assert_eq!((stats.stored, stats.shared, stats.zero), (7, 0, 1));
+
+    // Same base, one page changed.
+    let second = image(8, &[7]);
+    second.write(3 * page_size(), &[42; 8]);
+    let (manifest, stats) = dedup::write_snapshot(&store, second.file(), second.len()).unwrap();
+    assert_eq!((stats.stored, stats.shared, stats.zero), (1, 6, 1));
+    assert_eq!(store.len(), 8);
+
+    let mut buf = vec![0; page_size()];
+    assert_eq!(
+        store.read(&manifest.hashes[3], &mut buf).unwrap(),
+        Page::Data
+    );
+    assert_eq!(&buf[..8], &[42; 8]);
+    assert_eq!(
+        store.read(&manifest.hashes[7], &mut buf).unwrap(),
+        Page::Zero
+    );
+}
+
+#[test]
+fn store_and_manifest_survive_reopening() {
+    let (path, manifest_path) = (
+        scratch_path("reopen.store"),
+        scratch_path("reopen.manifest"),
+    );
+    let memory = image(4, &[]);
+    {
+        let store = PageStore::create(&path, page_size()).unwrap();
+        let (manifest, _) = dedup::write_snapshot(&store, memory.file(), memory.len()).unwrap();
+        manifest.save(&manifest_path).unwrap();
+    }
+
+    let store = PageStore::open(&path).unwrap();
+    let manifest = Manifest::open(&manifest_path).unwrap();
+    assert_eq!(store.len(), 4);
+    assert_eq!(manifest.hashes.len(), 4);
+
+    let source = StoreSource::new(Arc::new(store), manifest).unwrap();
+    let mut buf = vec![0; page_size()];
+    assert_eq!(source.read_page(2, &mut buf).unwrap(), Page::Data);
+    assert_eq!(&buf[..8], &[3; 8]);
+    assert_eq!(source.read_page(4, &mut buf).unwrap(), Page::Absent);
+}
+
+#[test]
+fn damaged_pages_fail_their_hash() {
+    let path = scratch_path("damaged.store");
+    let memory = image(2, &[]);
+    let store = PageStore::create(&path, page_size()).unwrap();
+    let (manifest, _) = dedup::write_snapshot(&store, memory.file(), memory.len()).unwrap();
+
+    // Page 0's record comes first, right after the 16 byte header.
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[0xee], 16 + 32 + 4).unwrap();
+
+    let source = StoreSource::new(Arc::new(store), manifest).unwrap();
+    let mut buf = vec![0; page_size()];
+    let err = source.read_page(0, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(source.read_page(1, &mut buf).unwrap(), Page::Data);
+}
+
+#[test]
+fn handlers_share_one_store() {
+    let path = scratch_path("handlers.store");
+    let store = Arc::new(PageStore::create(&path, page_size()).unwrap());
+
+    let base = image(4, &[]);
+    let (first, _) = dedup::write_snapshot(&store, base.file(), base.len()).unwrap();
+    base.write(page_size(), &[9; 8]);
+    let (second, _) = dedup::write_snapshot(&store, base.file(), base.len()).unwrap();
+
+    let vms: Vec<_> = [first, second]
+        .into_iter()
+        .map(|manifest| {
+            let (memory, vm, handler) = registered(4, RegisterMode::MISSING);
+            let source = StoreSource::new(store.clone(), manifest).unwrap();
+            handler.page_source(source, None).spawn();
+            (memory, vm)
+        })
+        .collect();
+
+    assert_eq!(vms[0].1.read(page_size(), 8), vec![2; 8]);
+    assert_eq!(vms[1].1.read(page_size(), 8), vec![9; 8]);
+    assert_eq!(vms[0].1.read(3 * page_size(), 8), vec![4; 8]);
+    assert_eq!(vms[1].1.read(3 * page_size(), 8), vec![4; 8]);
+}
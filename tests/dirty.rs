@@ -0,0 +1,34 @@
+mod common;
+
+use common::registered;
+use uffd_bug::{memfd_register_mode, page_size, DirtyTracker};
+use userfaultfd::RegisterMode;
+
+#[test]
+fn write_protect_faults_feed_dirty_set() {
+    let (memory, vm, handler) = registered(
+        4,
+        memfd_register_mode().unwrap() | RegisterMode::WRITE_PROTECT,
+    );
+
+    // Map every page first so later writes only take write-protect faults.
+    for page in 0..4 {
+        memory.write(page * page_size(), &[1]);
+    }
+    let tracker = DirtyTracker::new(handler.uffd(), &vm).unwrap();
+    handler.dirty_tracker(tracker.clone()).spawn();
+    for page in 0..4 {
+        vm.read(page * page_size(), 1);
+    }
+
+    vm.write(page_size(), &[2]);
+    vm.write(3 * page_size(), &[2]);
+    let dirty = tracker.take().unwrap();
+    assert_eq!(dirty.iter_ones().collect::<Vec<_>>(), vec![1, 3]);
+    assert_eq!(tracker.count(), 0);
+
+    vm.write(page_size() + 8, &[3]);
+    let dirty = tracker.take().unwrap();
+    assert_eq!(dirty.iter_ones().collect::<Vec<_>>(), vec![1]);
+    assert_eq!(memory.read(page_size() + 8, 1), vec![3]);
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use std::{
+    io::Write,
+    os::unix::{net::UnixStream, prelude::AsRawFd},
+    path::Path,
+    time::Duration,
+};
+
+use common::scratch_path;
+use uffd_bug::{
+    firecracker::{self, GuestRegionUffdMapping},
+    page_size, VmRegion,
+};
+use userfaultfd::{RegisterMode, UffdBuilder};
+
+fn connect(path: &Path) -> UnixStream {
+    for _ in 0..100 {
+        if let Ok(stream) = UnixStream::connect(path) {
+            return stream;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("handler never listened on {:?}", path);
+}
+
+#[test]
+fn serves_guest_memory_from_snapshot() {
+    let pages = 4;
+    let socket = scratch_path("fc.sock");
+    let snapshot = scratch_path("fc.mem");
+    let _ = std::fs::remove_file(&socket);
+
+    let mut file = std::fs::File::create(&snapshot).unwrap();
+    for page in 0..pages {
+        file.write_all(&vec![page as u8 + 1; page_size()]).unwrap();
+    }
+    drop(file);
+
+    std::thread::spawn({
+        let (socket, snapshot) = (socket.to_path_buf(), snapshot.to_path_buf());
+        move || firecracker::serve(&socket, &snapshot, None)
+    });
+
+    // Stand in for Firecracker: anonymous guest memory, registered for
+    // MISSING faults on a non-blocking uffd.
+    let guest = VmRegion::anonymous(pages * page_size()).unwrap();
+    let uffd = UffdBuilder::new()
+        .close_on_exec(true)
+        .non_blocking(true)
+        .user_mode_only(false)
+        .create()
+        .unwrap();
+    guest.register(&uffd, RegisterMode::MISSING).unwrap();
+
+    let stream = connect(&socket);
+    let mappings = [GuestRegionUffdMapping {
+        base_host_virt_addr: guest.as_ptr() as u64,
+        size: guest.len(),
+        offset: 0,
+        page_size_kib: Some(page_size() / 1024),
+        page_size: None,
+    }];
+    firecracker::send(&stream, uffd.as_raw_fd(), &mappings).unwrap();
+
+    for page in 0..pages {
+        assert_eq!(guest.read(page * page_size(), 2), vec![page as u8 + 1; 2]);
+    }
+}
+
+#[test]
+fn mapping_accepts_both_page_size_fields() {
+    let old: Vec<GuestRegionUffdMapping> = serde_json::from_str(
+        r#"[{"base_host_virt_addr":4096,"size":8192,"offset":0,"page_size_kib":4}]"#,
+    )
+    .unwrap();
+    let new: Vec<GuestRegionUffdMapping> = serde_json::from_str(
+        r#"[{"base_host_virt_addr":4096,"size":8192,"offset":0,"page_size":2097152}]"#,
+    )
+    .unwrap();
+
+    assert_eq!(old[0].page_bytes(), 4096);
+    assert_eq!(new[0].page_bytes(), 2 * 1024 * 1024);
+}
@@ -0,0 +1,170 @@
+mod common;
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use common::{minor_supported, registered};
+use uffd_bug::{
+    memfd_register_mode, page_size, FaultHandler, MemfdRegion, MemorySource, Page, PageSource,
+};
+use userfaultfd::RegisterMode;
+
+fn setup(pages: usize) -> (MemfdRegion, uffd_bug::VmRegion) {
+    setup_with(pages, |handler| handler)
+}
+
+fn setup_with(
+    pages: usize,
+    configure: impl FnOnce(FaultHandler) -> FaultHandler,
+) -> (MemfdRegion, uffd_bug::VmRegion) {
+    let (memory, vm, handler) = registered(pages, memfd_register_mode().unwrap());
+    configure(handler).spawn();
+    (memory, vm)
+}
+
+#[test]
+fn minor_fault_sees_memfd_write() {
+    let (memory, vm) = setup(4);
+
+    memory.write(0, &[1, 2, 3]);
+
+    assert_eq!(vm.read(0, 3), vec![1, 2, 3]);
+}
+
+#[test]
+fn missing_fault_reads_zeroes() {
+    let (_memory, vm) = setup(4);
+    let last_page = 3 * page_size();
+
+    assert_eq!(vm.read(last_page, 8), vec![0; 8]);
+}
+
+#[test]
+fn block_resolve_stops_at_region_end() {
+    let (memory, vm) = setup_with(4, |handler| handler.block_pages(3));
+    memory.write(page_size(), &[9]);
+
+    // The block holding page 3 is clamped to one page.
+    assert_eq!(vm.read(3 * page_size(), 1), vec![0]);
+    // Page 0's block covers pages 0..3 and maps the populated page 1 too.
+    assert_eq!(vm.read(0, 1), vec![0]);
+    assert_eq!(vm.read(page_size(), 1), vec![9]);
+}
+
+#[test]
+fn missing_fault_copies_from_source() {
+    let mut source = MemorySource::new();
+    source.insert(2, vec![5; 16]);
+    let (_memory, vm) = setup_with(4, |handler| handler.page_source(source, None));
+
+    assert_eq!(vm.read(2 * page_size(), 4), vec![5; 4]);
+    assert_eq!(vm.read(3 * page_size(), 4), vec![0; 4]);
+}
+
+#[test]
+fn minor_fault_populates_memfd_from_source() {
+    if !minor_supported() {
+        return;
+    }
+    let (memory, vm, handler) = registered(4, memfd_register_mode().unwrap());
+    let mut source = MemorySource::new();
+    source.insert(1, vec![6; 16]);
+    let memfd = memory.file().try_clone().unwrap();
+    handler.page_source(source, Some(memfd)).spawn();
+
+    // Put a placeholder page in the page cache so the access is MINOR.
+    memory.write(page_size(), &[1]);
+
+    assert_eq!(vm.read(page_size(), 4), vec![6; 4]);
+    assert_eq!(memory.read(page_size(), 4), vec![6; 4]);
+}
+
+/// Counts how often each page is read.
+struct CountingSource {
+    reads: Vec<AtomicUsize>,
+}
+
+impl PageSource for CountingSource {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        self.reads[index].fetch_add(1, Ordering::Relaxed);
+        buf.fill(index as u8 + 1);
+        Ok(Page::Data)
+    }
+}
+
+#[test]
+fn workers_resolve_each_page_once() {
+    let pages = 64;
+    let source = Arc::new(CountingSource {
+        reads: (0..pages).map(|_| AtomicUsize::new(0)).collect(),
+    });
+    let (_memory, vm) = setup_with(pages, |handler| {
+        handler.page_source(source.clone(), None).workers(4)
+    });
+    let base = vm.as_ptr() as usize;
+
+    // Every thread touches every page, so most faults race on a page
+    // another thread is faulting on too.
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                for page in 0..pages {
+                    let byte = (base + page * page_size()) as *const u8;
+                    assert_eq!(unsafe { byte.read_volatile() }, page as u8 + 1);
+                }
+            });
+        }
+    });
+
+    for reads in &source.reads {
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+    }
+}
+
+#[test]
+fn event_stats_count_every_read() {
+    let pages = 32;
+    let (_memory, vm, handler) = registered(pages, RegisterMode::MISSING);
+    let handler = handler.read_batch(8);
+    let stats = handler.event_stats();
+    handler.spawn();
+    let base = vm.as_ptr() as usize;
+
+    std::thread::scope(|s| {
+        for thread in 0..4 {
+            s.spawn(move || {
+                for page in (thread..pages).step_by(4) {
+                    let byte = (base + page * page_size()) as *const u8;
+                    assert_eq!(unsafe { byte.read_volatile() }, 0);
+                }
+            });
+        }
+    });
+
+    // One MISSING fault per page, however the reads batched them.
+    assert_eq!(stats.events(), pages as u64);
+    assert!(stats.reads() >= 1 && stats.reads() <= stats.events());
+    assert!((1..=8).contains(&stats.largest()));
+}
+
+#[test]
+fn all_zero_source_pages_are_not_copied() {
+    let mut source = MemorySource::new();
+    source.insert(0, vec![0; 16]);
+    source.insert(1, vec![3; 16]);
+    let (_memory, vm, handler) = registered(4, RegisterMode::MISSING);
+    let handler = handler.page_source(source, None);
+    let stats = handler.zero_page_stats();
+    handler.spawn();
+
+    assert_eq!(vm.read(0, 4), vec![0; 4]);
+    assert_eq!(vm.read(page_size(), 4), vec![3; 4]);
+
+    assert_eq!(stats.pages(), 1);
+    assert_eq!(stats.bytes_saved(), page_size() as u64);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::registered;
+use uffd_bug::{
+    create_uffd, move_supported, page_size, FaultHandler, Install, MemorySource, VmRegion,
+};
+use userfaultfd::RegisterMode;
+
+/// Page N holds N + 1, except that every fourth page is absent.
+fn source(pages: usize) -> MemorySource {
+    let mut source = MemorySource::new();
+    for page in (0..pages).filter(|page| page % 4 != 3) {
+        source.insert(page, vec![page as u8 + 1; page_size()]);
+    }
+    source
+}
+
+fn expected(page: usize) -> u8 {
+    if page % 4 == 3 {
+        0
+    } else {
+        page as u8 + 1
+    }
+}
+
+#[test]
+fn moved_pages_hold_the_source_contents() {
+    let pages = 32;
+    let vm = VmRegion::anonymous(pages * page_size()).unwrap();
+    let uffd = create_uffd().unwrap();
+    vm.register(&uffd, RegisterMode::MISSING).unwrap();
+    FaultHandler::new(uffd, &vm)
+        .page_source(source(pages), None)
+        .install(Install::Move)
+        .workers(4)
+        .spawn();
+
+    std::thread::scope(|s| {
+        for thread in 0..4 {
+            let vm = &vm;
+            s.spawn(move || {
+                for page in (thread..pages).step_by(4) {
+                    let bytes = vm.read(page * page_size(), page_size());
+                    assert!(bytes.iter().all(|&b| b == expected(page)), "page {}", page);
+                }
+            });
+        }
+    });
+
+    // Moved in, the pages are the region's own.
+    vm.write(page_size(), &[0xaa; 8]);
+    assert_eq!(vm.read(page_size(), 9), [&[0xaa; 8][..], &[2]].concat());
+    if !move_supported() {
+        eprintln!("UFFDIO_MOVE is not supported; pages were copied");
+    }
+}
+
+#[test]
+fn move_copies_into_shared_memory() {
+    let pages = 8;
+    let (memory, vm, handler) = registered(pages, RegisterMode::MISSING);
+    handler
+        .page_source(source(pages), None)
+        .install(Install::Move)
+        .spawn();
+
+    for page in 0..pages {
+        assert_eq!(vm.read(page * page_size(), 8), vec![expected(page); 8]);
+    }
+    assert_eq!(memory.read(0, 8), vec![1; 8]);
+}
@@ -0,0 +1,224 @@
+mod common;
+
+use std::{fs::OpenOptions, io, os::unix::fs::FileExt, path::Path};
+
+use common::{minor_supported, registered, scratch_path, ScratchPath};
+use nix::{
+    sys::{
+        signal::Signal,
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{fork, ForkResult},
+};
+use uffd_bug::{
+    checksum, memfd_register_mode, page_size, poison_supported,
+    snapshot::{self, SnapshotChain},
+    CorruptPolicy, Error, FaultHandler, MemfdRegion, Page, PageSource, VmRegion,
+};
+use userfaultfd::RegisterMode;
+
+/// A four page base layer whose page 2 was damaged after it was written.
+fn corrupt_layer(name: &str) -> ScratchPath {
+    let path = scratch_path(name);
+    let memory = MemfdRegion::new(4 * page_size()).unwrap();
+    for page in 0..4 {
+        memory.write(page * page_size(), &[page as u8 + 1; 8]);
+    }
+    snapshot::write_base(&mut std::fs::File::create(&path).unwrap(), &memory).unwrap();
+
+    // The header fits in the first page; page 2 is the third one stored.
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[0xee], (3 * page_size() + 4) as u64)
+        .unwrap();
+    path
+}
+
+fn serve(
+    path: &Path,
+    mode: RegisterMode,
+    policy: CorruptPolicy,
+) -> (MemfdRegion, VmRegion, FaultHandler) {
+    let chain = SnapshotChain::open(&[path]).unwrap();
+    serve_source(chain, mode, policy)
+}
+
+fn serve_source(
+    source: impl PageSource + 'static,
+    mode: RegisterMode,
+    policy: CorruptPolicy,
+) -> (MemfdRegion, VmRegion, FaultHandler) {
+    let (memory, vm, handler) = registered(4, mode);
+    let memfd = memory.file().try_clone().unwrap();
+    let handler = handler.page_source(source, Some(memfd)).on_corrupt(policy);
+    (memory, vm, handler)
+}
+
+/// Page N holds N + 1, except that page 1 can no longer be read.
+struct LostSource;
+
+impl PageSource for LostSource {
+    fn read_page(&self, index: usize, buf: &mut [u8]) -> io::Result<Page> {
+        if index == 1 {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "source gone"));
+        }
+        buf.fill(index as u8 + 1);
+        Ok(Page::Data)
+    }
+}
+
+/// Reads the first byte at `offset` of `vm` from a forked child, which
+/// the handler adopts, and returns how the child ended.
+fn touch_in_child(vm: &VmRegion, offset: usize) -> WaitStatus {
+    let addr = vm.as_ptr() as usize + offset;
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => unsafe {
+            (addr as *const u8).read_volatile();
+            nix::libc::_exit(0)
+        },
+        ForkResult::Parent { child } => waitpid(child, None).unwrap(),
+    }
+}
+
+fn assert_sigbus(status: WaitStatus) {
+    match status {
+        WaitStatus::Signaled(_, Signal::SIGBUS, _) => {}
+        status => panic!("expected the child to get SIGBUS, got {:?}", status),
+    }
+}
+
+#[test]
+fn layers_keep_a_checksum_per_stored_page() {
+    let path = corrupt_layer("sums.base");
+    let chain = SnapshotChain::open(&[&path]).unwrap();
+    let mut buf = vec![0; page_size()];
+
+    chain.read_page(1, &mut buf).unwrap();
+    assert_eq!(chain.checksum(1), Some(checksum(&buf)));
+    chain.read_page(2, &mut buf).unwrap();
+    assert_ne!(chain.checksum(2), Some(checksum(&buf)));
+}
+
+#[test]
+fn abort_fails_the_fault() {
+    let path = corrupt_layer("abort.base");
+    let (_memory, vm, mut handler) = serve(&path, RegisterMode::MISSING, CorruptPolicy::Abort);
+
+    let addr = vm.as_ptr() as usize + 2 * page_size();
+    let reader = std::thread::spawn(move || unsafe { (addr as *const u8).read_volatile() });
+    let event = handler.uffd().read_event().unwrap().unwrap();
+    match handler.handle_event(event) {
+        Err(Error::Corrupt { page: 2, .. }) => {}
+        result => panic!("expected a checksum failure, got {:?}", result),
+    }
+
+    // Closing the uffd lets the reader through to a fresh page.
+    drop(handler);
+    reader.join().unwrap();
+}
+
+#[test]
+fn poison_never_maps_corrupt_contents() {
+    let path = corrupt_layer("poison.base");
+    let (_memory, vm, handler) = serve(&path, RegisterMode::MISSING, CorruptPolicy::Poison);
+    let stats = handler.integrity_stats();
+    handler.spawn();
+
+    if poison_supported() {
+        assert_sigbus(touch_in_child(&vm, 2 * page_size()));
+    } else {
+        assert_eq!(vm.read(2 * page_size(), 8), vec![0; 8]);
+    }
+    assert_eq!(vm.read(page_size(), 8), vec![2; 8]);
+    assert_eq!(
+        (stats.corrupt(), stats.served(), stats.withheld()),
+        (1, 0, 1)
+    );
+}
+
+#[test]
+fn without_poison_withheld_pages_read_as_zeroes() {
+    let path = corrupt_layer("nopoison.base");
+    let (_memory, vm, handler) = serve(&path, RegisterMode::MISSING, CorruptPolicy::Poison);
+    let stats = handler.integrity_stats();
+    handler.use_poison(false).spawn();
+
+    assert_eq!(vm.read(2 * page_size(), 8), vec![0; 8]);
+    assert_eq!(vm.read(page_size(), 8), vec![2; 8]);
+    assert_eq!((stats.corrupt(), stats.withheld()), (1, 1));
+
+    let (_memory, vm, handler) =
+        serve_source(LostSource, RegisterMode::MISSING, CorruptPolicy::Poison);
+    handler.use_poison(false).spawn();
+
+    assert_eq!(vm.read(page_size(), 8), vec![0; 8]);
+    assert_eq!(vm.read(2 * page_size(), 8), vec![3; 8]);
+}
+
+#[test]
+fn unreadable_pages_raise_sigbus_and_the_rest_are_served() {
+    if !poison_supported() {
+        eprintln!("skipping: UFFDIO_POISON is not supported");
+        return;
+    }
+    let (_memory, vm, handler) =
+        serve_source(LostSource, RegisterMode::MISSING, CorruptPolicy::Poison);
+    handler.spawn();
+
+    assert_sigbus(touch_in_child(&vm, page_size()));
+    assert!(matches!(
+        touch_in_child(&vm, 3 * page_size()),
+        WaitStatus::Exited(_, 0)
+    ));
+    assert_eq!(vm.read(0, 8), vec![1; 8]);
+    assert_eq!(vm.read(2 * page_size(), 8), vec![3; 8]);
+}
+
+#[test]
+fn abort_fails_on_unreadable_pages() {
+    let (_memory, vm, mut handler) =
+        serve_source(LostSource, RegisterMode::MISSING, CorruptPolicy::Abort);
+
+    let addr = vm.as_ptr() as usize + page_size();
+    let reader = std::thread::spawn(move || unsafe { (addr as *const u8).read_volatile() });
+    let event = handler.uffd().read_event().unwrap().unwrap();
+    assert!(matches!(handler.handle_event(event), Err(Error::Io(_))));
+
+    drop(handler);
+    reader.join().unwrap();
+}
+
+#[test]
+fn poison_applies_before_populating_the_memfd() {
+    if !minor_supported() {
+        return;
+    }
+    let path = corrupt_layer("minor.base");
+    let (memory, vm, handler) = serve(&path, memfd_register_mode().unwrap(), CorruptPolicy::Poison);
+    // In the page cache, so the fault is a MINOR one.
+    memory.write(2 * page_size(), &[7; 8]);
+    handler.spawn();
+
+    if poison_supported() {
+        assert_sigbus(touch_in_child(&vm, 2 * page_size()));
+        assert_eq!(memory.read(2 * page_size(), 8), vec![7; 8]);
+    } else {
+        assert_eq!(vm.read(2 * page_size(), 8), vec![0; 8]);
+    }
+}
+
+#[test]
+fn log_serves_the_page_anyway() {
+    let path = corrupt_layer("log.base");
+    let (_memory, vm, handler) = serve(&path, RegisterMode::MISSING, CorruptPolicy::Log);
+    let stats = handler.integrity_stats();
+    handler.spawn();
+
+    assert_eq!(vm.read(2 * page_size() + 4, 1), vec![0xee]);
+    assert_eq!(
+        (stats.corrupt(), stats.served(), stats.withheld()),
+        (1, 1, 0)
+    );
+    let mismatch = stats.last_mismatch().unwrap();
+    assert_eq!(mismatch.page, 2);
+    assert_ne!(mismatch.expected, mismatch.actual);
+}
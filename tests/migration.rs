@@ -0,0 +1,253 @@
+mod common;
+
+use std::{net::TcpListener, time::Duration};
+
+use nix::sys::mman::{madvise, MmapAdvise};
+use uffd_bug::{
+    migration::postcopy::{Destination, PageServer, RemoteSource, ServerStats},
+    page_size, MemfdRegion, SnapshotFile,
+};
+
+/// Starts a page server for `pages` pages where page N is filled with N + 1.
+fn source(pages: usize) -> (MemfdRegion, std::net::SocketAddr) {
+    let (memory, addr, _) = watched_source(pages);
+    (memory, addr)
+}
+
+/// As [`source`], along with the server's stats.
+fn watched_source(pages: usize) -> (MemfdRegion, std::net::SocketAddr, ServerStats) {
+    let memory = MemfdRegion::new(pages * page_size()).unwrap();
+    for page in 0..pages {
+        memory.write(page * page_size(), &vec![page as u8 + 1; page_size()]);
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let snapshot = SnapshotFile::from_file(memory.file().try_clone().unwrap()).unwrap();
+    let server = PageServer::new(snapshot, page_size(), pages);
+    let stats = server.stats();
+    std::thread::spawn(move || server.serve(listener));
+
+    (memory, addr, stats)
+}
+
+#[test]
+fn faults_fetch_pages_on_demand() {
+    let (_memory, addr) = source(8);
+    let dest = Destination::connect(addr, None).unwrap();
+
+    assert_eq!(dest.vm.read(5 * page_size(), 4), vec![6; 4]);
+    assert_eq!(dest.vm.read(0, 4), vec![1; 4]);
+}
+
+#[test]
+fn prefetcher_streams_remaining_pages() {
+    let pages = 64;
+    let (_memory, addr) = source(pages);
+    let mut dest = Destination::connect(addr, Some(8)).unwrap();
+
+    // Race the pre-fetcher on a page near the end.
+    assert_eq!(dest.vm.read(60 * page_size(), 1), vec![61]);
+
+    let stats = dest.prefetcher.take().unwrap().join().unwrap();
+    assert_eq!(stats.installed + stats.already_present, pages as u64);
+    for page in 0..pages {
+        assert_eq!(
+            dest.memory.read(page * page_size(), 1),
+            vec![page as u8 + 1]
+        );
+    }
+}
+
+#[test]
+fn prefetched_pages_are_not_fetched_again() {
+    let pages = 8;
+    let (_memory, addr) = source(pages);
+    let mut dest = Destination::connect(addr, Some(4)).unwrap();
+    let stats = dest.prefetcher.take().unwrap().join().unwrap();
+    assert_eq!(stats.installed, pages as u64);
+
+    // Once a page arrived, the destination's memory is authoritative: a
+    // page the guest discards comes back empty, not from the source.
+    let page = unsafe { dest.vm.as_ptr().add(2 * page_size()) };
+    unsafe { madvise(page, page_size(), MmapAdvise::MADV_REMOVE) }.unwrap();
+    assert_eq!(dest.vm.read(2 * page_size(), 1), vec![0]);
+}
+
+#[test]
+fn empty_prefetch_batch_is_rejected() {
+    let (_memory, addr) = source(4);
+
+    assert!(Destination::connect(addr, Some(0)).is_err());
+}
+
+#[test]
+fn requests_past_the_end_are_refused() {
+    let (_memory, addr, stats) = watched_source(4);
+    let mut buf = vec![0; 2 * page_size()];
+
+    // The server hangs up rather than answer.
+    let remote = RemoteSource::connect(addr).unwrap();
+    assert!(remote.fetch(3, 2, &mut buf).is_err());
+    let remote = RemoteSource::connect(addr).unwrap();
+    assert!(remote.fetch(usize::MAX, 1, &mut buf).is_err());
+
+    // Each refusal is recorded once its connection thread is done.
+    for _ in 0..100 {
+        if stats.failed() == 2 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!((stats.connections(), stats.failed()), (2, 2));
+    assert!(stats.last_error().unwrap().contains("past the end"));
+}
+
+mod precopy {
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use crate::common::registered;
+    use uffd_bug::{
+        memfd_register_mode,
+        migration::{
+            postcopy::{Destination, PageServer},
+            precopy::{self, Outcome, PrecopyConfig, Received},
+        },
+        page_size, DirtyTracker, MemfdRegion, SnapshotFile, VmRegion,
+    };
+    use userfaultfd::RegisterMode;
+
+    const PAGES: usize = 32;
+
+    /// A source guest: memory, its tracked mapping and a thread that keeps
+    /// writing to pages 3 and 7 until paused.
+    struct Guest {
+        memory: MemfdRegion,
+        vm: Arc<VmRegion>,
+        tracker: DirtyTracker,
+    }
+
+    impl Guest {
+        fn new() -> Self {
+            let (memory, vm, handler) = registered(
+                PAGES,
+                memfd_register_mode().unwrap() | RegisterMode::WRITE_PROTECT,
+            );
+            for page in 0..PAGES {
+                memory.write(page * page_size(), &[page as u8; 16]);
+            }
+            let tracker = DirtyTracker::new(handler.uffd(), &vm).unwrap();
+            handler.dirty_tracker(tracker.clone()).spawn();
+
+            Self {
+                memory,
+                vm: Arc::new(vm),
+                tracker,
+            }
+        }
+
+        fn start_writer(&self) -> impl FnOnce() {
+            let stop = Arc::new(AtomicBool::new(false));
+            let writer = std::thread::spawn({
+                let (vm, stop) = (self.vm.clone(), stop.clone());
+                move || {
+                    let mut n = 0u8;
+                    while !stop.load(Ordering::Relaxed) {
+                        n = n.wrapping_add(1);
+                        vm.write(3 * page_size(), &[n]);
+                        vm.write(7 * page_size(), &[n]);
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            move || {
+                stop.store(true, Ordering::Relaxed);
+                writer.join().unwrap();
+            }
+        }
+    }
+
+    fn migrate(guest: &Guest, config: PrecopyConfig) -> (precopy::Report, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver =
+            std::thread::spawn(move || precopy::receive(listener.accept().unwrap().0).unwrap());
+
+        let pause = guest.start_writer();
+        let report = precopy::send(
+            TcpStream::connect(addr).unwrap(),
+            &guest.memory,
+            &guest.tracker,
+            config,
+            pause,
+        )
+        .unwrap();
+        (report, receiver.join().unwrap())
+    }
+
+    #[test]
+    fn converges_with_small_dirty_set() {
+        let guest = Guest::new();
+        let config = PrecopyConfig {
+            threshold: 4,
+            ..PrecopyConfig::default()
+        };
+
+        let (report, received) = migrate(&guest, config);
+
+        assert_eq!(report.outcome, Outcome::Converged);
+        assert_eq!(report.rounds[0].pages_sent, PAGES);
+        // The stop-and-copy round is reported last.
+        let last = report.rounds.last().unwrap();
+        assert!(report.rounds.len() >= 2);
+        assert_eq!(last.dirtied, 0);
+        let memory = match received {
+            Received::Complete(memory) => memory,
+            Received::PostCopy { .. } => panic!("expected a complete copy"),
+        };
+        for page in 0..PAGES {
+            let offset = page * page_size();
+            assert_eq!(memory.read(offset, 16), guest.memory.read(offset, 16));
+        }
+    }
+
+    #[test]
+    fn switches_to_post_copy_when_not_converging() {
+        let guest = Guest::new();
+        let config = PrecopyConfig {
+            threshold: 0,
+            max_rounds: 2,
+            stalled_rounds: 100,
+        };
+
+        let (report, received) = migrate(&guest, config);
+
+        assert_eq!(report.rounds.len(), 2);
+        let remaining = match &report.outcome {
+            Outcome::PostCopy { remaining } => remaining.clone(),
+            Outcome::Converged => panic!("expected a switch to post-copy"),
+        };
+        assert!(remaining.get(3) && remaining.get(7));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let snapshot = SnapshotFile::from_file(guest.memory.file().try_clone().unwrap()).unwrap();
+        std::thread::spawn(move || PageServer::new(snapshot, page_size(), PAGES).serve(listener));
+
+        let memory = match received {
+            Received::PostCopy { memory, .. } => memory,
+            Received::Complete(_) => panic!("expected a post-copy handover"),
+        };
+        let dest = Destination::resume(memory, addr, None).unwrap();
+        for page in 0..PAGES {
+            let offset = page * page_size();
+            assert_eq!(dest.vm.read(offset, 16), guest.memory.read(offset, 16));
+        }
+    }
+}
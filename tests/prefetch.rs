@@ -0,0 +1,82 @@
+mod common;
+
+use common::registered;
+use uffd_bug::{
+    memfd_register_mode, page_size,
+    prefetch::TracePrefetcher,
+    trace::{Trace, TraceKind, TraceRecord},
+    FaultHandler, MemfdRegion, MemorySource, VmRegion,
+};
+
+fn trace_of(pages: &[usize]) -> Trace {
+    let records = pages
+        .iter()
+        .enumerate()
+        .map(|(i, &page)| TraceRecord {
+            timestamp_ns: i as u64,
+            offset: (page * page_size()) as u64,
+            kind: TraceKind::Missing,
+            write: false,
+            handler_thread: 0,
+            latency_ns: 0,
+        })
+        .collect();
+    Trace {
+        page_size: page_size(),
+        records,
+    }
+}
+
+fn setup(pages: usize) -> (MemfdRegion, VmRegion, FaultHandler) {
+    let (memory, vm, handler) = registered(pages, memfd_register_mode().unwrap());
+    let mut source = MemorySource::new();
+    for page in 0..pages {
+        source.insert(page, vec![page as u8 + 1; 8]);
+    }
+    let memfd = memory.file().try_clone().unwrap();
+    let handler = handler.page_source(source, Some(memfd));
+    (memory, vm, handler)
+}
+
+#[test]
+fn traced_pages_are_mapped_without_faults() {
+    let (memory, vm, handler) = setup(4);
+    let stats = handler.event_stats();
+    let prefetcher = TracePrefetcher::spawn(&handler, &trace_of(&[2, 0, 3, 2])).unwrap();
+    handler.spawn();
+
+    let prefetched = prefetcher.join().unwrap();
+    assert_eq!(prefetched.installed, 3);
+    assert_eq!(prefetched.hit_rate(), 1.0);
+
+    for page in [0, 2, 3] {
+        assert_eq!(vm.read(page * page_size(), 1), vec![page as u8 + 1]);
+        assert_eq!(memory.read(page * page_size(), 1), vec![page as u8 + 1]);
+    }
+    assert_eq!(stats.events(), 0);
+
+    assert_eq!(vm.read(page_size(), 1), vec![2]);
+    assert_eq!(stats.events(), 1);
+}
+
+#[test]
+fn pages_faulted_first_count_as_misses() {
+    let (_memory, vm, mut handler) = setup(2);
+
+    // Serve a fault on page 0 by hand before the prefetcher starts.
+    let base = vm.as_ptr() as usize;
+    let reader = std::thread::spawn(move || unsafe { (base as *const u8).read_volatile() });
+    let event = handler.uffd().read_event().unwrap().unwrap();
+    handler.handle_event(event).unwrap();
+    assert_eq!(reader.join().unwrap(), 1);
+
+    let prefetched = TracePrefetcher::spawn(&handler, &trace_of(&[0, 1]))
+        .unwrap()
+        .join()
+        .unwrap();
+
+    assert_eq!(prefetched.already_present, 1);
+    assert_eq!(prefetched.installed, 1);
+    assert_eq!(prefetched.hit_rate(), 0.5);
+    assert_eq!(vm.read(page_size(), 1), vec![2]);
+}
@@ -0,0 +1,97 @@
+use std::os::unix::prelude::AsRawFd;
+
+use uffd_bug::{
+    available_features, create_uffd, memfd_register_mode, move_supported, poison_supported,
+    probe::{Capabilities, FEATURES, IOCTLS},
+    required_features,
+};
+use userfaultfd::{FeatureFlags, RegisterMode};
+
+fn ioctl(name: &str) -> u64 {
+    IOCTLS.iter().find(|(n, _)| *n == name).unwrap().1
+}
+
+fn feature(name: &str) -> u64 {
+    FEATURES.iter().find(|(n, _)| *n == name).unwrap().1
+}
+
+#[test]
+fn probe_reports_the_api_and_range_ioctls() {
+    let caps = Capabilities::probe().unwrap();
+    assert!(!caps.kernel.is_empty());
+
+    for name in ["UFFDIO_API", "UFFDIO_REGISTER", "UFFDIO_UNREGISTER"] {
+        assert_ne!(caps.ioctls & ioctl(name), 0, "{}", name);
+    }
+    for name in ["UFFDIO_WAKE", "UFFDIO_COPY", "UFFDIO_ZEROPAGE"] {
+        assert_ne!(caps.anon_ioctls & ioctl(name), 0, "{}", name);
+        assert_ne!(caps.shmem_ioctls & ioctl(name), 0, "{}", name);
+    }
+    if caps.has(FeatureFlags::MINOR_SHMEM.bits()) {
+        assert_ne!(caps.shmem_ioctls & ioctl("UFFDIO_CONTINUE"), 0);
+    }
+}
+
+#[test]
+fn handler_asks_only_for_what_the_kernel_has() {
+    let caps = Capabilities::probe().unwrap();
+    let features = available_features().unwrap();
+    let mode = memfd_register_mode().unwrap();
+    assert_eq!(features, caps.available_features());
+    assert!(required_features().contains(features));
+    assert_eq!(mode, caps.memfd_mode());
+    assert!(mode.contains(RegisterMode::MISSING));
+    assert_eq!(
+        mode.contains(RegisterMode::MODE_MINOR),
+        caps.has(FeatureFlags::MINOR_SHMEM.bits())
+    );
+
+    create_uffd().unwrap();
+}
+
+#[test]
+fn report_lists_every_feature_and_ioctl() {
+    let caps = Capabilities::probe().unwrap();
+    let report = caps.to_string();
+
+    assert!(report.starts_with(&format!("kernel {}\n", caps.kernel)));
+    for (name, _) in FEATURES.iter().chain(IOCTLS) {
+        assert!(report.contains(name), "{} missing from\n{}", name, report);
+    }
+    assert!(report.contains("memfd regions: "));
+}
+
+#[test]
+fn report_names_what_the_handler_goes_without() {
+    let caps = Capabilities {
+        kernel: "5.10.0".to_string(),
+        features: FeatureFlags::MISSING_SHMEM.bits() | FeatureFlags::EVENT_FORK.bits(),
+        ioctls: 0,
+        anon_ioctls: 0,
+        shmem_ioctls: 0,
+    };
+    assert_eq!(caps.memfd_mode(), RegisterMode::MISSING);
+
+    let report = caps.to_string();
+    assert!(report.contains("MINOR_SHMEM"));
+    assert!(report.contains("handler runs without"));
+    assert!(report.ends_with("memfd regions: MISSING faults only\n"));
+}
+
+#[test]
+fn handshake_asks_for_poison_and_move_where_offered() {
+    let uffd = create_uffd().unwrap();
+
+    // The kernel shows what a uffd's handshake settled on as
+    // "API:\t<api>:<features>:<ioctls>", in hex.
+    let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", uffd.as_raw_fd()));
+    let features = fdinfo
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("API:\t"))
+        .and_then(|api| api.split(':').nth(1))
+        .map(|features| u64::from_str_radix(features, 16).unwrap())
+        .expect("no API line in fdinfo");
+    assert_eq!(features & feature("POISON") != 0, poison_supported());
+    assert_eq!(features & feature("MOVE") != 0, move_supported());
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use common::{minor_supported, registered};
+use uffd_bug::{
+    memfd_register_mode, page_size, EventStats, FaultHandler, MemfdRegion, MemorySource,
+    ReadaheadStats, VmRegion,
+};
+
+fn setup(
+    pages: usize,
+    configure: impl FnOnce(FaultHandler) -> FaultHandler,
+) -> (MemfdRegion, VmRegion, EventStats, ReadaheadStats) {
+    let (memory, vm, handler) = registered(pages, memfd_register_mode().unwrap());
+    let handler = configure(handler);
+    let (events, readahead) = (handler.event_stats(), handler.readahead_stats());
+    handler.spawn();
+    (memory, vm, events, readahead)
+}
+
+fn numbered(pages: usize) -> MemorySource {
+    let mut source = MemorySource::new();
+    for page in 0..pages {
+        source.insert(page, vec![page as u8 + 1; 16]);
+    }
+    source
+}
+
+#[test]
+fn sequential_missing_faults_grow_the_window() {
+    let pages = 64;
+    let (_memory, vm, events, readahead) = setup(pages, |handler| {
+        handler.page_source(numbered(pages), None).readahead(8)
+    });
+
+    for page in 0..pages {
+        assert_eq!(vm.read(page * page_size(), 1), vec![page as u8 + 1]);
+    }
+
+    // Faults on 0, 1 and 2 start the stream; then the window doubles from
+    // 2 pages to 8 at the faults on 5, 10 and 19, and stays there.
+    assert_eq!(events.events(), 10);
+    assert_eq!(readahead.pages(), 54);
+    assert_eq!(readahead.hits(), 46);
+    assert_eq!(readahead.wasted(), 0);
+}
+
+#[test]
+fn strided_faults_read_ahead_at_the_stride() {
+    let pages = 64;
+    let (_memory, vm, events, readahead) = setup(pages, |handler| {
+        handler.page_source(numbered(pages), None).readahead(4)
+    });
+
+    for page in (0..pages).step_by(4) {
+        assert_eq!(vm.read(page * page_size(), 1), vec![page as u8 + 1]);
+    }
+
+    // Faults on 0, 4, 8, 20, 40 and 60.
+    assert_eq!(events.events(), 6);
+    assert_eq!(readahead.pages(), 10);
+    assert_eq!(readahead.hits(), 10);
+
+    // The pages between the strides were left alone.
+    assert_eq!(vm.read(page_size(), 1), vec![2]);
+    assert_eq!(events.events(), 7);
+}
+
+#[test]
+fn sequential_minor_faults_continue_ahead() {
+    if !minor_supported() {
+        return;
+    }
+    let pages = 32;
+    let (memory, vm, events, readahead) = setup(pages, |handler| handler.readahead(8));
+    for page in 0..pages {
+        memory.write(page * page_size(), &[page as u8 + 1]);
+    }
+
+    for page in 0..pages {
+        assert_eq!(vm.read(page * page_size(), 1), vec![page as u8 + 1]);
+    }
+
+    assert_eq!(events.events(), 7);
+    assert_eq!(readahead.pages(), 25);
+}
+
+#[test]
+fn readahead_stops_at_region_end() {
+    let pages = 8;
+    let (_memory, vm, events, readahead) = setup(pages, |handler| {
+        handler.page_source(numbered(pages), None).readahead(64)
+    });
+
+    for page in 0..pages {
+        assert_eq!(vm.read(page * page_size(), 1), vec![page as u8 + 1]);
+    }
+
+    // The fault on 2 reads 3 and 4 ahead, the one on 5 only 6 and 7.
+    assert_eq!(events.events(), 4);
+    assert_eq!(readahead.pages(), 4);
+}
+
+#[test]
+fn abandoned_window_counts_as_wasted() {
+    let pages = 64;
+    let (_memory, vm, _events, readahead) = setup(pages, |handler| handler.readahead(8));
+
+    // Faults on 0, 1 and 2 read pages 3 and 4 ahead.
+    for page in 0..3 {
+        vm.read(page * page_size(), 1);
+    }
+    vm.read(40 * page_size(), 1);
+
+    assert_eq!(readahead.pages(), 2);
+    assert_eq!(readahead.wasted(), 2);
+    assert_eq!(readahead.hits(), 0);
+}
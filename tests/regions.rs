@@ -0,0 +1,163 @@
+use std::ffi::c_void;
+
+use uffd_bug::{
+    create_uffd, page_size, Error, FaultHandler, MemorySource, Region, RegionTable, VmRegion,
+};
+use userfaultfd::{Event, FaultKind, ReadWrite, RegisterMode};
+
+fn region(start: usize, pages: usize, offset_pages: u64) -> Region {
+    Region {
+        start,
+        len: pages * page_size(),
+        offset: offset_pages * page_size() as u64,
+        mode: RegisterMode::MISSING,
+    }
+}
+
+#[test]
+fn table_keeps_regions_sorted() {
+    let ps = page_size();
+    let mut table = RegionTable::new();
+    table.insert(region(0x20000 * ps, 2, 2)).unwrap();
+    table.insert(region(0x10000 * ps, 2, 0)).unwrap();
+
+    let starts: Vec<usize> = table.iter().map(|r| r.start).collect();
+    assert_eq!(starts, vec![0x10000 * ps, 0x20000 * ps]);
+    assert_eq!(table.backing_len(), 4 * ps as u64);
+}
+
+#[test]
+fn table_rejects_overlap_and_empty() {
+    let ps = page_size();
+    let mut table = RegionTable::new();
+    table.insert(region(0x10000 * ps, 4, 0)).unwrap();
+
+    assert!(matches!(
+        table.insert(region(0x10003 * ps, 2, 4)),
+        Err(Error::InvalidRegion(_))
+    ));
+    assert!(matches!(
+        table.insert(region(0xfffe * ps, 3, 4)),
+        Err(Error::InvalidRegion(_))
+    ));
+    assert!(matches!(
+        table.insert(region(0x20000 * ps, 0, 4)),
+        Err(Error::InvalidRegion(_))
+    ));
+    // Touching is fine.
+    table.insert(region(0x10004 * ps, 1, 4)).unwrap();
+    assert_eq!(table.len(), 2);
+}
+
+#[test]
+fn table_rejects_unaligned_regions() {
+    let ps = page_size();
+    let mut table = RegionTable::new();
+    let aligned = region(0x10000 * ps, 2, 0);
+
+    for unaligned in [
+        Region {
+            start: aligned.start + 8,
+            ..aligned
+        },
+        Region {
+            len: aligned.len - 8,
+            ..aligned
+        },
+        Region {
+            offset: 8,
+            ..aligned
+        },
+    ] {
+        assert!(matches!(
+            table.insert(unaligned),
+            Err(Error::InvalidRegion(_))
+        ));
+    }
+    assert!(table.is_empty());
+}
+
+#[test]
+fn lookup_finds_region_or_reports_gap() {
+    let ps = page_size();
+    let mut table = RegionTable::new();
+    table.insert(region(0x10000 * ps, 2, 0)).unwrap();
+    table.insert(region(0x20000 * ps, 2, 2)).unwrap();
+
+    let found = table.lookup(0x20001 * ps + 5).unwrap();
+    assert_eq!(found.start, 0x20000 * ps);
+    assert_eq!(found.file_offset(0x20001 * ps + 5), 3 * ps as u64 + 5);
+
+    let gap = 0x18000 * ps;
+    assert!(matches!(table.lookup(gap), Err(Error::OutOfRegion { addr }) if addr == gap));
+    assert!(matches!(
+        table.lookup(0x10002 * ps),
+        Err(Error::OutOfRegion { .. })
+    ));
+}
+
+#[test]
+fn faults_fill_each_region_from_its_file_offset() {
+    let ps = page_size();
+    let low = VmRegion::anonymous(2 * ps).unwrap();
+    let high = VmRegion::anonymous(2 * ps).unwrap();
+    let mut table = RegionTable::new();
+    table.insert(low.region(0, RegisterMode::MISSING)).unwrap();
+    table
+        .insert(high.region(2 * ps as u64, RegisterMode::MISSING))
+        .unwrap();
+
+    let uffd = create_uffd().unwrap();
+    table.register(&uffd).unwrap();
+    let mut source = MemorySource::new();
+    for page in 0..4 {
+        source.insert(page, vec![page as u8 + 1; 8]);
+    }
+    FaultHandler::with_regions(uffd, table, ps)
+        .page_source(source, None)
+        .spawn();
+
+    assert_eq!(low.read(ps, 2), vec![2; 2]);
+    assert_eq!(high.read(0, 2), vec![3; 2]);
+    assert_eq!(high.read(ps, 2), vec![4; 2]);
+}
+
+#[test]
+fn fault_outside_regions_is_an_error() {
+    let ps = page_size();
+    let vm = VmRegion::anonymous(2 * ps).unwrap();
+    let mut table = RegionTable::new();
+    table.insert(vm.region(0, RegisterMode::MISSING)).unwrap();
+    let mut handler = FaultHandler::with_regions(create_uffd().unwrap(), table, ps);
+
+    let addr = vm.as_ptr() as usize + 2 * ps;
+    let result = handler.handle_event(Event::Pagefault {
+        kind: FaultKind::Missing,
+        rw: ReadWrite::Read,
+        addr: addr as *mut c_void,
+    });
+    assert!(matches!(result, Err(Error::OutOfRegion { addr: a }) if a == addr));
+}
+
+#[test]
+fn stray_faults_are_counted_and_served_when_asked() {
+    let ps = page_size();
+    let vm = VmRegion::anonymous(3 * ps).unwrap();
+    let uffd = create_uffd().unwrap();
+    vm.register(&uffd, RegisterMode::MISSING).unwrap();
+    // The table knows only the first two of the registered pages.
+    let mut table = RegionTable::new();
+    table
+        .insert(Region {
+            len: 2 * ps,
+            ..vm.region(0, RegisterMode::MISSING)
+        })
+        .unwrap();
+    let handler = FaultHandler::with_regions(uffd, table, ps).serve_stray(true);
+    let stats = handler.event_stats();
+    handler.spawn();
+
+    assert_eq!(vm.read(2 * ps, 2), vec![0; 2]);
+    assert_eq!(stats.stray(), 1);
+    assert_eq!(vm.read(0, 2), vec![0; 2]);
+}
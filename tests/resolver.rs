@@ -0,0 +1,35 @@
+use uffd_bug::{PageBitmap, Resolver};
+
+const PAGE: usize = 4096;
+
+#[test]
+fn block_aligns_down_to_page() {
+    let resolver = Resolver::new(PAGE);
+    let region = 0x10000..0x10000 + 4 * PAGE;
+
+    assert_eq!(
+        resolver.block(0x10000 + PAGE + 17, region),
+        0x10000 + PAGE..0x10000 + 2 * PAGE
+    );
+}
+
+#[test]
+fn block_is_clamped_to_region_end() {
+    let resolver = Resolver::new(PAGE).block_pages(3);
+    let region = 0x10000..0x10000 + 4 * PAGE;
+
+    assert_eq!(
+        resolver.block(0x10000 + 3 * PAGE, region),
+        0x10000 + 3 * PAGE..0x10000 + 4 * PAGE
+    );
+}
+
+#[test]
+fn clear_runs_skip_populated_pages() {
+    let mut bitmap = PageBitmap::new(8);
+    bitmap.set(2);
+    bitmap.set_range(5..7);
+
+    assert_eq!(bitmap.clear_runs(0..8), vec![0..2, 3..5, 7..8]);
+    assert_eq!(bitmap.clear_runs(5..7), vec![]);
+}
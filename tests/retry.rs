@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use nix::libc;
+use uffd_bug::{ErrorClass, Fallback, Retry, RetryPolicy};
+
+#[test]
+fn errnos_are_classified() {
+    assert_eq!(
+        ErrorClass::from_errno(libc::EAGAIN),
+        ErrorClass::MappingChanging
+    );
+    assert_eq!(
+        ErrorClass::from_errno(libc::EEXIST),
+        ErrorClass::AlreadyMapped
+    );
+    assert_eq!(
+        ErrorClass::from_errno(libc::ENOENT),
+        ErrorClass::NotInPageCache
+    );
+    assert_eq!(ErrorClass::from_errno(libc::EFAULT), ErrorClass::BadAddress);
+    assert_eq!(
+        ErrorClass::from_errno(libc::ESRCH),
+        ErrorClass::TargetExited
+    );
+    assert_eq!(
+        ErrorClass::from_errno(libc::EINVAL),
+        ErrorClass::Other(libc::EINVAL)
+    );
+}
+
+#[test]
+fn default_policy_is_bounded() {
+    let policy = RetryPolicy::default();
+
+    for errno in [libc::EAGAIN, libc::EFAULT, libc::ESRCH, libc::EINVAL] {
+        let rule = policy.rule(ErrorClass::from_errno(errno));
+        assert!(rule.max_attempts < 100);
+    }
+    assert_eq!(
+        policy.rule(ErrorClass::NotInPageCache).fallback,
+        Fallback::Zeropage
+    );
+}
+
+#[test]
+fn backoff_doubles_per_attempt() {
+    let rule = Retry {
+        max_attempts: 4,
+        backoff: Duration::from_micros(10),
+        fallback: Fallback::GiveUp,
+    };
+
+    assert_eq!(rule.delay(1), Duration::from_micros(10));
+    assert_eq!(rule.delay(3), Duration::from_micros(40));
+}
+
+#[test]
+fn overrides_replace_existing_rule() {
+    let policy =
+        RetryPolicy::default().with(ErrorClass::NotInPageCache, Retry::once(Fallback::Copy));
+
+    assert_eq!(
+        policy.rule(ErrorClass::NotInPageCache),
+        Retry::once(Fallback::Copy)
+    );
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use uffd_bug::scenario::{self, Outcome};
+
+fn assert_passes(name: &str) {
+    let scenario = scenario::find(name).expect("scenario is registered");
+    assert_eq!(
+        scenario.run(Duration::from_secs(10)),
+        Outcome::Pass,
+        "{}",
+        name
+    );
+}
+
+#[test]
+fn fork_event_adopts_child_uffd() {
+    assert_passes("fork-with-registered-range");
+}
+
+#[test]
+fn remap_event_moves_region() {
+    assert_passes("remap-registered-range");
+}
+
+#[test]
+fn partial_remap_splits_region() {
+    assert_passes("remap-part-of-range");
+}
+
+#[test]
+fn unmap_event_drops_page_state() {
+    assert_passes("unmap-part-of-range");
+}
+
+#[test]
+fn remove_event_invalidates_page_state() {
+    assert_passes("remove-during-fault");
+}
+
+#[test]
+fn write_protect_fault_is_resolved() {
+    assert_passes("write-protect-then-write");
+}
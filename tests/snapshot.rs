@@ -0,0 +1,118 @@
+mod common;
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+};
+
+use common::scratch_path;
+use uffd_bug::{
+    page_size,
+    snapshot::{self, LayerKind, SnapshotChain},
+    MemfdRegion, Page, PageBitmap, PageSource,
+};
+
+fn write_layers(memory: &MemfdRegion, base: &Path, diff: &Path) {
+    for page in 0..4 {
+        memory.write(page * page_size(), &[page as u8 + 1; 8]);
+    }
+    snapshot::write_base(&mut File::create(base).unwrap(), memory).unwrap();
+
+    memory.write(2 * page_size(), &[9; 8]);
+    let mut dirty = PageBitmap::new(4);
+    dirty.set(2);
+    snapshot::write_diff(&mut File::create(diff).unwrap(), memory, &dirty).unwrap();
+}
+
+#[test]
+fn chain_reads_newest_layer_first() {
+    let (base, diff) = (scratch_path("chain.base"), scratch_path("chain.diff"));
+    let memory = MemfdRegion::new(4 * page_size()).unwrap();
+    write_layers(&memory, &base, &diff);
+
+    let chain = SnapshotChain::open(&[&base, &diff]).unwrap();
+    let mut buf = vec![0; page_size()];
+
+    assert_eq!(chain.header().kind, LayerKind::Base);
+    assert_eq!(chain.read_page(1, &mut buf).unwrap(), Page::Data);
+    assert_eq!(&buf[..8], &[2; 8]);
+    assert_eq!(chain.read_page(2, &mut buf).unwrap(), Page::Data);
+    assert_eq!(&buf[..8], &[9; 8]);
+    assert_eq!(chain.changed_pages(memory.file()).unwrap().count_ones(), 0);
+}
+
+#[test]
+fn diff_must_sit_on_a_base() {
+    let (base, diff) = (scratch_path("order.base"), scratch_path("order.diff"));
+    let memory = MemfdRegion::new(4 * page_size()).unwrap();
+    write_layers(&memory, &base, &diff);
+
+    assert!(SnapshotChain::open(&[&diff]).is_err());
+}
+
+#[test]
+fn restore_serves_faults_from_chain() {
+    let (base, diff) = (scratch_path("restore.base"), scratch_path("restore.diff"));
+    let memory = MemfdRegion::new(4 * page_size()).unwrap();
+    write_layers(&memory, &base, &diff);
+
+    let restored = snapshot::restore(SnapshotChain::open(&[&base, &diff]).unwrap()).unwrap();
+
+    assert_eq!(restored.vm.read(0, 8), vec![1; 8]);
+    assert_eq!(restored.vm.read(2 * page_size(), 8), vec![9; 8]);
+    assert_eq!(restored.vm.read(3 * page_size(), 8), vec![4; 8]);
+}
+
+#[test]
+fn zero_pages_are_stored_as_bits_only() {
+    let path = scratch_path("zero.base");
+    let memory = MemfdRegion::new(4 * page_size()).unwrap();
+    memory.write(0, &[1; 8]);
+    memory.write(3 * page_size(), &[4; 8]);
+
+    let stats = snapshot::write_base(&mut File::create(&path).unwrap(), &memory).unwrap();
+    assert_eq!((stats.data_pages, stats.zero_pages), (2, 2));
+    assert_eq!(stats.bytes_saved(), 2 * page_size() as u64);
+    // One page of header and bitmaps, the two pages with data and their
+    // checksums.
+    let len = std::fs::metadata(&path).unwrap().len();
+    assert_eq!(len, 3 * page_size() as u64 + 8);
+
+    let chain = SnapshotChain::open(&[&path]).unwrap();
+    let mut buf = vec![0; page_size()];
+    assert_eq!(chain.read_page(1, &mut buf).unwrap(), Page::Zero);
+    assert_eq!(chain.read_page(3, &mut buf).unwrap(), Page::Data);
+    assert_eq!(&buf[..8], &[4; 8]);
+
+    let restored = snapshot::restore(chain).unwrap();
+    assert_eq!(restored.vm.read(page_size(), 8), vec![0; 8]);
+    assert_eq!(restored.vm.read(3 * page_size(), 8), vec![4; 8]);
+    assert_eq!(restored.zero_pages.pages(), 1);
+}
+
+#[test]
+fn bad_header_sizes_are_rejected() {
+    let path = scratch_path("bad.base");
+    let memory = MemfdRegion::new(4 * page_size()).unwrap();
+    memory.write(0, &[1; 8]);
+    snapshot::write_base(&mut File::create(&path).unwrap(), &memory).unwrap();
+
+    // page_size sits at byte 16 of the header, total_pages at byte 24.
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    let patch = |offset, bytes: &[u8]| file.write_all_at(bytes, offset).unwrap();
+    let rejected = || {
+        let err = SnapshotChain::open(&[&path]).err().expect("layer opened");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    };
+    patch(16, &0u32.to_le_bytes());
+    rejected();
+    patch(16, &3000u32.to_le_bytes());
+    rejected();
+    patch(16, &(page_size() as u32).to_le_bytes());
+    patch(24, &u64::MAX.to_le_bytes());
+    rejected();
+    patch(24, &(1u64 << 40).to_le_bytes());
+    rejected();
+}
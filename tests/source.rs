@@ -0,0 +1,49 @@
+mod common;
+
+use std::io::Write;
+
+use common::tempfile;
+use uffd_bug::{is_zero, MemorySource, Page, PageSource, SnapshotFile, ZeroSource};
+
+#[test]
+fn snapshot_file_pads_last_page_and_reports_absent_past_end() {
+    let mut file = tempfile();
+    file.write_all(&[1; 6]).unwrap();
+    let source = SnapshotFile::from_file(file).unwrap();
+    let mut buf = [0xff; 4];
+
+    assert_eq!(source.read_page(1, &mut buf).unwrap(), Page::Data);
+    assert_eq!(buf, [1, 1, 0, 0]);
+    assert_eq!(source.read_page(2, &mut buf).unwrap(), Page::Absent);
+}
+
+#[test]
+fn memory_source_returns_inserted_pages() {
+    let mut source = MemorySource::new();
+    source.insert(3, vec![7, 8]);
+    let mut buf = [0xff; 4];
+
+    assert_eq!(source.read_page(3, &mut buf).unwrap(), Page::Data);
+    assert_eq!(buf, [7, 8, 0, 0]);
+    assert_eq!(source.read_page(0, &mut buf).unwrap(), Page::Absent);
+}
+
+#[test]
+fn zero_source_is_all_zero() {
+    let mut buf = [0xff; 4];
+
+    assert_eq!(ZeroSource.read_page(9, &mut buf).unwrap(), Page::Zero);
+}
+
+#[test]
+fn is_zero_checks_every_byte() {
+    let mut page = vec![0; 4096 + 3];
+    assert!(is_zero(&page));
+    assert!(is_zero(&[]));
+
+    for at in [0, 63, 64, 4095, 4097] {
+        page[at] = 1;
+        assert!(!is_zero(&page), "byte {} set", at);
+        page[at] = 0;
+    }
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use std::{io::Cursor, path::Path, time::Duration};
+
+use common::{minor_supported, registered, scratch_path};
+use uffd_bug::{
+    memfd_register_mode, page_size,
+    trace::{Trace, TraceKind, TraceRecorder},
+};
+use userfaultfd::RegisterMode;
+
+/// Reads the trace once it holds `count` records. Faulting threads are
+/// woken before their fault is recorded, so the last record may lag.
+fn wait_for_records(path: &Path, recorder: &TraceRecorder, count: usize) -> Trace {
+    for _ in 0..100 {
+        recorder.flush().unwrap();
+        let trace = Trace::open(path).unwrap();
+        if trace.records.len() >= count {
+            return trace;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("trace never reached {} records", count);
+}
+
+#[test]
+fn records_each_fault_in_order() {
+    if !minor_supported() {
+        return;
+    }
+    let path = scratch_path("faults.trace");
+    let (memory, vm, handler) = registered(4, memfd_register_mode().unwrap());
+    let recorder = TraceRecorder::create(&path, page_size()).unwrap();
+    handler.trace(recorder.clone()).spawn();
+
+    memory.write(page_size(), &[1]);
+    vm.read(2 * page_size(), 1);
+    vm.read(page_size(), 1);
+    vm.write(3 * page_size(), &[2]);
+
+    let trace = wait_for_records(&path, &recorder, 3);
+    assert_eq!(trace.page_size, page_size());
+    let faults: Vec<(u64, TraceKind, bool)> = trace
+        .records
+        .iter()
+        .map(|r| (r.offset, r.kind, r.write))
+        .collect();
+    assert_eq!(
+        faults,
+        vec![
+            (2 * page_size() as u64, TraceKind::Missing, false),
+            (page_size() as u64, TraceKind::Minor, false),
+            (3 * page_size() as u64, TraceKind::Missing, true),
+        ]
+    );
+    assert!(trace
+        .records
+        .windows(2)
+        .all(|w| w[0].timestamp_ns <= w[1].timestamp_ns));
+}
+
+#[test]
+fn dumps_csv_and_json() {
+    let path = scratch_path("dump.trace");
+    let (_memory, vm, handler) = registered(1, RegisterMode::MISSING);
+    let recorder = TraceRecorder::create(&path, page_size()).unwrap();
+    handler.trace(recorder.clone()).spawn();
+    vm.read(0, 1);
+    let trace = wait_for_records(&path, &recorder, 1);
+    let record = trace.records[0];
+
+    let mut csv = Vec::new();
+    trace.write_csv(&mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines[0],
+        "timestamp_ns,offset,kind,write,handler_thread,latency_ns"
+    );
+    assert_eq!(
+        lines[1],
+        format!(
+            "{},0,missing,0,{},{}",
+            record.timestamp_ns, record.handler_thread, record.latency_ns
+        )
+    );
+
+    let mut json = Vec::new();
+    trace.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json[0]["kind"], "missing");
+    assert_eq!(json[0]["offset"], 0);
+    assert_eq!(json[0]["write"], false);
+}
+
+#[test]
+fn rejects_other_files() {
+    let err = Trace::read_from(&mut Cursor::new(b"UFFDSNAP\x01\0\0\0\0\x10\0\0")).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}